@@ -0,0 +1,4 @@
+// Migrations are embedded by sqlx::migrate!, which doesn't tell cargo to rebuild when they change.
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+}
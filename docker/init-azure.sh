@@ -0,0 +1,6 @@
+#!/bin/bash
+# Azurite's well-known development account, reached through the compose network.
+CONNECTION_STRING="DefaultEndpointsProtocol=http;AccountName=devstoreaccount1;AccountKey=Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==;BlobEndpoint=http://azurite:10000/devstoreaccount1;"
+until az storage container create --name smtp2s-data-storage --connection-string "$CONNECTION_STRING"; do
+  sleep 1
+done
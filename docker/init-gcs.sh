@@ -0,0 +1,4 @@
+#!/bin/sh
+until curl -sf -X POST http://fake-gcs-server:4443/storage/v1/b -H "Content-Type: application/json" -d '{"name": "smtp2s-data-storage"}'; do
+  sleep 1
+done
@@ -0,0 +1,4 @@
+#!/bin/sh
+until /opt/kafka/bin/kafka-topics.sh --bootstrap-server kafka:29092 --create --if-not-exists --topic smtp2s-messages; do
+  sleep 1
+done
@@ -0,0 +1,4 @@
+#!/bin/sh
+until nats --server nats://nats:4222 stream add smtp2s-messages --subjects 'smtp2s.messages.>' --defaults; do
+  sleep 1
+done
@@ -0,0 +1,49 @@
+CREATE TABLE messages (
+    -- ULID, sorting by id sorts by reception time
+    id TEXT PRIMARY KEY,
+    received_at TIMESTAMPTZ NOT NULL,
+    client TEXT NOT NULL,
+    client_address TEXT NOT NULL,
+    authenticated_user TEXT,
+    mail_from TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    -- The Date header, as sent
+    date TEXT,
+    message_id TEXT,
+    -- Client attributes relayed through XCLIENT or XFORWARD
+    forwarded JSONB,
+    -- The message exactly as received
+    raw_message BYTEA NOT NULL
+);
+
+CREATE INDEX messages_received_at ON messages (received_at);
+CREATE INDEX messages_message_id ON messages (message_id);
+
+CREATE TABLE recipients (
+    message_id TEXT NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    -- 'envelope' for RCPT TO addresses, or the 'to', 'cc' and 'bcc' headers
+    kind TEXT NOT NULL,
+    address TEXT NOT NULL
+);
+
+CREATE INDEX recipients_message_id ON recipients (message_id);
+CREATE INDEX recipients_address ON recipients (address);
+
+CREATE TABLE bodies (
+    message_id TEXT NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    file_name TEXT NOT NULL,
+    content_type TEXT NOT NULL,
+    synthesized BOOLEAN NOT NULL,
+    content TEXT NOT NULL,
+    PRIMARY KEY (message_id, file_name)
+);
+
+CREATE TABLE attachments (
+    message_id TEXT NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    content_type TEXT NOT NULL,
+    content_id TEXT,
+    size BIGINT NOT NULL,
+    content BYTEA NOT NULL,
+    PRIMARY KEY (message_id, name)
+);
@@ -1,62 +1,164 @@
 pub mod smtp;
 pub mod storage;
 pub mod metrics;
+pub mod proxy_protocol;
+pub mod listener;
 
-use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpListener;
-use tokio::net::TcpStream;
-use tracing::{debug, error, info, instrument};
+use ipnet::IpNet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, instrument, warn};
 
-use crate::smtp::protocol::handle_message;
+use crate::listener::{ClientAddr, Listener};
+use crate::smtp::models::Protocol;
+use crate::smtp::protocol::{handle_message, SessionConfig};
 use crate::storage::Storage;
 
-pub async fn run_server(
-    listener: TcpListener,
-    storage_strategy: Box<dyn Storage>,
-    allowed_addresses: &Vec<String>,
-    mut shutdown_rx: tokio::sync::oneshot::Receiver<()>
+/// Longest command line accepted, above the 512 octets of RFC 5321 as XCLIENT lines may be longer.
+const MAX_COMMAND_LENGTH: usize = 4096;
+
+#[derive(Clone)]
+pub struct ServerOptions {
+    /// Addresses allowed to authenticate, or "*" for any.
+    pub allowed_addresses: Vec<String>,
+    /// How long in-flight sessions may keep running once shutdown was requested.
+    pub drain_timeout: Duration,
+    /// Expect a PROXY protocol v1/v2 header on every connection and use the client address it carries.
+    pub proxy_protocol: bool,
+    /// Whether sessions speak SMTP or LMTP.
+    pub protocol: Protocol,
+    /// Networks of upstream MTAs allowed to forward client attributes through XCLIENT/XFORWARD.
+    pub trusted_networks: Vec<IpNet>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            allowed_addresses: vec![],
+            drain_timeout: Duration::from_secs(30),
+            proxy_protocol: false,
+            protocol: Protocol::Smtp,
+            trusted_networks: vec![],
+        }
+    }
+}
+
+pub async fn run_server<L: Listener>(
+    listener: L,
+    storage: Arc<dyn Storage>,
+    options: ServerOptions,
+    shutdown: CancellationToken,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    info!("Server listening on port {}", listener.local_addr()?.port());
+    info!("Server listening on {}", listener.local_description()?);
 
-    let storage = std::sync::Arc::new(storage_strategy);
+    let options = Arc::new(options);
+    let mut sessions = JoinSet::new();
 
     loop {
         tokio::select!{
             res = listener.accept() => {
                 let (socket, addr) = res?;
                 let storage_strategy = storage.clone();
-                tokio::spawn(handle_client(socket, addr, storage_strategy, allowed_addresses.clone()));
+                sessions.spawn(accept_client(socket, addr, storage_strategy, options.clone(), shutdown.clone()));
             }
-            _ = &mut shutdown_rx => {
-                info!("Shutdown signal received, terminating server.");
+            Some(_) = sessions.join_next(), if !sessions.is_empty() => {}
+            _ = shutdown.cancelled() => {
+                info!("Shutdown signal received, no longer accepting connections.");
                 break;
             }
         }
     }
+    drop(listener);
+
+    info!(sessions = sessions.len(), "Draining active sessions...");
+    let drain = async { while sessions.join_next().await.is_some() {} };
+    if tokio::time::timeout(options.drain_timeout, drain).await.is_err() {
+        warn!(sessions = sessions.len(), "Drain timeout elapsed, aborting remaining sessions.");
+        sessions.shutdown().await;
+    }
+    info!("Server terminated.");
     Ok(())
 }
 
 use crate::metrics::METRICS_INSTANCE;
 
-#[instrument(name = "client_handler", skip(socket, storage, allowed_addresses), fields(client.addr = %addr))]
-async fn handle_client(
-    mut socket: TcpStream,
-    addr: SocketAddr,
-    storage: std::sync::Arc<Box<dyn Storage>>,
-    allowed_addresses: Vec<String>,
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn accept_client<S: AsyncRead + AsyncWrite + Unpin>(
+    mut socket: S,
+    peer_addr: ClientAddr,
+    storage: Arc<dyn Storage>,
+    options: Arc<ServerOptions>,
+    shutdown: CancellationToken,
+) {
+    let addr = if options.proxy_protocol {
+        match tokio::time::timeout(PROXY_HEADER_TIMEOUT, proxy_protocol::read_header(&mut socket)).await {
+            Ok(Ok(Some(client_addr))) => client_addr.into(),
+            // The proxy opened the connection itself (e.g. a health check).
+            Ok(Ok(None)) => peer_addr,
+            Ok(Err(e)) => {
+                warn!(peer.addr = %peer_addr, error.message = %e, "Invalid PROXY protocol header, dropping connection");
+                return;
+            }
+            Err(_) => {
+                warn!(peer.addr = %peer_addr, "Timed out waiting for PROXY protocol header, dropping connection");
+                return;
+            }
+        }
+    } else {
+        peer_addr
+    };
+    handle_client(socket, addr, storage, options, shutdown).await
+}
+
+#[instrument(name = "client_handler", skip(socket, storage, options, shutdown), fields(client.addr = %addr))]
+async fn handle_client<S: AsyncRead + AsyncWrite + Unpin>(
+    mut socket: S,
+    addr: ClientAddr,
+    storage: Arc<dyn Storage>,
+    options: Arc<ServerOptions>,
+    shutdown: CancellationToken,
 ) {
     METRICS_INSTANCE.message_exchange_started.add(1, &[]);
     info!("Connection accepted");
     let mut buf = vec![0; 1024];
+    // Received but not handled yet, as a pipelining client sends several commands at once.
+    let mut pending: Vec<u8> = vec![];
     let mut data_vec: Vec<u8> = vec![];
-    let mut message_metadata = smtp::models::Metadata::default();
+    let mut message_metadata = smtp::models::Metadata {
+        client_address: addr.to_string(),
+        client_ip: addr.ip(),
+        peer_ip: addr.ip(),
+        ..Default::default()
+    };
     let mut state = smtp::models::State::Initialized;
-    let _ = socket
-        .write_all(b"220 localhost ESMTP Service Ready\r\n")
-        .await;
+    let config = SessionConfig {
+        storage: &*storage,
+        allowed_addresses: &options.allowed_addresses,
+        protocol: options.protocol,
+        trusted_networks: &options.trusted_networks,
+    };
+    let greeting: &[u8] = match options.protocol {
+        Protocol::Smtp => b"220 localhost ESMTP Service Ready\r\n",
+        Protocol::Lmtp => b"220 localhost LMTP Service Ready\r\n",
+    };
+    let _ = socket.write_all(greeting).await;
     loop {
-        let n = match socket.read(&mut buf).await {
+        // Sessions in the middle of DATA are left alone so the message can finish storing.
+        let read = tokio::select! {
+            res = socket.read(&mut buf) => res,
+            _ = shutdown.cancelled(), if !matches!(state, smtp::models::State::ProvidingData { .. }) => {
+                info!("Server shutting down, closing idle session");
+                let _ = socket
+                    .write_all(b"421 4.3.2 Service shutting down, closing transmission channel\r\n")
+                    .await;
+                return;
+            }
+        };
+        let n = match read {
             Ok(0) => {
                 info!("Connection closed by client");
                 return;
@@ -68,17 +170,30 @@ async fn handle_client(
             Ok(n) => n,
         };
 
-        let response = handle_message(
-            &buf[0..n],
-            &mut message_metadata,
-            &mut state,
-            &mut data_vec,
-            &**storage,
-            &allowed_addresses,
-        )
-        .await;
-
-        if matches!(state, smtp::models::State::ProvidingData) && response.is_empty() {
+        pending.extend_from_slice(&buf[0..n]);
+        let mut response = vec![];
+        let mut consumed = 0;
+        // Commands and message data are handled one complete line at a time.
+        while consumed < pending.len() {
+            let rest = &pending[consumed..];
+            let receiving_data = matches!(state, smtp::models::State::ProvidingData { .. });
+            let end = match rest.iter().position(|byte| *byte == b'\n') {
+                Some(end) => end + 1,
+                // Lines of message data may be longer than any command, those are handled in pieces.
+                None if receiving_data && rest.len() > MAX_COMMAND_LENGTH => rest.len(),
+                None if rest.len() > MAX_COMMAND_LENGTH => {
+                    consumed = pending.len();
+                    response.push(b"500 5.5.2 Line too long".to_vec());
+                    break;
+                }
+                None => break,
+            };
+            response.extend(handle_message(&rest[..end], &mut message_metadata, &mut state, &mut data_vec, &config).await);
+            consumed += end;
+        }
+        pending.drain(..consumed);
+
+        if response.is_empty() {
             debug!("Accepted data package, waiting for more or delimiter.");
             continue;
         }
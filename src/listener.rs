@@ -0,0 +1,171 @@
+use std::fmt::{Display, Formatter};
+use std::io::Error;
+use std::net::{IpAddr, SocketAddr};
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Where a session comes from, as seen by logging and the message metadata.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClientAddr {
+    Ip(SocketAddr),
+    /// Unix socket peers are usually unnamed.
+    Unix(Option<String>),
+}
+
+impl Display for ClientAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientAddr::Ip(addr) => write!(f, "{}", addr),
+            ClientAddr::Unix(Some(path)) => write!(f, "unix:{}", path),
+            ClientAddr::Unix(None) => write!(f, "unix"),
+        }
+    }
+}
+
+impl ClientAddr {
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            ClientAddr::Ip(addr) => Some(addr.ip()),
+            ClientAddr::Unix(_) => None,
+        }
+    }
+}
+
+impl From<SocketAddr> for ClientAddr {
+    fn from(addr: SocketAddr) -> Self {
+        ClientAddr::Ip(addr)
+    }
+}
+
+/// A source of client connections `run_server` can accept sessions from.
+#[async_trait]
+pub trait Listener: Send + Sync {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    async fn accept(&self) -> Result<(Self::Stream, ClientAddr), Error>;
+
+    /// Human readable description of where the listener is bound, used for logging.
+    fn local_description(&self) -> Result<String, Error>;
+}
+
+#[async_trait]
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept(&self) -> Result<(TcpStream, ClientAddr), Error> {
+        let (socket, addr) = TcpListener::accept(self).await?;
+        Ok((socket, addr.into()))
+    }
+
+    fn local_description(&self) -> Result<String, Error> {
+        Ok(self.local_addr()?.to_string())
+    }
+}
+
+#[cfg(unix)]
+pub use unix::{bind_unix_socket, UnixSocketListener};
+
+#[cfg(unix)]
+mod unix {
+    use std::fs::{DirBuilder, Permissions};
+    use std::io::Error;
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt};
+    use std::path::{Path, PathBuf};
+
+    use async_trait::async_trait;
+    use tokio::net::{UnixListener, UnixStream};
+    use ulid::Ulid;
+
+    use super::{ClientAddr, Listener};
+
+    #[async_trait]
+    impl Listener for UnixListener {
+        type Stream = UnixStream;
+
+        async fn accept(&self) -> Result<(UnixStream, ClientAddr), Error> {
+            let (socket, addr) = UnixListener::accept(self).await?;
+            let path = addr.as_pathname().map(|p| p.display().to_string());
+            Ok((socket, ClientAddr::Unix(path)))
+        }
+
+        fn local_description(&self) -> Result<String, Error> {
+            let addr = self.local_addr()?;
+            let path = addr
+                .as_pathname()
+                .map(|p| p.display().to_string())
+                .unwrap_or_default();
+            Ok(format!("unix:{}", path))
+        }
+    }
+
+    /// A Unix socket bound by `bind_unix_socket`, whose file is removed once the listener is
+    /// dropped, i.e. when the server stops accepting connections.
+    pub struct UnixSocketListener {
+        listener: UnixListener,
+        path: PathBuf,
+        inode: u64,
+    }
+
+    #[async_trait]
+    impl Listener for UnixSocketListener {
+        type Stream = UnixStream;
+
+        async fn accept(&self) -> Result<(UnixStream, ClientAddr), Error> {
+            Listener::accept(&self.listener).await
+        }
+
+        fn local_description(&self) -> Result<String, Error> {
+            Ok(format!("unix:{}", self.path.display()))
+        }
+    }
+
+    impl Drop for UnixSocketListener {
+        fn drop(&mut self) {
+            // Left alone if another process replaced it in the meantime.
+            if std::fs::symlink_metadata(&self.path).is_ok_and(|metadata| metadata.ino() == self.inode) {
+                let _ = std::fs::remove_file(&self.path);
+            }
+        }
+    }
+
+    /// Binds a Unix socket at `path`, replacing a stale socket left by a previous run.
+    ///
+    /// `mode` sets the socket file permissions (e.g. `0o660`) so only the intended
+    /// local users or groups can submit mail. The socket is then bound in a private
+    /// directory and only moved to `path` once its permissions are set, so it is never
+    /// reachable with the default ones.
+    pub fn bind_unix_socket(path: &Path, mode: Option<u32>) -> Result<UnixSocketListener, Error> {
+        if let Ok(file_metadata) = std::fs::symlink_metadata(path) {
+            if file_metadata.file_type().is_socket() {
+                std::fs::remove_file(path)?;
+            }
+        }
+        let listener = match mode {
+            Some(mode) => bind_with_mode(path, mode)?,
+            None => UnixListener::bind(path)?,
+        };
+        let inode = std::fs::symlink_metadata(path)?.ino();
+        Ok(UnixSocketListener {
+            listener,
+            path: path.to_path_buf(),
+            inode,
+        })
+    }
+
+    fn bind_with_mode(path: &Path, mode: u32) -> Result<UnixListener, Error> {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let private_dir = path.with_file_name(format!(".{}.{}", file_name, Ulid::new()));
+        DirBuilder::new().mode(0o700).create(&private_dir)?;
+        let private_path = private_dir.join("socket");
+        let result = UnixListener::bind(&private_path).and_then(|listener| {
+            std::fs::set_permissions(&private_path, Permissions::from_mode(mode))?;
+            std::fs::rename(&private_path, path)?;
+            Ok(listener)
+        });
+        let _ = std::fs::remove_file(&private_path);
+        let _ = std::fs::remove_dir(&private_dir);
+        result
+    }
+}
@@ -1,24 +1,48 @@
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server};
 use smtp2s::metrics::{gather_metrics, setup_metrics_provider};
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::BufReader;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::thread;
+use std::time::Duration;
 
 use aws_config::meta::region::RegionProviderChain;
 use aws_config::BehaviorVersion;
 use aws_sdk_s3::{Client, Config};
-use clap::{command, Parser, ValueEnum};
+use clap::{Parser, ValueEnum};
 use dotenvy::dotenv;
+use futures::future::try_join_all;
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+use ipnet::IpNet;
 use serde::Deserialize;
-use smtp2s::run_server;
-use smtp2s::storage::local::LocalFileStorage;
-use smtp2s::storage::s3::S3FileStorage;
-use smtp2s::storage::Storage;
+#[cfg(unix)]
+use smtp2s::listener::bind_unix_socket;
+use smtp2s::smtp::models::Protocol;
+use smtp2s::{run_server, ServerOptions};
+use smtp2s::storage::azure::{build_azure_blob_storage, CONNECTION_STRING_VARIABLE};
+use smtp2s::storage::bus::kafka::KafkaPublisher;
+use smtp2s::storage::bus::nats::NatsPublisher;
+use smtp2s::storage::bus::redis_streams::RedisStreamsPublisher;
+use smtp2s::storage::bus::{BusContent, BusOptions, BusStorage, TopicTemplate};
+use smtp2s::storage::gcs::build_gcs_storage;
+use smtp2s::storage::local::{LocalFileStorage, LocalOptions};
+use smtp2s::storage::maildir::MaildirStorage;
+use smtp2s::storage::mbox::{MboxSplit, MboxStorage};
+use smtp2s::storage::multi::{MultiPolicy, MultiStorage, NamedStorage};
+use smtp2s::storage::routing::{RouteAction, RoutingRule, RoutingStorage};
+use smtp2s::storage::postgres::{PostgresOptions, PostgresStorage, DATABASE_URL_VARIABLE};
+use smtp2s::storage::s3::{S3FileStorage, S3Options};
+use smtp2s::storage::sqlite::SqliteStorage;
+use smtp2s::storage::webhook::{WebhookOptions, WebhookStorage};
+use smtp2s::storage::{LayoutOptions, Storage, UploadOptions};
 use std::path::PathBuf;
+use std::sync::Arc;
 use tracing::level_filters::LevelFilter;
+use tokio_util::sync::CancellationToken;
 use tracing::{error, info};
 use tracing_appender::non_blocking::WorkerGuard;
 use tracing_subscriber::layer::SubscriberExt;
@@ -52,19 +76,150 @@ pub enum LoggingType {
 enum Strategy {
     Local {
         base_path: String,
+        #[serde(flatten)]
+        layout: LayoutOptions,
+        #[serde(flatten)]
+        options: LocalOptions,
+    },
+    Maildir {
+        path: String,
+        #[serde(default)]
+        per_recipient: bool,
+    },
+    Mbox {
+        /// The mbox file, or the folder holding them when split per recipient or per day.
+        path: String,
+        #[serde(default)]
+        split: MboxSplit,
     },
     S3 {
         bucket_name: String,
         override_aws_endpoint: Option<String>,
+        #[serde(flatten)]
+        layout: LayoutOptions,
+        #[serde(flatten)]
+        options: S3Options,
+    },
+    AzureBlob {
+        container_name: String,
+        /// Falls back to the AZURE_STORAGE_CONNECTION_STRING environment variable.
+        connection_string: Option<String>,
+        #[serde(flatten)]
+        layout: LayoutOptions,
+        #[serde(flatten)]
+        options: UploadOptions,
+    },
+    Gcs {
+        bucket_name: String,
+        /// Service account key or authorized user file, the application default credentials when unset.
+        credentials_file: Option<String>,
+        override_gcs_endpoint: Option<String>,
+        #[serde(flatten)]
+        layout: LayoutOptions,
+        #[serde(flatten)]
+        options: UploadOptions,
+    },
+    Sqlite {
+        path: PathBuf,
+    },
+    Postgres {
+        /// Falls back to the DATABASE_URL environment variable.
+        url: Option<String>,
+        #[serde(flatten)]
+        options: PostgresOptions,
+    },
+    Webhook {
+        url: String,
+        #[serde(flatten)]
+        options: WebhookOptions,
+    },
+    Kafka {
+        brokers: Vec<String>,
+        topic: TopicTemplate,
+        #[serde(default)]
+        content: Content,
+        #[serde(flatten)]
+        options: BusOptions,
+    },
+    Nats {
+        url: String,
+        /// Must be bound to a JetStream stream.
+        subject: TopicTemplate,
+        #[serde(default)]
+        content: Content,
+        #[serde(flatten)]
+        options: BusOptions,
+    },
+    RedisStreams {
+        url: String,
+        stream: TopicTemplate,
+        #[serde(default)]
+        content: Content,
+        #[serde(flatten)]
+        options: BusOptions,
+    },
+    Multi {
+        policy: MultiPolicy,
+        backends: Vec<NamedBackend>,
+    },
+    Routed {
+        backends: Vec<NamedBackend>,
+        rules: Vec<RoutingRule>,
+        default_action: RouteAction,
+    },
+}
+
+/// What the message bus strategies publish besides the metadata.
+#[derive(Deserialize, Debug, Default)]
+#[serde(tag = "type")]
+enum Content {
+    #[default]
+    Inline,
+    /// Store the message with another strategy and publish where it is.
+    Stored { backend: Box<Strategy> },
+}
+
+#[derive(Deserialize, Debug)]
+struct NamedBackend {
+    name: String,
+    #[serde(flatten)]
+    strategy: Strategy,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type")]
+enum Listener {
+    Tcp {
+        port: u16,
+        #[serde(default)]
+        proxy_protocol: bool,
+        #[serde(default)]
+        protocol: Protocol,
+    },
+    Unix {
+        path: String,
+        permissions: Option<String>,
+        #[serde(default)]
+        protocol: Protocol,
     },
 }
 
 #[derive(Deserialize, Debug)]
 struct Smpt2sConfig {
-    port: i16,
+    port: Option<u16>,
+    #[serde(default)]
+    listeners: Vec<Listener>,
     metrics_port: Option<u16>,
     strategy: Strategy,
     allowed_addresses: Vec<String>,
+    #[serde(default = "default_drain_timeout_seconds")]
+    drain_timeout_seconds: u64,
+    #[serde(default)]
+    trusted_networks: Vec<String>,
+}
+
+fn default_drain_timeout_seconds() -> u64 {
+    30
 }
 
 #[tokio::main]
@@ -72,7 +227,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv().ok();
     let args = Smpt2sArgs::parse();
 
-    let _observability_guard = setup_logging(
+    let observability_guard = setup_logging(
         &args.log_level,
         &args.stdout_log_kind,
         &args.file_log_kind,
@@ -88,33 +243,273 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     start_metric_exposure(&config);
 
-    let storage_strategy: Box<dyn Storage> = match config.strategy {
-        Strategy::Local { base_path } => {
-            let storage_path = PathBuf::from(base_path);
-            Box::new(LocalFileStorage {
-                base_path: storage_path,
-            })
+    let storage_strategy = build_storage(config.strategy).await?;
+
+    let mut listeners = config.listeners;
+    if let Some(port) = config.port {
+        listeners.push(Listener::Tcp {
+            port,
+            proxy_protocol: false,
+            protocol: Protocol::Smtp,
+        });
+    }
+    if listeners.is_empty() {
+        return Err("Config must define a port or at least one listener".into());
+    }
+
+    let shutdown = CancellationToken::new();
+    tokio::spawn(wait_for_shutdown_signal(shutdown.clone()));
+    let base_options = ServerOptions {
+        allowed_addresses: config.allowed_addresses,
+        drain_timeout: Duration::from_secs(config.drain_timeout_seconds),
+        trusted_networks: parse_trusted_networks(&config.trusted_networks)?,
+        ..Default::default()
+    };
+
+    let mut servers = Vec::new();
+    for listener in listeners {
+        match listener {
+            Listener::Tcp {
+                port,
+                proxy_protocol,
+                protocol,
+            } => {
+                let listener = tokio::net::TcpListener::bind(format!("127.0.0.1:{}", port)).await?;
+                let options = ServerOptions {
+                    proxy_protocol,
+                    protocol,
+                    ..base_options.clone()
+                };
+                servers.push(
+                    run_server(listener, storage_strategy.clone(), options, shutdown.clone())
+                        .boxed_local(),
+                );
+            }
+            #[cfg(unix)]
+            Listener::Unix {
+                path,
+                permissions,
+                protocol,
+            } => {
+                let mode = match permissions {
+                    Some(permissions) => Some(u32::from_str_radix(&permissions, 8)?),
+                    None => None,
+                };
+                let listener = bind_unix_socket(std::path::Path::new(&path), mode)?;
+                let options = ServerOptions {
+                    protocol,
+                    ..base_options.clone()
+                };
+                servers.push(
+                    run_server(listener, storage_strategy.clone(), options, shutdown.clone())
+                        .boxed_local(),
+                );
+            }
+            #[cfg(not(unix))]
+            Listener::Unix { .. } => {
+                return Err("Unix socket listeners are only supported on Unix platforms".into());
+            }
+        }
+    }
+    let result = try_join_all(servers).await.map(|_| ());
+
+    info!("Flushing logs before exiting...");
+    drop(observability_guard);
+    result
+}
+
+/// Accepts CIDR ranges as well as single addresses.
+fn parse_trusted_networks(networks: &[String]) -> Result<Vec<IpNet>, Box<dyn std::error::Error>> {
+    networks
+        .iter()
+        .map(|network| {
+            network
+                .parse::<IpNet>()
+                .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| format!("Invalid trusted network: {}", network).into())
+        })
+        .collect()
+}
+
+/// Backends are referred to by name, so a name can only be used once.
+fn check_backend_names(strategy: &str, backends: &[NamedBackend]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut names = HashSet::new();
+    for backend in backends {
+        if !names.insert(backend.name.as_str()) {
+            return Err(format!("The {} strategy has more than one backend named {}", strategy, backend.name).into());
+        }
+    }
+    Ok(())
+}
+
+async fn wait_for_shutdown_signal(shutdown: CancellationToken) {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                error!(error.message = %e, "Failed to listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
         }
-        Strategy::S3 {
-            bucket_name,
-            override_aws_endpoint,
-        } => Box::new(build_s3_file_storage(bucket_name, override_aws_endpoint).await),
     };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-    let listener = tokio::net::TcpListener::bind(format!("127.0.0.1:{}", config.port)).await?;
-    let (_shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
-    run_server(
-        listener,
-        storage_strategy,
-        &config.allowed_addresses,
-        shutdown_rx,
-    )
-    .await
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+        _ = terminate => info!("Received SIGTERM"),
+    }
+    shutdown.cancel();
+}
+
+fn build_storage(strategy: Strategy) -> LocalBoxFuture<'static, Result<Arc<dyn Storage>, Box<dyn std::error::Error>>> {
+    async move {
+        let storage: Arc<dyn Storage> = match strategy {
+            Strategy::Local {
+                base_path,
+                layout,
+                options,
+            } => {
+                let storage_path = PathBuf::from(base_path);
+                Arc::new(LocalFileStorage {
+                    base_path: storage_path,
+                    layout,
+                    options,
+                })
+            }
+            Strategy::Maildir { path, per_recipient } => Arc::new(MaildirStorage::new(PathBuf::from(path), per_recipient)),
+            Strategy::Mbox { path, split } => Arc::new(MboxStorage::new(PathBuf::from(path), split)),
+            Strategy::S3 {
+                bucket_name,
+                override_aws_endpoint,
+                layout,
+                options,
+            } => Arc::new(build_s3_file_storage(bucket_name, override_aws_endpoint, layout, options).await),
+            Strategy::AzureBlob {
+                container_name,
+                connection_string,
+                layout,
+                options,
+            } => {
+                let connection_string = match connection_string {
+                    Some(connection_string) => connection_string,
+                    None => std::env::var(CONNECTION_STRING_VARIABLE).map_err(|_| {
+                        format!("The AzureBlob strategy needs a connection_string or {}", CONNECTION_STRING_VARIABLE)
+                    })?,
+                };
+                Arc::new(build_azure_blob_storage(container_name, &connection_string, layout, options)?)
+            }
+            Strategy::Gcs {
+                bucket_name,
+                credentials_file,
+                override_gcs_endpoint,
+                layout,
+                options,
+            } => Arc::new(build_gcs_storage(bucket_name, credentials_file, override_gcs_endpoint, layout, options)?),
+            Strategy::Sqlite { path } => Arc::new(SqliteStorage::connect(&path).await?),
+            Strategy::Postgres { url, options } => {
+                let url = match url {
+                    Some(url) => url,
+                    None => std::env::var(DATABASE_URL_VARIABLE)
+                        .map_err(|_| format!("The Postgres strategy needs a url or {}", DATABASE_URL_VARIABLE))?,
+                };
+                Arc::new(PostgresStorage::connect(&url, &options).await?)
+            }
+            Strategy::Webhook { url, options } => Arc::new(WebhookStorage::new(url, options)?),
+            Strategy::Kafka {
+                brokers,
+                topic,
+                content,
+                options,
+            } => {
+                let content = build_bus_content(content).await?;
+                let publisher = KafkaPublisher::connect(brokers, Duration::from_secs(options.timeout_seconds)).await?;
+                Arc::new(BusStorage::new(Box::new(publisher), topic, content, options))
+            }
+            Strategy::Nats {
+                url,
+                subject,
+                content,
+                options,
+            } => {
+                let content = build_bus_content(content).await?;
+                let publisher = NatsPublisher::connect(&url).await?;
+                Arc::new(BusStorage::new(Box::new(publisher), subject, content, options))
+            }
+            Strategy::RedisStreams {
+                url,
+                stream,
+                content,
+                options,
+            } => {
+                let content = build_bus_content(content).await?;
+                let publisher = RedisStreamsPublisher::connect(&url).await?;
+                Arc::new(BusStorage::new(Box::new(publisher), stream, content, options))
+            }
+            Strategy::Multi { policy, backends } => {
+                if backends.is_empty() {
+                    return Err("The Multi strategy needs at least one backend".into());
+                }
+                check_backend_names("Multi", &backends)?;
+                let mut named_backends = vec![];
+                for backend in backends {
+                    named_backends.push(NamedStorage {
+                        name: backend.name,
+                        storage: build_storage(backend.strategy).await?,
+                    });
+                }
+                Arc::new(MultiStorage::new(named_backends, policy))
+            }
+            Strategy::Routed {
+                backends,
+                rules,
+                default_action,
+            } => {
+                check_backend_names("Routed", &backends)?;
+                let mut named_backends = HashMap::new();
+                for backend in backends {
+                    named_backends.insert(backend.name, build_storage(backend.strategy).await?);
+                }
+                Arc::new(RoutingStorage::new(named_backends, rules, default_action)?)
+            }
+        };
+        Ok(storage)
+    }
+    .boxed_local()
+}
+
+async fn build_bus_content(content: Content) -> Result<BusContent, Box<dyn std::error::Error>> {
+    match content {
+        Content::Inline => Ok(BusContent::Inline),
+        Content::Stored { backend } => {
+            if !reports_location(&backend) {
+                return Err("Stored content needs a Local, S3, AzureBlob or Gcs backend, or a Multi or Routed one made of them".into());
+            }
+            Ok(BusContent::Stored(build_storage(*backend).await?))
+        }
+    }
+}
+
+/// Whether the backend tells where each message is stored, which the published envelopes point to.
+fn reports_location(strategy: &Strategy) -> bool {
+    match strategy {
+        Strategy::Local { .. } | Strategy::S3 { .. } | Strategy::AzureBlob { .. } | Strategy::Gcs { .. } => true,
+        Strategy::Multi { backends, .. } | Strategy::Routed { backends, .. } => {
+            backends.iter().all(|backend| reports_location(&backend.strategy))
+        }
+        _ => false,
+    }
 }
 
 async fn build_s3_file_storage(
     bucket_name: String,
     override_aws_endpoint: Option<String>,
+    layout: LayoutOptions,
+    options: S3Options,
 ) -> S3FileStorage {
     let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
     // Gets the default AWS config from environment (~/.aws/config)
@@ -135,7 +530,7 @@ async fn build_s3_file_storage(
         None => Client::new(&shared_config),
     };
 
-    return S3FileStorage::new(client, bucket_name);
+    S3FileStorage::new(client, bucket_name, layout, options)
 }
 
 pub fn setup_logging(
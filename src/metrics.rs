@@ -18,6 +18,8 @@ pub struct Metrics {
     pub message_processed_successfully: Counter<u64>,
     pub data_storage_timing: Histogram<f64>,
     pub attachments_stored: Counter<u64>,
+    pub storage_failures: Counter<u64>,
+    pub messages_routed: Counter<u64>,
 }
 
 impl Metrics {
@@ -46,6 +48,14 @@ impl Metrics {
                 .u64_counter("attachments_stored")
                 .with_description("Counts the number of stored attachments.")
                 .init(),
+            storage_failures: meter
+                .u64_counter("storage_failures")
+                .with_description("Counts the number of messages that could not be stored.")
+                .init(),
+            messages_routed: meter
+                .u64_counter("messages_routed")
+                .with_description("Counts the number of messages per routing action and backend.")
+                .init(),
         }
     }
 }
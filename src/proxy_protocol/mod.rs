@@ -0,0 +1,104 @@
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Reads a PROXY protocol (v1 or v2) header from the start of `stream`.
+///
+/// Returns the original client address, or `None` when the proxy did not forward one
+/// (v1 `UNKNOWN`, v2 `LOCAL` commands and non-IP address families, used by health checks).
+/// Only the header bytes are consumed, so the SMTP exchange can continue on the same stream.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>, Error> {
+    // Both versions are at least this long: "PROXY UNKNOWN\r\n" has 15 bytes, v2 has 16.
+    let mut header = vec![0; 8];
+    stream.read_exact(&mut header).await?;
+
+    if header.starts_with(V1_PREFIX) {
+        while !header.ends_with(b"\r\n") {
+            if header.len() >= V1_MAX_LENGTH {
+                return Err(invalid("PROXY v1 header exceeds maximum length"));
+            }
+            header.push(stream.read_u8().await?);
+        }
+        parse_v1(&header)
+    } else if header == V2_SIGNATURE[..8] {
+        header.resize(16, 0);
+        stream.read_exact(&mut header[8..]).await?;
+        if header[..12] != *V2_SIGNATURE {
+            return Err(invalid("Invalid PROXY v2 signature"));
+        }
+        let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+        let mut addresses = vec![0; length];
+        stream.read_exact(&mut addresses).await?;
+        parse_v2(header[12], header[13], &addresses)
+    } else {
+        Err(invalid("Connection did not start with a PROXY protocol header"))
+    }
+}
+
+fn parse_v1(header: &[u8]) -> Result<Option<SocketAddr>, Error> {
+    let line = std::str::from_utf8(header)
+        .map_err(|_| invalid("PROXY v1 header is not valid ASCII"))?
+        .trim_end();
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _destination, source_port, _destination_port] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| invalid("Invalid source address in PROXY v1 header"))?;
+            let port: u16 = source_port
+                .parse()
+                .map_err(|_| invalid("Invalid source port in PROXY v1 header"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("Malformed PROXY v1 header")),
+    }
+}
+
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> Result<Option<SocketAddr>, Error> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("Unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0F {
+        // LOCAL: the connection was opened by the proxy itself.
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("Unsupported PROXY v2 command")),
+    }
+
+    match family >> 4 {
+        // AF_INET
+        0x1 => {
+            if addresses.len() < 12 {
+                return Err(invalid("Truncated PROXY v2 IPv4 addresses"));
+            }
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // AF_INET6
+        0x2 => {
+            if addresses.len() < 36 {
+                return Err(invalid("Truncated PROXY v2 IPv6 addresses"));
+            }
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)))
+        }
+        // AF_UNSPEC and AF_UNIX carry no usable client address.
+        _ => Ok(None),
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,66 @@
+use super::*;
+
+#[tokio::test]
+async fn test_v1_tcp4_header() {
+    let mut stream: &[u8] = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 25\r\nEHLO test\r\n";
+
+    let address = read_header(&mut stream).await.unwrap();
+
+    assert_eq!(address, Some("192.168.0.1:56324".parse().unwrap()));
+    // Only the header is consumed, the SMTP exchange is left untouched.
+    assert_eq!(stream, b"EHLO test\r\n");
+}
+
+#[tokio::test]
+async fn test_v1_tcp6_header() {
+    let mut stream: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 25\r\n";
+
+    let address = read_header(&mut stream).await.unwrap();
+
+    assert_eq!(address, Some("[2001:db8::1]:4000".parse().unwrap()));
+}
+
+#[tokio::test]
+async fn test_v1_unknown_header() {
+    let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+
+    let address = read_header(&mut stream).await.unwrap();
+
+    assert_eq!(address, None);
+}
+
+#[tokio::test]
+async fn test_v2_ipv4_header() {
+    let mut header = V2_SIGNATURE.to_vec();
+    header.extend_from_slice(&[0x21, 0x11, 0x00, 0x0C]);
+    header.extend_from_slice(&[10, 0, 0, 7, 10, 0, 0, 1]);
+    header.extend_from_slice(&50000u16.to_be_bytes());
+    header.extend_from_slice(&25u16.to_be_bytes());
+    header.extend_from_slice(b"EHLO test\r\n");
+    let mut stream: &[u8] = &header;
+
+    let address = read_header(&mut stream).await.unwrap();
+
+    assert_eq!(address, Some("10.0.0.7:50000".parse().unwrap()));
+    assert_eq!(stream, b"EHLO test\r\n");
+}
+
+#[tokio::test]
+async fn test_v2_local_command() {
+    let mut header = V2_SIGNATURE.to_vec();
+    header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+    let mut stream: &[u8] = &header;
+
+    let address = read_header(&mut stream).await.unwrap();
+
+    assert_eq!(address, None);
+}
+
+#[tokio::test]
+async fn test_missing_header_is_rejected() {
+    let mut stream: &[u8] = b"EHLO test.client\r\n";
+
+    let result = read_header(&mut stream).await;
+
+    assert!(result.is_err());
+}
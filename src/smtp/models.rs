@@ -1,8 +1,20 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
 
 #[derive(Default, Serialize, Debug, Clone)]
 pub struct Metadata {
     pub client: String,
+    /// The connecting peer, e.g. `10.1.2.3:41234`, or the client an XCLIENT command stands for, as
+    /// Postfix logs it, e.g. `mail.example.com[203.0.113.5]:41234`.
+    pub client_address: String,
+    #[serde(skip)]
+    pub client_ip: Option<IpAddr>,
+    /// Address of the connecting peer, which XCLIENT doesn't replace. Attribute forwarding is only
+    /// allowed when it is in a trusted network.
+    #[serde(skip)]
+    pub peer_ip: Option<IpAddr>,
+    /// Original client attributes relayed by a trusted MTA through XCLIENT or XFORWARD.
+    pub forwarded: Option<ForwardedClient>,
     pub authenticated_user: Option<String>,
     pub from: String,
     pub recipients: Vec<String>,
@@ -12,6 +24,51 @@ pub struct Metadata {
     pub subject: String,
     pub date: Option<String>,
     pub message_id: Option<String>,
+    pub bodies: Vec<BodyPart>,
+}
+
+impl Metadata {
+    /// Lowercased domain of the first recipient, which is the only one in LMTP deliveries.
+    pub fn recipient_domain(&self) -> Option<String> {
+        self.recipients
+            .first()
+            .and_then(|recipient| recipient.rsplit_once('@'))
+            .map(|(_, domain)| domain.to_lowercase())
+    }
+
+    /// Clears what was collected for the last message, keeping what is known about the client.
+    pub fn reset_transaction(&mut self) {
+        *self = Metadata {
+            client: std::mem::take(&mut self.client),
+            client_address: std::mem::take(&mut self.client_address),
+            client_ip: self.client_ip,
+            peer_ip: self.peer_ip,
+            forwarded: self.forwarded.take(),
+            authenticated_user: self.authenticated_user.take(),
+            ..Default::default()
+        };
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct BodyPart {
+    pub file_name: String,
+    pub content_type: &'static str,
+    pub index: usize,
+    /// Converted from a body of the other type, or a placeholder when the message had no body.
+    pub synthesized: bool,
+}
+
+#[derive(Default, Serialize, Debug, Clone)]
+pub struct ForwardedClient {
+    pub name: Option<String>,
+    pub addr: Option<String>,
+    pub port: Option<String>,
+    pub proto: Option<String>,
+    pub helo: Option<String>,
+    pub login: Option<String>,
+    pub ident: Option<String>,
+    pub source: Option<String>,
 }
 
 pub enum AuthState {
@@ -34,6 +91,17 @@ pub enum State {
     ProvidingHeaders {
         state: HeadersState,
     },
-    ProvidingData,
+    ProvidingData {
+        /// Set once the message went past the advertised SIZE, the rest of it is read and dropped.
+        oversized: bool,
+    },
     Quitting,
 }
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    #[default]
+    Smtp,
+    /// RFC 2033 Local Mail Transfer Protocol, used for final delivery from MTAs such as Postfix.
+    Lmtp,
+}
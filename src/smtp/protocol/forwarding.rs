@@ -0,0 +1,142 @@
+use crate::metrics::METRICS_INSTANCE;
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use tracing::{info, warn};
+
+use crate::smtp::models::{ForwardedClient, Metadata, Protocol, State};
+
+const XCLIENT_ATTRIBUTES: &[&str] = &["NAME", "ADDR", "PORT", "PROTO", "HELO", "LOGIN", "DESTADDR", "DESTPORT"];
+const XFORWARD_ATTRIBUTES: &[&str] = &["NAME", "ADDR", "PORT", "PROTO", "HELO", "IDENT", "SOURCE"];
+
+/// Whether the session comes from one of the networks allowed to forward client attributes.
+pub fn is_trusted(message_metadata: &Metadata, trusted_networks: &[IpNet]) -> bool {
+    match message_metadata.peer_ip {
+        Some(ip) => trusted_networks.iter().any(|network| network.contains(&ip.to_canonical())),
+        None => false,
+    }
+}
+
+/// EHLO/LHLO capability lines, only advertised to trusted clients.
+pub fn capabilities() -> Vec<Vec<u8>> {
+    vec![
+        format!("250-XCLIENT {}", XCLIENT_ATTRIBUTES.join(" ")).into_bytes(),
+        format!("250-XFORWARD {}", XFORWARD_ATTRIBUTES.join(" ")).into_bytes(),
+    ]
+}
+
+/// Handles Postfix's XCLIENT and XFORWARD commands, returning `None` for any other command.
+pub fn handle_forwarding_command(
+    buffer_str: &str,
+    message_metadata: &mut Metadata,
+    state: &mut State,
+    allowed_addresses: &[String],
+    protocol: Protocol,
+    trusted_networks: &[IpNet],
+) -> Option<Vec<Vec<u8>>> {
+    let (command, arguments) = buffer_str.split_once(' ').unwrap_or((buffer_str, ""));
+    let is_xclient = command.eq_ignore_ascii_case("XCLIENT");
+    if !is_xclient && !command.eq_ignore_ascii_case("XFORWARD") {
+        return None;
+    }
+
+    if !is_trusted(message_metadata, trusted_networks) {
+        warn!(command, "Rejected attribute forwarding from untrusted client");
+        return Some(vec![b"550 5.7.0 Insufficient authorization".to_vec()]);
+    }
+
+    let supported = if is_xclient { XCLIENT_ATTRIBUTES } else { XFORWARD_ATTRIBUTES };
+    let mut forwarded = message_metadata.forwarded.clone().unwrap_or_default();
+    let mut identity_forwarded = false;
+    for attribute in arguments.split_whitespace() {
+        let (name, value) = match attribute.split_once('=') {
+            Some((name, value)) if supported.iter().any(|s| s.eq_ignore_ascii_case(name)) => {
+                (name.to_ascii_uppercase(), decode_xtext(value))
+            }
+            _ => {
+                return Some(vec![format!("501 5.5.4 Bad {} attribute: {}", command.to_ascii_uppercase(), attribute).into_bytes()])
+            }
+        };
+        identity_forwarded |= matches!(name.as_str(), "NAME" | "ADDR" | "PORT");
+        let field = match name.as_str() {
+            "NAME" => &mut forwarded.name,
+            "ADDR" => &mut forwarded.addr,
+            "PORT" => &mut forwarded.port,
+            "PROTO" => &mut forwarded.proto,
+            "HELO" => &mut forwarded.helo,
+            "LOGIN" => &mut forwarded.login,
+            "IDENT" => &mut forwarded.ident,
+            "SOURCE" => &mut forwarded.source,
+            // Destination attributes describe smtp2s itself, nothing to record.
+            _ => continue,
+        };
+        *field = value;
+    }
+    info!(?forwarded, "Received forwarded client attributes");
+
+    if !is_xclient {
+        message_metadata.forwarded = Some(forwarded);
+        return Some(vec![b"250 2.0.0 Ok".to_vec()]);
+    }
+
+    // XCLIENT replaces the session identity, including who is authenticated.
+    let client_ip = match forwarded.addr.as_deref().map(str::parse::<IpAddr>) {
+        Some(Ok(ip)) => Some(ip),
+        Some(Err(_)) => {
+            return Some(vec![format!("501 5.5.4 Bad XCLIENT attribute: ADDR={}", forwarded.addr.unwrap()).into_bytes()])
+        }
+        None => None,
+    };
+    if let Some(login) = &forwarded.login {
+        if !allowed_addresses.contains(login) && !allowed_addresses.contains(&"*".to_string()) {
+            METRICS_INSTANCE.authorization_failed.add(1, &[]);
+            return Some(vec![b"535 5.7.8 Authentication credentials invalid".to_vec()]);
+        }
+    }
+    if identity_forwarded {
+        message_metadata.client_ip = client_ip;
+        message_metadata.client_address = client_address(&forwarded);
+    }
+    message_metadata.authenticated_user = forwarded.login.clone();
+    message_metadata.forwarded = Some(forwarded);
+    *state = State::Initialized;
+    let greeting: &[u8] = match protocol {
+        Protocol::Smtp => b"220 localhost ESMTP Service Ready",
+        Protocol::Lmtp => b"220 localhost LMTP Service Ready",
+    };
+    Some(vec![greeting.to_vec()])
+}
+
+/// The forwarded client as Postfix logs it, e.g. `mail.example.com[203.0.113.5]:41234`, with
+/// `unknown` for the name or address the upstream MTA didn't have.
+fn client_address(forwarded: &ForwardedClient) -> String {
+    let name = forwarded.name.as_deref().unwrap_or("unknown");
+    let addr = forwarded.addr.as_deref().unwrap_or("unknown");
+    match &forwarded.port {
+        Some(port) => format!("{}[{}]:{}", name, addr, port),
+        None => format!("{}[{}]", name, addr),
+    }
+}
+
+/// Decodes an RFC 3461 xtext value, mapping Postfix's placeholders for missing values to `None`.
+fn decode_xtext(value: &str) -> Option<String> {
+    if value.eq_ignore_ascii_case("[UNAVAILABLE]") || value.eq_ignore_ascii_case("[TEMPUNAVAIL]") {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(value.len());
+    let bytes = value.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'+' {
+            if let Some(Ok(byte)) = value.get(i + 1..i + 3).map(|hex| u8::from_str_radix(hex, 16)) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    let decoded = String::from_utf8_lossy(&decoded).into_owned();
+    Some(decoded.strip_prefix("IPV6:").map(String::from).unwrap_or(decoded))
+}
@@ -1,41 +1,76 @@
 use crate::metrics::METRICS_INSTANCE;
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
-use mail_parser::{Address, MessageParser};
+use ipnet::IpNet;
+use mail_parser::{Address, Message, MessageParser};
 use tracing::{debug, error, info};
-use twoway::{find_str, rfind_bytes};
+use twoway::find_str;
 
-use crate::smtp::models::{AuthState, HeadersState, Metadata, State};
-use crate::storage::Storage;
+use crate::smtp::models::{AuthState, HeadersState, Metadata, Protocol, State};
+use crate::storage::body::describe_bodies;
+use crate::storage::{Storage, StorageError};
+
+mod forwarding;
+use forwarding::handle_forwarding_command;
+
+/// What a session is configured with, the same for every command it handles.
+pub struct SessionConfig<'a> {
+    pub storage: &'a dyn Storage,
+    /// Addresses allowed to authenticate, or "*" for any.
+    pub allowed_addresses: &'a [String],
+    pub protocol: Protocol,
+    /// Networks of upstream MTAs allowed to forward client attributes through XCLIENT/XFORWARD.
+    pub trusted_networks: &'a [IpNet],
+}
 
 pub async fn handle_message(
     buffer: &[u8],
     message_metadata: &mut Metadata,
     state: &mut State,
     data_vec: &mut Vec<u8>,
-    storage: &dyn Storage,
-    allowed_addresses: &Vec<String>,
+    config: &SessionConfig<'_>,
 ) -> Vec<Vec<u8>> {
+    let SessionConfig {
+        storage,
+        allowed_addresses,
+        protocol,
+        trusted_networks,
+    } = *config;
+
+    // Message data is taken as is, it needs not be UTF-8.
+    if matches!(state, State::ProvidingData { .. }) {
+        return handle_data(buffer, message_metadata, state, data_vec, storage, protocol).await;
+    }
+
     let buffer_str = match std::str::from_utf8(buffer) {
-        Ok(s) => {
-            if matches!(state, State::ProvidingData) {
-                s
-            } else {
-                s.trim()
-            }
-        }
-        Err(_) => return vec![b"500 Invalid UTF-8 sequence".to_vec()],
+        Ok(s) => s.trim(),
+        Err(_) => return vec![b"500 5.5.2 Invalid UTF-8 sequence".to_vec()],
     };
 
     debug!(buffer_str, "Received command");
 
+    // Upstream MTAs forward the original client attributes before starting a transaction.
+    if matches!(
+        state,
+        State::Authenticating { state: AuthState::AwaithAuthRequest, .. }
+            | State::ProvidingHeaders { state: HeadersState::ProvidingFrom }
+    ) {
+        if let Some(response) =
+            handle_forwarding_command(buffer_str, message_metadata, state, allowed_addresses, protocol, trusted_networks)
+        {
+            return response;
+        }
+    }
+
     match state {
-        State::Initialized => initialize_trade(buffer_str, message_metadata, state),
+        State::Initialized => initialize_trade(buffer_str, message_metadata, state, protocol, trusted_networks),
         State::Authenticating { .. } => handle_auth_process(buffer_str, message_metadata, state, allowed_addresses),
-        State::ProvidingHeaders { .. } => handle_headers(buffer_str, message_metadata, state),
-        State::ProvidingData => {
-            handle_data(buffer_str, message_metadata, state, data_vec, storage).await
+        State::ProvidingHeaders { .. } if buffer_str.eq_ignore_ascii_case("RSET") => {
+            end_transaction(message_metadata, state, data_vec);
+            vec![b"250 2.0.0 OK".to_vec()]
         }
+        State::ProvidingHeaders { .. } => handle_headers(buffer_str, message_metadata, state),
+        State::ProvidingData { .. } => unreachable!("message data is handled before commands"),
         State::Quitting => handle_quit(buffer_str),
     }
 }
@@ -44,35 +79,63 @@ fn initialize_trade(
     buffer_str: &str,
     message_metadata: &mut Metadata,
     state: &mut State,
+    protocol: Protocol,
+    trusted_networks: &[IpNet],
 ) -> Vec<Vec<u8>> {
+    let greeting_command = match protocol {
+        Protocol::Smtp => "EHLO",
+        Protocol::Lmtp => "LHLO",
+    };
     let (command, client) = match buffer_str.split_once(' ') {
         Some((cmd, cl)) => (cmd, cl),
-        None => return vec![b"501 Syntax error, expected: EHLO <domain>".to_vec()],
+        None => {
+            return vec![format!("501 Syntax error, expected: {} <domain>", greeting_command).into_bytes()]
+        }
     };
 
-    if !command.eq_ignore_ascii_case("EHLO") {
-        return vec![b"552 Initial message must be EHLO".to_vec()];
+    if !command.eq_ignore_ascii_case(greeting_command) {
+        return vec![format!("552 Initial message must be {}", greeting_command).into_bytes()];
     }
     message_metadata.client = client.trim().into();
-    *state = State::Authenticating {
-        state: AuthState::AwaithAuthRequest,
-        username: None,
-    };
-    vec![
-        format!("250-smtp2s greets {}", client)
-            .as_bytes()
-            .to_vec(),
-        b"250-AUTH LOGIN PLAIN".to_vec(),
-        b"250-SIZE 104857600".to_vec(),
-        b"250 8BITMIME".to_vec(),
-    ]
+
+    let mut response = vec![format!("250-smtp2s greets {}", client).as_bytes().to_vec()];
+    match protocol {
+        // Clients already authenticated through XCLIENT LOGIN go straight to the transaction.
+        Protocol::Smtp if message_metadata.authenticated_user.is_some() => {
+            *state = State::ProvidingHeaders {
+                state: HeadersState::ProvidingFrom,
+            };
+        }
+        Protocol::Smtp => {
+            *state = State::Authenticating {
+                state: AuthState::AwaithAuthRequest,
+                username: None,
+            };
+            response.push(b"250-AUTH LOGIN PLAIN".to_vec());
+        }
+        // LMTP is spoken by trusted MTAs doing final delivery, so there is no authentication step.
+        // RFC 2033 requires LMTP servers to support both extensions.
+        Protocol::Lmtp => {
+            *state = State::ProvidingHeaders {
+                state: HeadersState::ProvidingFrom,
+            };
+            response.push(b"250-PIPELINING".to_vec());
+            response.push(b"250-ENHANCEDSTATUSCODES".to_vec());
+        }
+    }
+    if forwarding::is_trusted(message_metadata, trusted_networks) {
+        response.extend(forwarding::capabilities());
+    }
+    response.push(format!("250-SIZE {}", MAX_MESSAGE_SIZE).into_bytes());
+    response.push(b"250 8BITMIME".to_vec());
+    response
 }
 
 fn handle_auth_process(
     buffer_str: &str,
     message_metadata: &mut Metadata,
     state: &mut State,
-    allowed_addresses: &Vec<String>,
+    allowed_addresses: &[String],
 ) -> Vec<Vec<u8>> {
     let (auth_state, username) = if let State::Authenticating { state, username } = state {
         (state, username)
@@ -93,12 +156,12 @@ fn handle_auth_process(
             let decoded_username = match BASE64_STANDARD.decode(buffer_str) {
                 Ok(bytes) => bytes,
                 Err(_) => {
-                    return vec![b"501 Syntax error in parameters (malformed base64)".to_vec()];
+                    return vec![b"501 5.5.2 Syntax error in parameters (malformed base64)".to_vec()];
                 }
             };
             let parsed_username = match String::from_utf8(decoded_username) {
                 Ok(s) => s,
-                Err(_) => return vec![b"552 Invalid UTF-8 in username".to_vec()],
+                Err(_) => return vec![b"552 5.5.2 Invalid UTF-8 in username".to_vec()],
             };
 
             info!(?parsed_username, "Received username");
@@ -135,93 +198,217 @@ fn handle_headers(
         HeadersState::ProvidingFrom => {
             let (command, mail_from) = match buffer_str.split_once(':') {
                 Some((cmd, mail_from)) => (cmd, sanitize_address(mail_from)),
-                None => return vec![b"501 Syntax error, expected: 'MAIL FROM:<address>'".to_vec()],
+                None => return vec![b"501 5.5.4 Syntax error, expected: 'MAIL FROM:<address>'".to_vec()],
             };
             if !command.eq_ignore_ascii_case("MAIL FROM") {
-                return vec![b"501 Syntax error, expected: 'MAIL FROM:<address>'".to_vec()];
+                return vec![b"501 5.5.4 Syntax error, expected: 'MAIL FROM:<address>'".to_vec()];
+            }
+            if declared_size(buffer_str).is_some_and(|size| size > MAX_MESSAGE_SIZE) {
+                return vec![b"552 5.3.4 Message size exceeds fixed maximum message size".to_vec()];
             }
             message_metadata.from = mail_from.into();
             *headers_state = HeadersState::ProvidingRecipients;
-            vec![b"250 OK".to_vec()]
+            vec![b"250 2.1.0 OK".to_vec()]
         }
         HeadersState::ProvidingRecipients => {
             if buffer_str.eq_ignore_ascii_case("DATA") {
                 if message_metadata.recipients.is_empty() {
                     return vec![
-                        b"503 Client must provide at least one recipient before calling DATA"
+                        b"503 5.5.1 Client must provide at least one recipient before calling DATA"
                             .to_vec(),
                     ];
                 }
-                *state = State::ProvidingData;
+                *state = State::ProvidingData { oversized: false };
                 return vec![b"354 End data with <CRLF>.<CRLF>".to_vec()];
             }
             let (command, mail_to) = match buffer_str.split_once(':') {
                 Some((cmd, mail_to)) => (cmd, sanitize_address(mail_to)),
-                None => return vec![b"501 Syntax error, expected: 'RCPT TO:<address>'".to_vec()],
+                None => return vec![b"501 5.5.4 Syntax error, expected: 'RCPT TO:<address>'".to_vec()],
             };
             if !command.eq_ignore_ascii_case("RCPT TO") {
-                return vec![b"501 Syntax error, expected: 'RCPT TO:<address>'".to_vec()];
+                return vec![b"501 5.5.4 Syntax error, expected: 'RCPT TO:<address>'".to_vec()];
             }
             if !message_metadata.recipients.contains(&mail_to.to_string()) {
                 message_metadata.recipients.push(mail_to.into());
             }
-            vec![b"250 OK".to_vec()]
+            vec![b"250 2.1.5 OK".to_vec()]
         }
     }
 }
 
-const DATA_TERMINATOR: &str = "\r\n.\r\n";
+/// Largest message accepted, advertised through the SIZE extension of RFC 1870.
+pub const MAX_MESSAGE_SIZE: usize = 100 * 1024 * 1024;
 
 async fn handle_data(
-    buffer_str: &str,
+    buffer: &[u8],
     message_metadata: &mut Metadata,
     state: &mut State,
     data_vec: &mut Vec<u8>,
     storage: &dyn Storage,
+    protocol: Protocol,
 ) -> Vec<Vec<u8>> {
-    data_vec.extend_from_slice(buffer_str.as_bytes());
+    let State::ProvidingData { oversized } = state else {
+        unreachable!("handle_data called with a state other than ProvidingData");
+    };
+    let complete = unstuff_data(buffer, data_vec);
+    if data_vec.len() > MAX_MESSAGE_SIZE && !*oversized {
+        info!("Message exceeds {} bytes, discarding the rest of it", MAX_MESSAGE_SIZE);
+        *oversized = true;
+    }
+    if *oversized {
+        // Only the last byte is kept, to tell whether the next data starts a line.
+        data_vec.drain(..data_vec.len().saturating_sub(1));
+    }
+    if !complete {
+        return vec![];
+    }
 
-    if rfind_bytes(&data_vec, DATA_TERMINATOR.as_bytes()).is_some() {
-        let relevant_buffer_str = sanitize_dot_stuffing(std::str::from_utf8(&data_vec).unwrap());
-        let message = match MessageParser::default().parse(&relevant_buffer_str) {
-            Some(message) => message,
-            None => return vec![b"501 Syntax Error, could not parse provided data.".to_vec()],
+    if *oversized {
+        let reply = b"552 5.3.4 Message size exceeds fixed maximum message size".to_vec();
+        return match protocol {
+            Protocol::Smtp => {
+                *state = State::Quitting;
+                vec![reply]
+            }
+            Protocol::Lmtp => {
+                let replies = vec![reply; message_metadata.recipients.len()];
+                end_transaction(message_metadata, state, data_vec);
+                replies
+            }
         };
+    }
+
+    let message = match MessageParser::default().parse(data_vec.as_slice()) {
+        Some(message) => message,
+        None => {
+            let reply = b"501 5.6.0 Syntax Error, could not parse provided data.".to_vec();
+            return match protocol {
+                Protocol::Smtp => {
+                    *state = State::Quitting;
+                    data_vec.clear();
+                    vec![reply]
+                }
+                Protocol::Lmtp => {
+                    let replies = vec![reply; message_metadata.recipients.len()];
+                    end_transaction(message_metadata, state, data_vec);
+                    replies
+                }
+            };
+        }
+    };
 
-        message_metadata.to = address_to_vec(&message.to());
-        message_metadata.cc = address_to_vec(&message.cc());
-        message_metadata.bcc = address_to_vec(&message.bcc());
-        message_metadata.subject = message.subject().map(String::from).unwrap_or_default();
-        message_metadata.date = message.date().map(|d| d.to_rfc3339());
-        message_metadata.message_id = message.message_id().map(String::from);
+    message_metadata.to = address_to_vec(&message.to());
+    message_metadata.cc = address_to_vec(&message.cc());
+    message_metadata.bcc = address_to_vec(&message.bcc());
+    message_metadata.subject = message.subject().map(String::from).unwrap_or_default();
+    message_metadata.date = message.date().map(|d| d.to_rfc3339());
+    message_metadata.message_id = message.message_id().map(String::from);
+    message_metadata.bodies = describe_bodies(&message);
+
+    if protocol == Protocol::Lmtp {
+        let replies = deliver_per_recipient(message_metadata, &message, storage).await;
+        drop(message);
+        end_transaction(message_metadata, state, data_vec);
+        return replies;
+    }
 
-        if let Err(e) = storage.save(message_metadata, &message).await {
-            error!(error.message = %e, "Failed to save message");
-            return vec![b"554 Transaction failed".to_vec()];
+    // The transaction is over either way, the buffered data must not be stored again.
+    *state = State::Quitting;
+    if let Err(e) = storage.save(message_metadata, &message).await {
+        error!(error.message = %e, "Failed to save message");
+        let reply: &[u8] = match e {
+            StorageError::Transient(_) => b"451 4.3.0 Temporary storage failure, try again later",
+            StorageError::Permanent(_) => b"554 5.3.0 Transaction failed",
+            StorageError::Rejected(_) => b"550 5.7.1 Message rejected",
+        };
+        return vec![reply.to_vec()];
+    }
+
+    METRICS_INSTANCE.message_processed_successfully.add(1, &[]);
+    vec![b"250 2.0.0 Message accepted for delivery".to_vec()]
+}
+
+/// Forgets the envelope and the data of the current transaction, so the client may start another one
+/// on the same session, as LMTP clients do after each message.
+fn end_transaction(message_metadata: &mut Metadata, state: &mut State, data_vec: &mut Vec<u8>) {
+    message_metadata.reset_transaction();
+    data_vec.clear();
+    *state = State::ProvidingHeaders {
+        state: HeadersState::ProvidingFrom,
+    };
+}
+
+/// Appends message data to `data_vec` line by line, undoing the dot-stuffing of RFC 5321 section
+/// 4.5.2 so the message is kept exactly as the client composed it. Returns whether the `.<CRLF>`
+/// terminator was reached.
+fn unstuff_data(buffer: &[u8], data_vec: &mut Vec<u8>) -> bool {
+    for line in buffer.split_inclusive(|byte| *byte == b'\n') {
+        let at_line_start = data_vec.last().is_none_or(|byte| *byte == b'\n');
+        if !at_line_start {
+            data_vec.extend_from_slice(line);
+        } else if line == b".\r\n" {
+            return true;
+        } else {
+            data_vec.extend_from_slice(line.strip_prefix(b".").unwrap_or(line));
         }
+    }
+    false
+}
+
+/// The `SIZE=` parameter of a `MAIL FROM` command, the size of the message the client is about to send.
+fn declared_size(mail_from: &str) -> Option<usize> {
+    let (_, parameters) = mail_from.rsplit_once('>')?;
+    parameters.split_whitespace().find_map(|parameter| {
+        let (name, value) = parameter.split_once('=')?;
+        if name.eq_ignore_ascii_case("SIZE") {
+            value.parse().ok()
+        } else {
+            None
+        }
+    })
+}
 
-        METRICS_INSTANCE.message_processed_successfully.add(1, &[]);
-        *state = State::Quitting;
-        return vec![b"250 Message accepted for delivery".to_vec()];
+/// LMTP replies once per recipient, so each one is stored on its own and may fail independently.
+async fn deliver_per_recipient(
+    message_metadata: &Metadata,
+    message: &Message<'_>,
+    storage: &dyn Storage,
+) -> Vec<Vec<u8>> {
+    let mut replies = vec![];
+    for recipient in &message_metadata.recipients {
+        let recipient_metadata = Metadata {
+            recipients: vec![recipient.clone()],
+            ..message_metadata.clone()
+        };
+        match storage.save(&recipient_metadata, message).await {
+            Ok(()) => {
+                METRICS_INSTANCE.message_processed_successfully.add(1, &[]);
+                replies.push(format!("250 2.1.5 <{}> Message accepted for delivery", recipient).into_bytes());
+            }
+            Err(e) => {
+                error!(error.message = %e, recipient, "Failed to save message for recipient");
+                let reply = match e {
+                    StorageError::Transient(_) => {
+                        format!("451 4.3.0 <{}> Temporary storage failure, try again later", recipient)
+                    }
+                    StorageError::Permanent(_) => format!("554 5.3.0 <{}> Transaction failed", recipient),
+                    StorageError::Rejected(_) => format!("550 5.7.1 <{}> Message rejected", recipient),
+                };
+                replies.push(reply.into_bytes());
+            }
+        }
     }
-    vec![]
+    replies
 }
 
 fn handle_quit(buffer_str: &str) -> Vec<Vec<u8>> {
     if buffer_str.eq_ignore_ascii_case("QUIT") {
-        vec![b"221 Bye".to_vec()]
+        vec![b"221 2.0.0 Bye".to_vec()]
     } else {
-        vec![b"501 Expected QUIT.".to_vec()]
+        vec![b"501 5.5.1 Expected QUIT.".to_vec()]
     }
 }
 
-fn sanitize_dot_stuffing(raw_str: &str) -> String {
-    raw_str
-        .strip_suffix(DATA_TERMINATOR)
-        .unwrap_or(raw_str)
-        .replace("..", ".")
-}
-
 fn sanitize_address(address: &str) -> &str {
     let trimmed_address = address.trim();
     let first_cut_idx = find_str(trimmed_address, "<").unwrap_or(0) + 1;
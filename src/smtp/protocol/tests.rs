@@ -1,6 +1,6 @@
 use super::*;
-use crate::smtp::models::{Metadata, State};
-use crate::storage::Storage;
+use crate::smtp::models::{HeadersState, Metadata, Protocol, State};
+use crate::storage::{Storage, StorageError};
 use async_trait::async_trait;
 use mail_parser::Message;
 
@@ -9,11 +9,36 @@ struct MockStorage;
 
 #[async_trait]
 impl Storage for MockStorage {
-    async fn save(&self, _metadata: &Metadata, _message: &Message<'_>) -> Result<(), std::io::Error> {
+    async fn save(&self, _metadata: &Metadata, _message: &Message<'_>) -> Result<(), StorageError> {
         Ok(())
     }
 }
 
+// A mock storage implementation that fails for a single recipient, for testing LMTP replies.
+struct FailingRecipientStorage {
+    failing_recipient: String,
+}
+
+#[async_trait]
+impl Storage for FailingRecipientStorage {
+    async fn save(&self, metadata: &Metadata, _message: &Message<'_>) -> Result<(), StorageError> {
+        if metadata.recipients.contains(&self.failing_recipient) {
+            return Err(StorageError::permanent("bucket does not exist"));
+        }
+        Ok(())
+    }
+}
+
+// A mock storage implementation whose backend is temporarily unavailable.
+struct UnavailableStorage;
+
+#[async_trait]
+impl Storage for UnavailableStorage {
+    async fn save(&self, _metadata: &Metadata, _message: &Message<'_>) -> Result<(), StorageError> {
+        Err(StorageError::transient("request timed out"))
+    }
+}
+
 macro_rules! assert_response {
     ($response:expr, $expected:expr) => {
         let response_str = String::from_utf8($response[0].clone()).unwrap();
@@ -28,6 +53,12 @@ async fn test_smtp_protocol_flow() {
     let mut data_vec: Vec<u8> = vec![];
     let storage = MockStorage {};
     let allowed_addresses = vec!["test@example.com".to_string()];
+    let config = SessionConfig {
+        storage: &storage,
+        allowed_addresses: &allowed_addresses,
+        protocol: Protocol::Smtp,
+        trusted_networks: &[],
+    };
 
     // 1. Initialize transaction
     let response = handle_message(
@@ -35,8 +66,7 @@ async fn test_smtp_protocol_flow() {
         &mut message_metadata,
         &mut state,
         &mut data_vec,
-        &storage,
-        &allowed_addresses,
+        &config,
     )
     .await;
     assert_response!(response, "250-");
@@ -48,8 +78,7 @@ async fn test_smtp_protocol_flow() {
         &mut message_metadata,
         &mut state,
         &mut data_vec,
-        &storage,
-        &allowed_addresses,
+        &config,
     )
     .await;
     assert_response!(response, "334"); // "Username:"
@@ -60,8 +89,7 @@ async fn test_smtp_protocol_flow() {
         &mut message_metadata,
         &mut state,
         &mut data_vec,
-        &storage,
-        &allowed_addresses,
+        &config,
     )
     .await;
     assert_response!(response, "334"); // "Password:"
@@ -72,8 +100,7 @@ async fn test_smtp_protocol_flow() {
         &mut message_metadata,
         &mut state,
         &mut data_vec,
-        &storage,
-        &allowed_addresses,
+        &config,
     )
     .await;
     assert_response!(response, "235");
@@ -85,8 +112,7 @@ async fn test_smtp_protocol_flow() {
         &mut message_metadata,
         &mut state,
         &mut data_vec,
-        &storage,
-        &allowed_addresses,
+        &config,
     )
     .await;
     assert_response!(response, "250");
@@ -97,8 +123,7 @@ async fn test_smtp_protocol_flow() {
         &mut message_metadata,
         &mut state,
         &mut data_vec,
-        &storage,
-        &allowed_addresses,
+        &config,
     )
     .await;
     assert_response!(response, "250");
@@ -109,12 +134,11 @@ async fn test_smtp_protocol_flow() {
         &mut message_metadata,
         &mut state,
         &mut data_vec,
-        &storage,
-        &allowed_addresses,
+        &config,
     )
     .await;
     assert_response!(response, "354");
-    assert!(matches!(state, State::ProvidingData));
+    assert!(matches!(state, State::ProvidingData { oversized: false }));
 
     // 8. Inform mail content
     let email_data = "From: <sender@example.com>\r\nTo: <recipient@example.com>\r\nSubject: Test\r\n\r\nBody\r\n.\r\n";
@@ -123,8 +147,7 @@ async fn test_smtp_protocol_flow() {
         &mut message_metadata,
         &mut state,
         &mut data_vec,
-        &storage,
-        &allowed_addresses,
+        &config,
     )
     .await;
     assert_response!(response, "250");
@@ -136,8 +159,7 @@ async fn test_smtp_protocol_flow() {
         &mut message_metadata,
         &mut state,
         &mut data_vec,
-        &storage,
-        &allowed_addresses,
+        &config,
     )
     .await;
     assert_response!(response, "221");
@@ -153,14 +175,19 @@ async fn test_invalid_authorization() {
     let mut data_vec: Vec<u8> = vec![];
     let storage = MockStorage {};
     let allowed_addresses = vec!["valid@example.com".to_string()];
+    let config = SessionConfig {
+        storage: &storage,
+        allowed_addresses: &allowed_addresses,
+        protocol: Protocol::Smtp,
+        trusted_networks: &[],
+    };
 
     let response = handle_message(
         b"d3JvbmcudXNlckBleGFtcGxlLmNvbQ==\r\n", // wrong.user@example.com
         &mut message_metadata,
         &mut state,
         &mut data_vec,
-        &storage,
-        &allowed_addresses,
+        &config,
     )
     .await;
 
@@ -174,14 +201,19 @@ async fn test_initial_message_not_ehlo() {
     let mut data_vec: Vec<u8> = vec![];
     let storage = MockStorage {};
     let allowed_addresses = vec!["*".to_string()];
+    let config = SessionConfig {
+        storage: &storage,
+        allowed_addresses: &allowed_addresses,
+        protocol: Protocol::Smtp,
+        trusted_networks: &[],
+    };
 
     let response = handle_message(
         b"MAIL FROM:<sender@example.com>\r\n",
         &mut message_metadata,
         &mut state,
         &mut data_vec,
-        &storage,
-        &allowed_addresses,
+        &config,
     )
     .await;
 
@@ -192,11 +224,17 @@ async fn test_initial_message_not_ehlo() {
 async fn test_data_before_recipient() {
     let mut message_metadata = Metadata::default();
     let mut state = State::ProvidingHeaders {
-        state: crate::smtp::models::HeadersState::ProvidingRecipients,
+        state: HeadersState::ProvidingRecipients,
     };
     let mut data_vec: Vec<u8> = vec![];
     let storage = MockStorage {};
     let allowed_addresses = vec!["*".to_string()];
+    let config = SessionConfig {
+        storage: &storage,
+        allowed_addresses: &allowed_addresses,
+        protocol: Protocol::Smtp,
+        trusted_networks: &[],
+    };
 
     // Set state to after MAIL FROM has been successfully called
     message_metadata.from = "sender@example.com".to_string();
@@ -206,10 +244,555 @@ async fn test_data_before_recipient() {
         &mut message_metadata,
         &mut state,
         &mut data_vec,
-        &storage,
-        &allowed_addresses,
+        &config,
     )
     .await;
 
     assert_response!(response, "503");
 }
+
+#[tokio::test]
+async fn test_lmtp_requires_lhlo() {
+    let mut message_metadata = Metadata::default();
+    let mut state = State::Initialized;
+    let mut data_vec: Vec<u8> = vec![];
+    let storage = MockStorage {};
+    let config = SessionConfig {
+        storage: &storage,
+        allowed_addresses: &[],
+        protocol: Protocol::Lmtp,
+        trusted_networks: &[],
+    };
+
+    let response = handle_message(
+        b"EHLO test.client\r\n",
+        &mut message_metadata,
+        &mut state,
+        &mut data_vec,
+        &config,
+    )
+    .await;
+    assert_response!(response, "552");
+
+    let response = handle_message(
+        b"LHLO test.client\r\n",
+        &mut message_metadata,
+        &mut state,
+        &mut data_vec,
+        &config,
+    )
+    .await;
+    assert_response!(response, "250-");
+    assert!(response.iter().any(|line| line == b"250-PIPELINING"));
+    assert!(response.iter().any(|line| line == b"250-ENHANCEDSTATUSCODES"));
+    assert!(matches!(state, State::ProvidingHeaders { .. }));
+}
+
+#[tokio::test]
+async fn test_lmtp_replies_once_per_recipient() {
+    let mut message_metadata = Metadata {
+        from: "sender@example.com".to_string(),
+        recipients: vec![
+            "first@example.com".to_string(),
+            "second@example.com".to_string(),
+        ],
+        ..Default::default()
+    };
+    let mut state = State::ProvidingData { oversized: false };
+    let mut data_vec: Vec<u8> = vec![];
+    let storage = FailingRecipientStorage {
+        failing_recipient: "second@example.com".to_string(),
+    };
+    let config = SessionConfig {
+        storage: &storage,
+        allowed_addresses: &[],
+        protocol: Protocol::Lmtp,
+        trusted_networks: &[],
+    };
+
+    let email_data = "From: <sender@example.com>\r\nSubject: Test\r\n\r\nBody\r\n.\r\n";
+    let response = handle_message(
+        email_data.as_bytes(),
+        &mut message_metadata,
+        &mut state,
+        &mut data_vec,
+        &config,
+    )
+    .await;
+
+    assert_eq!(response.len(), 2);
+    assert_eq!(
+        String::from_utf8(response[0].clone()).unwrap(),
+        "250 2.1.5 <first@example.com> Message accepted for delivery"
+    );
+    assert!(String::from_utf8(response[1].clone()).unwrap().starts_with("554"));
+    assert!(matches!(
+        state,
+        State::ProvidingHeaders {
+            state: HeadersState::ProvidingFrom
+        }
+    ));
+}
+
+#[tokio::test]
+async fn test_lmtp_session_goes_on_after_a_transaction() {
+    let mut message_metadata = Metadata {
+        client: "mta.example.com".to_string(),
+        from: "sender@example.com".to_string(),
+        recipients: vec!["first@example.com".to_string()],
+        ..Default::default()
+    };
+    let mut state = State::ProvidingData { oversized: false };
+    let mut data_vec: Vec<u8> = vec![];
+    let storage = MockStorage {};
+    let config = SessionConfig {
+        storage: &storage,
+        allowed_addresses: &[],
+        protocol: Protocol::Lmtp,
+        trusted_networks: &[],
+    };
+
+    let email_data = "From: <sender@example.com>\r\nSubject: First\r\n\r\nBody\r\n.\r\n";
+    let response = handle_message(
+        email_data.as_bytes(),
+        &mut message_metadata,
+        &mut state,
+        &mut data_vec,
+        &config,
+    )
+    .await;
+    assert_response!(response, "250 2.1.5");
+    assert!(data_vec.is_empty());
+    assert!(message_metadata.recipients.is_empty());
+    assert!(message_metadata.subject.is_empty());
+    assert_eq!(message_metadata.client, "mta.example.com");
+
+    // The next transaction may start right away, or after a reset.
+    let response = handle_message(
+        b"MAIL FROM:<other@example.com>\r\n",
+        &mut message_metadata,
+        &mut state,
+        &mut data_vec,
+        &config,
+    )
+    .await;
+    assert_response!(response, "250 2.1.0");
+    let response = handle_message(
+        b"RSET\r\n",
+        &mut message_metadata,
+        &mut state,
+        &mut data_vec,
+        &config,
+    )
+    .await;
+    assert_response!(response, "250 2.0.0");
+    assert!(message_metadata.from.is_empty());
+    let response = handle_message(
+        b"MAIL FROM:<other@example.com>\r\n",
+        &mut message_metadata,
+        &mut state,
+        &mut data_vec,
+        &config,
+    )
+    .await;
+    assert_response!(response, "250 2.1.0");
+}
+
+#[tokio::test]
+async fn test_transient_storage_failure_asks_sender_to_retry() {
+    let mut message_metadata = Metadata {
+        from: "sender@example.com".to_string(),
+        recipients: vec!["rcpt@example.com".to_string()],
+        ..Default::default()
+    };
+    let mut state = State::ProvidingData { oversized: false };
+    let mut data_vec: Vec<u8> = vec![];
+    let storage = UnavailableStorage {};
+    let config = SessionConfig {
+        storage: &storage,
+        allowed_addresses: &[],
+        protocol: Protocol::Smtp,
+        trusted_networks: &[],
+    };
+
+    let email_data = "From: <sender@example.com>\r\nSubject: Test\r\n\r\nBody\r\n.\r\n";
+    let response = handle_message(
+        email_data.as_bytes(),
+        &mut message_metadata,
+        &mut state,
+        &mut data_vec,
+        &config,
+    )
+    .await;
+
+    assert_response!(response, "451 4.3.0");
+    // Commands following the failed transaction must not be taken for more message data.
+    assert!(matches!(state, State::Quitting));
+}
+
+#[tokio::test]
+async fn test_unparsable_message_ends_the_transaction() {
+    let mut message_metadata = Metadata {
+        from: "sender@example.com".to_string(),
+        recipients: vec!["rcpt@example.com".to_string()],
+        ..Default::default()
+    };
+    let mut state = State::ProvidingData { oversized: false };
+    let mut data_vec: Vec<u8> = vec![];
+    let storage = MockStorage {};
+    let config = SessionConfig {
+        storage: &storage,
+        allowed_addresses: &[],
+        protocol: Protocol::Smtp,
+        trusted_networks: &[],
+    };
+
+    let response = handle_message(
+        b".\r\n",
+        &mut message_metadata,
+        &mut state,
+        &mut data_vec,
+        &config,
+    )
+    .await;
+    assert_response!(response, "501 5.6.0");
+    assert!(matches!(state, State::Quitting));
+    assert!(data_vec.is_empty());
+
+    // Commands following the failed transaction must not be taken for more message data.
+    let response = handle_message(
+        b"QUIT\r\n",
+        &mut message_metadata,
+        &mut state,
+        &mut data_vec,
+        &config,
+    )
+    .await;
+    assert_response!(response, "221");
+    assert!(data_vec.is_empty());
+}
+
+#[tokio::test]
+async fn test_xclient_rejected_from_untrusted_client() {
+    let mut message_metadata = Metadata {
+        peer_ip: Some("192.0.2.10".parse().unwrap()),
+        ..Default::default()
+    };
+    let mut state = State::Authenticating {
+        state: crate::smtp::models::AuthState::AwaithAuthRequest,
+        username: None,
+    };
+    let mut data_vec: Vec<u8> = vec![];
+    let storage = MockStorage {};
+    let allowed_addresses = vec!["*".to_string()];
+    let trusted_networks = ["10.0.0.0/8".parse().unwrap()];
+    let config = SessionConfig {
+        storage: &storage,
+        allowed_addresses: &allowed_addresses,
+        protocol: Protocol::Smtp,
+        trusted_networks: &trusted_networks,
+    };
+
+    let response = handle_message(
+        b"XCLIENT ADDR=203.0.113.5 LOGIN=user@example.com\r\n",
+        &mut message_metadata,
+        &mut state,
+        &mut data_vec,
+        &config,
+    )
+    .await;
+
+    assert_response!(response, "550");
+    assert!(message_metadata.forwarded.is_none());
+}
+
+#[tokio::test]
+async fn test_xclient_from_trusted_client() {
+    let mut message_metadata = Metadata {
+        peer_ip: Some("10.1.2.3".parse().unwrap()),
+        ..Default::default()
+    };
+    let mut state = State::Initialized;
+    let mut data_vec: Vec<u8> = vec![];
+    let storage = MockStorage {};
+    let allowed_addresses = vec!["user@example.com".to_string()];
+    let trusted_networks = ["10.0.0.0/8".parse().unwrap()];
+    let config = SessionConfig {
+        storage: &storage,
+        allowed_addresses: &allowed_addresses,
+        protocol: Protocol::Smtp,
+        trusted_networks: &trusted_networks,
+    };
+
+    let response = handle_message(
+        b"EHLO relay.example.com\r\n",
+        &mut message_metadata,
+        &mut state,
+        &mut data_vec,
+        &config,
+    )
+    .await;
+    assert!(response.iter().any(|line| line.starts_with(b"250-XCLIENT")));
+
+    let response = handle_message(
+        b"XCLIENT ADDR=IPV6:2001:db8::1 PORT=4711 HELO=client+2Eexample LOGIN=user@example.com\r\n",
+        &mut message_metadata,
+        &mut state,
+        &mut data_vec,
+        &config,
+    )
+    .await;
+    assert_response!(response, "220");
+    assert!(matches!(state, State::Initialized));
+
+    // The forwarded login is already authenticated, so no AUTH step is needed.
+    let response = handle_message(
+        b"EHLO relay.example.com\r\n",
+        &mut message_metadata,
+        &mut state,
+        &mut data_vec,
+        &config,
+    )
+    .await;
+    assert_response!(response, "250-");
+    assert!(matches!(state, State::ProvidingHeaders { .. }));
+
+    let forwarded = message_metadata.forwarded.as_ref().unwrap();
+    assert_eq!(forwarded.addr.as_deref(), Some("2001:db8::1"));
+    assert_eq!(forwarded.port.as_deref(), Some("4711"));
+    assert_eq!(forwarded.helo.as_deref(), Some("client.example"));
+    assert_eq!(message_metadata.authenticated_user.as_deref(), Some("user@example.com"));
+}
+
+#[tokio::test]
+async fn test_xclient_replaces_the_client_identity() {
+    let mut message_metadata = Metadata {
+        client_address: "10.1.2.3:41234".to_string(),
+        client_ip: Some("10.1.2.3".parse().unwrap()),
+        peer_ip: Some("10.1.2.3".parse().unwrap()),
+        ..Default::default()
+    };
+    let mut state = State::Initialized;
+    let mut data_vec: Vec<u8> = vec![];
+    let storage = MockStorage {};
+    let trusted_networks = ["10.0.0.0/8".parse().unwrap()];
+    let config = SessionConfig {
+        storage: &storage,
+        allowed_addresses: &[],
+        protocol: Protocol::Smtp,
+        trusted_networks: &trusted_networks,
+    };
+
+    handle_message(
+        b"EHLO relay.example.com\r\n",
+        &mut message_metadata,
+        &mut state,
+        &mut data_vec,
+        &config,
+    )
+    .await;
+    let response = handle_message(
+        b"XCLIENT NAME=mail.example.org ADDR=203.0.113.5 PORT=25\r\n",
+        &mut message_metadata,
+        &mut state,
+        &mut data_vec,
+        &config,
+    )
+    .await;
+    assert_response!(response, "220");
+    assert_eq!(message_metadata.client_ip, Some("203.0.113.5".parse().unwrap()));
+    assert_eq!(message_metadata.client_address, "mail.example.org[203.0.113.5]:25");
+
+    // Trust still depends on the connecting peer, which may forward the attributes again.
+    handle_message(
+        b"EHLO relay.example.com\r\n",
+        &mut message_metadata,
+        &mut state,
+        &mut data_vec,
+        &config,
+    )
+    .await;
+    let response = handle_message(
+        b"XCLIENT NAME=[UNAVAILABLE] ADDR=198.51.100.7\r\n",
+        &mut message_metadata,
+        &mut state,
+        &mut data_vec,
+        &config,
+    )
+    .await;
+    assert_response!(response, "220");
+    assert_eq!(message_metadata.client_ip, Some("198.51.100.7".parse().unwrap()));
+    assert_eq!(message_metadata.client_address, "unknown[198.51.100.7]:25");
+
+    handle_message(
+        b"EHLO relay.example.com\r\n",
+        &mut message_metadata,
+        &mut state,
+        &mut data_vec,
+        &config,
+    )
+    .await;
+    let response = handle_message(
+        b"XCLIENT ADDR=not-an-address\r\n",
+        &mut message_metadata,
+        &mut state,
+        &mut data_vec,
+        &config,
+    )
+    .await;
+    assert_response!(response, "501");
+    assert_eq!(message_metadata.client_ip, Some("198.51.100.7".parse().unwrap()));
+}
+
+#[tokio::test]
+async fn test_xclient_login_is_subject_to_acl() {
+    let mut message_metadata = Metadata {
+        peer_ip: Some("127.0.0.1".parse().unwrap()),
+        ..Default::default()
+    };
+    let mut state = State::Authenticating {
+        state: crate::smtp::models::AuthState::AwaithAuthRequest,
+        username: None,
+    };
+    let mut data_vec: Vec<u8> = vec![];
+    let storage = MockStorage {};
+    let allowed_addresses = vec!["valid@example.com".to_string()];
+    let trusted_networks = ["127.0.0.1/32".parse().unwrap()];
+    let config = SessionConfig {
+        storage: &storage,
+        allowed_addresses: &allowed_addresses,
+        protocol: Protocol::Smtp,
+        trusted_networks: &trusted_networks,
+    };
+
+    let response = handle_message(
+        b"XCLIENT LOGIN=intruder@example.com\r\n",
+        &mut message_metadata,
+        &mut state,
+        &mut data_vec,
+        &config,
+    )
+    .await;
+
+    assert_response!(response, "535");
+    assert!(message_metadata.authenticated_user.is_none());
+}
+
+#[tokio::test]
+async fn test_xforward_records_attributes() {
+    let mut message_metadata = Metadata {
+        peer_ip: Some("127.0.0.1".parse().unwrap()),
+        ..Default::default()
+    };
+    let mut state = State::ProvidingHeaders {
+        state: HeadersState::ProvidingFrom,
+    };
+    let mut data_vec: Vec<u8> = vec![];
+    let storage = MockStorage {};
+    let trusted_networks = ["127.0.0.0/8".parse().unwrap()];
+    let config = SessionConfig {
+        storage: &storage,
+        allowed_addresses: &[],
+        protocol: Protocol::Smtp,
+        trusted_networks: &trusted_networks,
+    };
+
+    let response = handle_message(
+        b"XFORWARD NAME=mail.example.com ADDR=198.51.100.7 PROTO=ESMTP SOURCE=REMOTE\r\n",
+        &mut message_metadata,
+        &mut state,
+        &mut data_vec,
+        &config,
+    )
+    .await;
+
+    assert_response!(response, "250");
+    assert!(matches!(state, State::ProvidingHeaders { .. }));
+    let forwarded = message_metadata.forwarded.as_ref().unwrap();
+    assert_eq!(forwarded.name.as_deref(), Some("mail.example.com"));
+    assert_eq!(forwarded.addr.as_deref(), Some("198.51.100.7"));
+    assert_eq!(forwarded.source.as_deref(), Some("REMOTE"));
+}
+
+#[test]
+fn test_dot_stuffing_is_removed_only_at_line_start() {
+    let mut data_vec: Vec<u8> = vec![];
+
+    // The stuffed line is split across two pieces of data.
+    assert!(!unstuff_data(b"Subject: Dots\r\n\r\n..lead", &mut data_vec));
+    assert!(unstuff_data(b"ing dot\r\nWait for it...\r\n.\r\n", &mut data_vec));
+
+    assert_eq!(data_vec, b"Subject: Dots\r\n\r\n.leading dot\r\nWait for it...\r\n");
+}
+
+#[tokio::test]
+async fn test_oversized_message_is_rejected_after_data() {
+    let mut message_metadata = Metadata {
+        recipients: vec!["user@example.com".to_string()],
+        ..Default::default()
+    };
+    let mut state = State::ProvidingData { oversized: false };
+    let mut data_vec: Vec<u8> = vec![];
+    let storage = MockStorage {};
+    let config = SessionConfig {
+        storage: &storage,
+        allowed_addresses: &[],
+        protocol: Protocol::Smtp,
+        trusted_networks: &[],
+    };
+
+    let mut line = vec![b'a'; 1024 * 1024 - 2];
+    line.extend_from_slice(b"\r\n");
+    for _ in 0..=MAX_MESSAGE_SIZE / line.len() {
+        let response = handle_message(
+            &line,
+            &mut message_metadata,
+            &mut state,
+            &mut data_vec,
+            &config,
+        )
+        .await;
+        assert!(response.is_empty());
+    }
+    assert!(matches!(state, State::ProvidingData { oversized: true }));
+    assert!(data_vec.len() <= 1);
+
+    let response = handle_message(
+        b".\r\n",
+        &mut message_metadata,
+        &mut state,
+        &mut data_vec,
+        &config,
+    )
+    .await;
+    assert_response!(response, "552 5.3.4");
+    assert!(matches!(state, State::Quitting));
+}
+
+#[tokio::test]
+async fn test_declared_size_over_the_limit_is_rejected() {
+    let mut message_metadata = Metadata::default();
+    let mut state = State::ProvidingHeaders {
+        state: HeadersState::ProvidingFrom,
+    };
+    let mut data_vec: Vec<u8> = vec![];
+    let storage = MockStorage {};
+    let config = SessionConfig {
+        storage: &storage,
+        allowed_addresses: &[],
+        protocol: Protocol::Smtp,
+        trusted_networks: &[],
+    };
+
+    let command = format!("MAIL FROM:<sender@example.com> SIZE={}\r\n", MAX_MESSAGE_SIZE + 1);
+    let response = handle_message(
+        command.as_bytes(),
+        &mut message_metadata,
+        &mut state,
+        &mut data_vec,
+        &config,
+    )
+    .await;
+
+    assert_response!(response, "552 5.3.4");
+}
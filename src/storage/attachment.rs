@@ -1,4 +1,119 @@
-use mail_parser::{MessagePart, MimeHeaders};
+use std::collections::HashSet;
+
+use mail_parser::{Message, MessagePart, MimeHeaders};
+use ulid::Ulid;
+
+use crate::storage::percent_encode;
+
+/// An attachment along with the unique file name it is stored under.
+pub struct StoredAttachment<'a> {
+    pub name: String,
+    pub part: &'a MessagePart<'a>,
+}
+
+impl StoredAttachment<'_> {
+    /// Path of the attachment relative to the message folder.
+    pub fn relative_path(&self) -> String {
+        format!("attachments/{}", self.name)
+    }
+
+    /// Percent-encoded `relative_path`, for use in links from the stored HTML bodies.
+    pub fn relative_url(&self) -> String {
+        format!("attachments/{}", percent_encode(&self.name))
+    }
+
+    /// `Content-Disposition` header value, with the file name encoded per RFC 6266.
+    pub fn content_disposition(&self) -> String {
+        format!("attachment; filename*=UTF-8''{}", percent_encode(&self.name))
+    }
+
+    pub fn mime_type(&self) -> String {
+        match self.part.content_type() {
+            Some(ct) => format!("{}/{}", ct.ctype(), ct.subtype().unwrap_or("octet-stream")),
+            None => "application/octet-stream".to_string(),
+        }
+    }
+}
+
+/// How an attachment whose name is already taken within the message is renamed.
+#[derive(Clone, Copy)]
+enum DuplicateNames {
+    /// `name (2).ext`, `name (3).ext`, as in the local message folders.
+    Numbered,
+    /// `name-<last characters of a ULID>.ext`, as in the object keys.
+    UlidSuffix,
+}
+
+/// Collects every attachment of the message, including the ones of nested messages,
+/// giving each one a file name that is unique within the message.
+pub fn collect_attachments<'a>(message: &'a Message<'a>) -> Vec<StoredAttachment<'a>> {
+    collect_with(message, DuplicateNames::UlidSuffix)
+}
+
+/// Same as `collect_attachments`, numbering the duplicate names the way the local storage always has.
+pub fn collect_numbered_attachments<'a>(message: &'a Message<'a>) -> Vec<StoredAttachment<'a>> {
+    collect_with(message, DuplicateNames::Numbered)
+}
+
+fn collect_with<'a>(message: &'a Message<'a>, duplicates: DuplicateNames) -> Vec<StoredAttachment<'a>> {
+    let mut attachments = vec![];
+    let mut file_names = HashSet::new();
+    collect_attachments_from_message(message, 0, duplicates, &mut file_names, &mut attachments);
+    attachments
+}
+
+fn collect_attachments_from_message<'a>(
+    msg: &'a Message<'a>,
+    depth: usize,
+    duplicates: DuplicateNames,
+    file_names: &mut HashSet<String>,
+    attachments: &mut Vec<StoredAttachment<'a>>,
+) {
+    for (i, part) in msg.attachments().enumerate() {
+        let name = determine_attachment_name(part, &depth, &i);
+        let name = match duplicates {
+            DuplicateNames::Numbered => number_filename(name, file_names),
+            DuplicateNames::UlidSuffix => suffix_filename(name, file_names),
+        };
+        file_names.insert(name.clone());
+        attachments.push(StoredAttachment { name, part });
+
+        if let Some(nested) = part.message() {
+            collect_attachments_from_message(nested, depth + 1, duplicates, file_names, attachments);
+        }
+    }
+}
+
+fn number_filename(name: String, file_names: &HashSet<String>) -> String {
+    if !file_names.contains(&name) {
+        return name;
+    }
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, Some(ext)),
+        _ => (name.as_str(), None),
+    };
+    for n in 2.. {
+        let candidate = match ext {
+            Some(ext) => format!("{stem} ({n}).{ext}"),
+            None => format!("{stem} ({n})"),
+        };
+        if !file_names.contains(&candidate) {
+            return candidate;
+        }
+    }
+    unreachable!()
+}
+
+fn suffix_filename(mut name: String, file_names: &HashSet<String>) -> String {
+    while file_names.contains(&name) {
+        let ulid = &Ulid::new().to_string()[20..];
+        match name.rsplit_once('.') {
+            Some((file_base_name, ext)) => name = format!("{}-{}.{}", file_base_name, ulid, ext),
+            None => name = format!("{}-{}", name, ulid),
+        }
+    }
+    name
+}
 
 pub fn determine_attachment_name(
     message_part: &MessagePart,
@@ -32,5 +147,5 @@ pub fn determine_attachment_name(
         }
     }
 
-    return attachment_name;
+    attachment_name
 }
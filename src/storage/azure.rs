@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use object_store::azure::{AzureConfigKey, MicrosoftAzureBuilder};
+
+use crate::storage::{object_storage::ObjectStorage, LayoutOptions, UploadOptions};
+
+/// Read when the config doesn't set a connection string, so the account key can stay out of it.
+pub const CONNECTION_STRING_VARIABLE: &str = "AZURE_STORAGE_CONNECTION_STRING";
+
+/// The well-known account of the Azurite emulator, used by `UseDevelopmentStorage=true`.
+const DEVELOPMENT_ACCOUNT_NAME: &str = "devstoreaccount1";
+const DEVELOPMENT_ACCOUNT_KEY: &str =
+    "Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==";
+const DEVELOPMENT_BLOB_ENDPOINT: &str = "http://127.0.0.1:10000/devstoreaccount1";
+
+/// Builds a storage writing to an Azure Blob Storage container, with the same object layout as
+/// `S3FileStorage`.
+///
+/// The connection string is the one shown in the Azure portal, e.g.
+/// `DefaultEndpointsProtocol=https;AccountName=...;AccountKey=...;EndpointSuffix=core.windows.net`.
+/// `BlobEndpoint` points to another endpoint, such as Azurite's `http://127.0.0.1:10000/devstoreaccount1`.
+pub fn build_azure_blob_storage(
+    container_name: String,
+    connection_string: &str,
+    layout: LayoutOptions,
+    options: UploadOptions,
+) -> Result<ObjectStorage, String> {
+    let settings = parse_connection_string(connection_string)?;
+    let setting = |name: &str| settings.get(&name.to_ascii_lowercase()).map(String::as_str);
+
+    let development = setting("UseDevelopmentStorage").is_some_and(|value| value.eq_ignore_ascii_case("true"));
+    let (account_name, account_key, blob_endpoint) = if development {
+        (
+            DEVELOPMENT_ACCOUNT_NAME.to_string(),
+            Some(DEVELOPMENT_ACCOUNT_KEY),
+            DEVELOPMENT_BLOB_ENDPOINT.to_string(),
+        )
+    } else {
+        let account_name = setting("AccountName").ok_or("Azure connection string has no AccountName")?;
+        let blob_endpoint = match setting("BlobEndpoint") {
+            Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+            None => format!(
+                "{}://{}.blob.{}",
+                setting("DefaultEndpointsProtocol").unwrap_or("https"),
+                account_name,
+                setting("EndpointSuffix").unwrap_or("core.windows.net")
+            ),
+        };
+        (account_name.to_string(), setting("AccountKey"), blob_endpoint)
+    };
+
+    let location = format!("{}/{}", blob_endpoint, container_name);
+    let mut builder = MicrosoftAzureBuilder::new()
+        .with_account(account_name)
+        .with_container_name(container_name)
+        .with_allow_http(blob_endpoint.starts_with("http://"))
+        .with_endpoint(blob_endpoint);
+    builder = match (account_key, setting("SharedAccessSignature")) {
+        (Some(account_key), _) => builder.with_access_key(account_key),
+        (None, Some(signature)) => builder.with_config(AzureConfigKey::SasKey, signature),
+        (None, None) => return Err("Azure connection string has neither AccountKey nor SharedAccessSignature".to_string()),
+    };
+    let store = builder.build().map_err(|e| e.to_string())?;
+
+    Ok(ObjectStorage::new(Arc::new(store), "AzureBlob", location, layout, options))
+}
+
+/// Splits `Name=value;Name=value` pairs, keyed by lowercase name as Azure treats names case-insensitively.
+fn parse_connection_string(connection_string: &str) -> Result<HashMap<String, String>, String> {
+    connection_string
+        .split(';')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| {
+            // Values such as keys and signatures contain '=' themselves.
+            pair.split_once('=')
+                .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+                .ok_or_else(|| "Invalid Azure connection string".to_string())
+        })
+        .collect()
+}
@@ -0,0 +1,170 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use mail_parser::{Message, MimeHeaders, PartType};
+
+use crate::smtp::models::{BodyPart, Metadata};
+
+use super::attachment::StoredAttachment;
+use super::{InlineImages, NO_BODY_FALLBACK};
+
+pub const HTML_CONTENT_TYPE: &str = "text/html";
+pub const TEXT_CONTENT_TYPE: &str = "text/plain";
+
+/// Lists every HTML and plain text body of the message, in the order they are stored.
+///
+/// The first body of each type keeps the `body.html`/`body.txt` names, the following ones get
+/// their position appended (`body-1.html`, `body-2.txt`, ...). A message without any body still
+/// gets a `body.html`, filled with `NO_BODY_FALLBACK`.
+pub fn describe_bodies(message: &Message<'_>) -> Vec<BodyPart> {
+    let mut bodies: Vec<BodyPart> = (0..message.html_body_count())
+        .map(|index| BodyPart {
+            file_name: body_file_name(index, "html"),
+            content_type: HTML_CONTENT_TYPE,
+            index,
+            synthesized: !message
+                .html_part(index as u32)
+                .is_some_and(|part| matches!(part.body, PartType::Html(_))),
+        })
+        .collect();
+
+    if bodies.is_empty() {
+        bodies.push(BodyPart {
+            file_name: body_file_name(0, "html"),
+            content_type: HTML_CONTENT_TYPE,
+            index: 0,
+            synthesized: true,
+        });
+    }
+
+    bodies.extend((0..message.text_body_count()).map(|index| BodyPart {
+        file_name: body_file_name(index, "txt"),
+        content_type: TEXT_CONTENT_TYPE,
+        index,
+        synthesized: !message
+            .text_part(index as u32)
+            .is_some_and(|part| matches!(part.body, PartType::Text(_))),
+    }));
+
+    bodies
+}
+
+/// Contents of a body listed by `describe_bodies`, converted to its content type when needed.
+///
+/// `cid:` references in HTML bodies are replaced using `image_sources`, see `inline_image_sources`.
+pub fn body_content<'x>(
+    message: &'x Message<'x>,
+    body: &BodyPart,
+    image_sources: &HashMap<String, String>,
+) -> Cow<'x, str> {
+    if body.content_type == HTML_CONTENT_TYPE {
+        let html = message
+            .body_html(body.index)
+            .unwrap_or(Cow::Borrowed(NO_BODY_FALLBACK));
+        if image_sources.is_empty() {
+            html
+        } else {
+            Cow::Owned(rewrite_cid_references(&html, image_sources))
+        }
+    } else {
+        message.body_text(body.index).unwrap_or_default()
+    }
+}
+
+/// Maps the Content-ID of every inline image to the source replacing its `cid:` references.
+///
+/// `link` gives the location of a stored attachment, as seen from the stored HTML body.
+pub fn inline_image_sources(
+    attachments: &[StoredAttachment],
+    inline_images: &InlineImages,
+    link: impl Fn(&StoredAttachment) -> String,
+) -> HashMap<String, String> {
+    if *inline_images == InlineImages::Keep {
+        return HashMap::new();
+    }
+    attachments
+        .iter()
+        .filter_map(|attachment| {
+            let content_id = attachment
+                .part
+                .content_id()?
+                .trim()
+                .trim_start_matches('<')
+                .trim_end_matches('>');
+            let source = match inline_images {
+                InlineImages::DataUri => format!(
+                    "data:{};base64,{}",
+                    attachment.mime_type(),
+                    BASE64_STANDARD.encode(attachment.part.contents())
+                ),
+                _ => link(attachment),
+            };
+            Some((content_id.to_string(), source))
+        })
+        .collect()
+}
+
+/// The metadata, the bodies and the base64 encoded attachments. `cid:` references are kept,
+/// receivers resolve them with the `content_id` of the attachments.
+pub fn message_document(
+    metadata: &Metadata,
+    message: &Message<'_>,
+    attachments: &[StoredAttachment<'_>],
+) -> serde_json::Value {
+    let bodies: Vec<_> = describe_bodies(message)
+        .into_iter()
+        .map(|body| {
+            serde_json::json!({
+                "file_name": body.file_name,
+                "content_type": body.content_type,
+                "content": body_content(message, &body, &HashMap::new()),
+            })
+        })
+        .collect();
+    let attachments: Vec<_> = attachments
+        .iter()
+        .map(|attachment| {
+            serde_json::json!({
+                "name": attachment.name,
+                "content_type": attachment.mime_type(),
+                "content_id": attachment.part.content_id(),
+                "content_base64": BASE64_STANDARD.encode(attachment.part.contents()),
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "metadata": metadata,
+        "bodies": bodies,
+        "attachments": attachments,
+    })
+}
+
+fn rewrite_cid_references(html: &str, image_sources: &HashMap<String, String>) -> String {
+    let mut rewritten = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find("cid:") {
+        let (before, reference) = rest.split_at(start);
+        rewritten.push_str(before);
+
+        let content_id = &reference["cid:".len()..];
+        let end = content_id
+            .find(|c: char| matches!(c, '"' | '\'' | ')' | '<' | '>') || c.is_whitespace())
+            .unwrap_or(content_id.len());
+        match image_sources.get(&content_id[..end]) {
+            Some(source) => rewritten.push_str(source),
+            None => rewritten.push_str(&reference[.."cid:".len() + end]),
+        }
+        rest = &content_id[end..];
+    }
+    rewritten.push_str(rest);
+    rewritten
+}
+
+fn body_file_name(index: usize, extension: &str) -> String {
+    match index {
+        0 => format!("body.{}", extension),
+        _ => format!("body-{}.{}", index, extension),
+    }
+}
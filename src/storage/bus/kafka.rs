@@ -0,0 +1,115 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use rskafka::client::error::{Error, ProtocolError};
+use rskafka::client::partition::{Compression, PartitionClient, UnknownTopicHandling};
+use rskafka::client::{Client, ClientBuilder};
+use rskafka::record::Record;
+use rskafka::BackoffConfig;
+use tokio::sync::Mutex;
+
+use crate::storage::bus::{Envelope, Publisher};
+use crate::storage::StorageError;
+
+/// Produces envelopes to Kafka topics, spread over their partitions. The leader only acknowledges
+/// a record once every in-sync replica has it.
+pub struct KafkaPublisher {
+    client: Client,
+    /// Partitions of the topics published to so far, looked up on first use.
+    partitions: Mutex<HashMap<String, Arc<Vec<PartitionClient>>>>,
+    next_partition: AtomicUsize,
+}
+
+impl KafkaPublisher {
+    /// Connects to the first reachable bootstrap broker. Requests are retried until `timeout`.
+    pub async fn connect(brokers: Vec<String>, timeout: Duration) -> Result<Self, Error> {
+        let backoff_config = BackoffConfig {
+            deadline: Some(timeout),
+            ..Default::default()
+        };
+        let client = ClientBuilder::new(brokers)
+            .client_id("smtp2s")
+            .backoff_config(backoff_config)
+            .build()
+            .await?;
+        Ok(Self {
+            client,
+            partitions: Mutex::new(HashMap::new()),
+            next_partition: AtomicUsize::new(0),
+        })
+    }
+
+    async fn partitions(&self, topic: &str) -> Result<Arc<Vec<PartitionClient>>, StorageError> {
+        let mut partitions = self.partitions.lock().await;
+        if let Some(topic_partitions) = partitions.get(topic) {
+            return Ok(topic_partitions.clone());
+        }
+        let topics = self.client.list_topics().await.map_err(classify_error)?;
+        let Some(found) = topics.into_iter().find(|found| found.name == topic) else {
+            return Err(StorageError::permanent(format!("Kafka topic {} doesn't exist", topic)));
+        };
+        let mut topic_partitions = vec![];
+        for partition in found.partitions {
+            let client = self
+                .client
+                .partition_client(topic, partition, UnknownTopicHandling::Retry)
+                .await
+                .map_err(classify_error)?;
+            topic_partitions.push(client);
+        }
+        if topic_partitions.is_empty() {
+            return Err(StorageError::transient(format!("Kafka topic {} has no partitions yet", topic)));
+        }
+        let topic_partitions = Arc::new(topic_partitions);
+        partitions.insert(topic.to_string(), topic_partitions.clone());
+        Ok(topic_partitions)
+    }
+}
+
+#[async_trait]
+impl Publisher for KafkaPublisher {
+    fn provider(&self) -> &'static str {
+        "Kafka"
+    }
+
+    async fn publish(&self, topic: &str, envelope: &Envelope) -> Result<(), StorageError> {
+        let partitions = self.partitions(topic).await?;
+        let partition = &partitions[self.next_partition.fetch_add(1, Ordering::Relaxed) % partitions.len()];
+        let record = Record {
+            key: Some(envelope.id.as_bytes().to_vec()),
+            value: Some(envelope.payload.clone()),
+            headers: BTreeMap::from([("content-type".to_string(), b"application/json".to_vec())]),
+            timestamp: Utc::now(),
+        };
+        partition
+            .produce(vec![record], Compression::NoCompression)
+            .await
+            .map_err(classify_error)?;
+        Ok(())
+    }
+}
+
+/// Refusals of the record itself or of the topic won't change on retry, everything else, such as a
+/// leader election or an unreachable broker, may.
+fn classify_error(err: Error) -> StorageError {
+    let permanent = matches!(
+        &err,
+        Error::ServerError {
+            protocol_error: ProtocolError::MessageTooLarge
+                | ProtocolError::RecordListTooLarge
+                | ProtocolError::InvalidRecord
+                | ProtocolError::InvalidTopicException
+                | ProtocolError::TopicAuthorizationFailed,
+            ..
+        }
+    );
+    if permanent {
+        StorageError::permanent(err)
+    } else {
+        StorageError::transient(err)
+    }
+}
@@ -0,0 +1,201 @@
+pub mod kafka;
+pub mod nats;
+pub mod redis_streams;
+
+use crate::metrics::METRICS_INSTANCE;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use mail_parser::Message;
+use opentelemetry::KeyValue;
+use serde::Deserialize;
+use tracing::{error, info};
+use ulid::Ulid;
+
+use crate::smtp::models::Metadata;
+use crate::storage::attachment::collect_attachments;
+use crate::storage::body::message_document;
+use crate::storage::path_template::{sanitize_value_keeping, tokenize, Token};
+use crate::storage::{Storage, StorageError};
+
+/// Settings shared by the message bus strategies.
+#[derive(Deserialize, Debug, Clone)]
+pub struct BusOptions {
+    /// Time limit for the broker to acknowledge an envelope, after which the sender is asked to retry.
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+}
+
+fn default_timeout_seconds() -> u64 {
+    10
+}
+
+impl Default for BusOptions {
+    fn default() -> Self {
+        Self {
+            timeout_seconds: default_timeout_seconds(),
+        }
+    }
+}
+
+/// What the envelope carries besides the metadata.
+pub enum BusContent {
+    /// The bodies and the base64 encoded attachments, as in the JSON webhook document.
+    Inline,
+    /// Where the backend stored the message, the envelope being published once it is stored.
+    Stored(Arc<dyn Storage>),
+}
+
+/// Kafka topic, NATS subject or Redis stream an envelope is published to, e.g.
+/// `mail.{recipient_domain}`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "String")]
+pub struct TopicTemplate {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    RecipientDomain,
+    AuthenticatedUser,
+    From,
+}
+
+impl TryFrom<String> for TopicTemplate {
+    type Error = String;
+
+    fn try_from(template: String) -> Result<Self, Self::Error> {
+        let segments = tokenize(&template, "topic")?
+            .into_iter()
+            .map(|token| match token {
+                Token::Literal(literal) => Ok(Segment::Literal(literal.to_string())),
+                Token::Placeholder("recipient_domain") => Ok(Segment::RecipientDomain),
+                Token::Placeholder("authenticated_user") => Ok(Segment::AuthenticatedUser),
+                Token::Placeholder("from") => Ok(Segment::From),
+                Token::Placeholder(other) => Err(format!("Unknown placeholder {{{}}} in topic template", other)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if segments.is_empty() {
+            return Err(format!("Invalid topic template \"{}\"", template));
+        }
+        Ok(TopicTemplate { segments })
+    }
+}
+
+impl TopicTemplate {
+    /// Values coming from the message are sanitized, so they can't add NATS subject tokens or wildcards,
+    /// nor characters Kafka refuses in topic names.
+    pub fn render(&self, metadata: &Metadata) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(literal) => literal.clone(),
+                Segment::RecipientDomain => sanitize_value(metadata.recipient_domain().as_deref()),
+                Segment::AuthenticatedUser => sanitize_value(metadata.authenticated_user.as_deref()),
+                Segment::From => sanitize_value(Some(metadata.from.as_str())),
+            })
+            .collect()
+    }
+}
+
+/// Keeps a value to letters, digits, `-` and `_`, using `unknown` for missing values.
+fn sanitize_value(value: Option<&str>) -> String {
+    sanitize_value_keeping(value, |c| c == '-' || c == '_')
+}
+
+/// A serialized envelope, along with its id for the brokers that deduplicate publications.
+pub struct Envelope {
+    pub id: String,
+    pub payload: Vec<u8>,
+}
+
+/// Sends envelopes to a broker.
+#[async_trait]
+pub trait Publisher: Send + Sync {
+    /// Label of the metrics recorded for this broker.
+    fn provider(&self) -> &'static str;
+
+    /// Resolves once the broker has acknowledged the envelope as durably stored.
+    async fn publish(&self, topic: &str, envelope: &Envelope) -> Result<(), StorageError>;
+}
+
+/// Publishes an envelope describing each message to a message bus. The sender only gets its `250`
+/// reply once the broker has acknowledged the envelope.
+pub struct BusStorage {
+    publisher: Box<dyn Publisher>,
+    topic: TopicTemplate,
+    content: BusContent,
+    timeout: Duration,
+}
+
+impl BusStorage {
+    pub fn new(publisher: Box<dyn Publisher>, topic: TopicTemplate, content: BusContent, options: BusOptions) -> Self {
+        Self {
+            publisher,
+            topic,
+            content,
+            timeout: Duration::from_secs(options.timeout_seconds),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for BusStorage {
+    async fn save(&self, metadata: &Metadata, message: &Message<'_>) -> Result<(), StorageError> {
+        let ulid = Ulid::new();
+        let received_at: DateTime<Utc> = ulid.datetime().into();
+        let mut inline_attachments = 0;
+        let mut document = match &self.content {
+            BusContent::Inline => {
+                let attachments = collect_attachments(message);
+                inline_attachments = attachments.len();
+                message_document(metadata, message, &attachments)
+            }
+            // A failure to publish once stored has the sender retry, and the message is stored again.
+            BusContent::Stored(storage) => {
+                let Some(location) = storage.save_located(metadata, message).await? else {
+                    info!("Message {} was dropped by the backend, no envelope is published", ulid);
+                    return Ok(());
+                };
+                serde_json::json!({
+                    "metadata": metadata,
+                    "location": location,
+                })
+            }
+        };
+        document["id"] = ulid.to_string().into();
+        document["received_at"] = received_at.to_rfc3339().into();
+        let envelope = Envelope {
+            id: ulid.to_string(),
+            payload: serde_json::to_vec(&document).unwrap(),
+        };
+
+        // The backend of stored content records its own metrics, these only cover the broker.
+        let start_time = Instant::now();
+        let provider = self.publisher.provider();
+        let topic = self.topic.render(metadata);
+        let published = match tokio::time::timeout(self.timeout, self.publisher.publish(&topic, &envelope)).await {
+            Ok(published) => published,
+            Err(_) => Err(StorageError::transient(format!("no acknowledgement from {} within {:?}", topic, self.timeout))),
+        };
+        match published {
+            Ok(()) => {
+                METRICS_INSTANCE.data_storage_timing.record(start_time.elapsed().as_secs_f64(), &[KeyValue::new("provider", provider)]);
+                METRICS_INSTANCE.attachments_stored.add(inline_attachments as u64, &[KeyValue::new("provider", provider)]);
+                info!("Envelope {} published to {}", envelope.id, topic);
+                Ok(())
+            }
+            Err(e) => {
+                METRICS_INSTANCE.storage_failures.add(1, &[KeyValue::new("provider", provider)]);
+                error!("Failed to publish envelope {} to {}, error is {}", envelope.id, topic, e);
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
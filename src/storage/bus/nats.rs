@@ -0,0 +1,53 @@
+use async_nats::jetstream::context::{PublishError, PublishErrorKind};
+use async_nats::jetstream::{self, Context};
+use async_nats::HeaderMap;
+use async_trait::async_trait;
+
+use crate::storage::bus::{Envelope, Publisher};
+use crate::storage::StorageError;
+
+/// Set to the envelope id, so JetStream drops duplicates published within the stream's duplicate window.
+const MESSAGE_ID_HEADER: &str = "Nats-Msg-Id";
+
+/// Publishes envelopes to NATS JetStream. The subject must be bound to a stream, which acknowledges
+/// each envelope once stored.
+pub struct NatsPublisher {
+    jetstream: Context,
+}
+
+impl NatsPublisher {
+    pub async fn connect(url: &str) -> Result<Self, async_nats::ConnectError> {
+        let client = async_nats::connect(url).await?;
+        Ok(Self {
+            jetstream: jetstream::new(client),
+        })
+    }
+}
+
+#[async_trait]
+impl Publisher for NatsPublisher {
+    fn provider(&self) -> &'static str {
+        "Nats"
+    }
+
+    async fn publish(&self, subject: &str, envelope: &Envelope) -> Result<(), StorageError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(MESSAGE_ID_HEADER, envelope.id.as_str());
+        headers.insert("Content-Type", "application/json");
+        let ack = self
+            .jetstream
+            .publish_with_headers(subject.to_string(), headers, envelope.payload.clone().into())
+            .await
+            .map_err(classify_error)?;
+        ack.await.map_err(classify_error)?;
+        Ok(())
+    }
+}
+
+/// A subject without a stream or a payload over the server limit won't change on retry.
+fn classify_error(err: PublishError) -> StorageError {
+    match err.kind() {
+        PublishErrorKind::StreamNotFound | PublishErrorKind::MaxPayloadExceeded => StorageError::permanent(err),
+        _ => StorageError::transient(err),
+    }
+}
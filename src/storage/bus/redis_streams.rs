@@ -0,0 +1,53 @@
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+use redis::{RedisError, RetryMethod};
+
+use crate::storage::bus::{Envelope, Publisher};
+use crate::storage::StorageError;
+
+/// Appends envelopes to Redis Streams with `XADD`, as entries with an `id` and an `envelope` field.
+pub struct RedisStreamsPublisher {
+    connection: ConnectionManager,
+}
+
+impl RedisStreamsPublisher {
+    /// The connection is shared by all sessions and re-established when lost.
+    pub async fn connect(url: &str) -> Result<Self, RedisError> {
+        let client = redis::Client::open(url)?;
+        let connection = client.get_connection_manager().await?;
+        Ok(Self { connection })
+    }
+}
+
+#[async_trait]
+impl Publisher for RedisStreamsPublisher {
+    fn provider(&self) -> &'static str {
+        "RedisStreams"
+    }
+
+    async fn publish(&self, stream: &str, envelope: &Envelope) -> Result<(), StorageError> {
+        let mut connection = self.connection.clone();
+        // The reply is the entry id, sent once the entry is appended.
+        redis::cmd("XADD")
+            .arg(stream)
+            .arg("*")
+            .arg("id")
+            .arg(&envelope.id)
+            .arg("envelope")
+            .arg(&envelope.payload)
+            .query_async::<String>(&mut connection)
+            .await
+            .map_err(classify_error)?;
+        Ok(())
+    }
+}
+
+/// Errors Redis may recover from, such as a lost connection, a replica being promoted or the dataset
+/// still loading, are worth a retry. Others, such as a key holding another type, aren't.
+fn classify_error(err: RedisError) -> StorageError {
+    if err.is_timeout() || !matches!(err.retry_method(), RetryMethod::NoRetry) {
+        StorageError::transient(err)
+    } else {
+        StorageError::permanent(err)
+    }
+}
@@ -0,0 +1,40 @@
+use super::*;
+
+fn metadata() -> Metadata {
+    Metadata {
+        from: "sender@example.com".to_string(),
+        recipients: vec!["someone@Tenant.Example".to_string()],
+        authenticated_user: Some("relay".to_string()),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_placeholders_are_rendered() {
+    let template = TopicTemplate::try_from("mail.{recipient_domain}.{authenticated_user}.{from}".to_string()).unwrap();
+
+    let topic = template.render(&metadata());
+
+    assert_eq!(topic, "mail.tenant_example.relay.sender_example_com");
+}
+
+#[test]
+fn test_values_cannot_add_subject_tokens_or_wildcards() {
+    let metadata = Metadata {
+        from: "a.*.>@example.com".to_string(),
+        ..Default::default()
+    };
+    let template = TopicTemplate::try_from("mail.{from}.{recipient_domain}".to_string()).unwrap();
+
+    let topic = template.render(&metadata);
+
+    assert_eq!(topic, "mail.a_____example_com.unknown");
+}
+
+#[test]
+fn test_invalid_templates_are_rejected() {
+    assert!(TopicTemplate::try_from("".to_string()).is_err());
+    assert!(TopicTemplate::try_from("mail.{ulid}".to_string()).is_err());
+    assert!(TopicTemplate::try_from("mail.{from".to_string()).is_err());
+    assert!(TopicTemplate::try_from("mail.from}".to_string()).is_err());
+}
@@ -0,0 +1,52 @@
+use std::sync::Arc;
+
+use object_store::{gcp::GoogleCloudStorageBuilder, ClientOptions};
+
+use crate::storage::{object_storage::ObjectStorage, LayoutOptions, UploadOptions};
+
+/// Builds a storage writing to a Google Cloud Storage bucket, with the same object layout as
+/// `S3FileStorage`.
+///
+/// Without a credentials file, the application default credentials are used: the file named by
+/// `GOOGLE_APPLICATION_CREDENTIALS`, the gcloud one, or the instance metadata server.
+/// `override_gcs_endpoint` points to another endpoint, such as fake-gcs-server's, where requests
+/// are sent unauthenticated unless a service account file is also given.
+pub fn build_gcs_storage(
+    bucket_name: String,
+    credentials_file: Option<String>,
+    override_gcs_endpoint: Option<String>,
+    layout: LayoutOptions,
+    options: UploadOptions,
+) -> Result<ObjectStorage, String> {
+    let location = format!("gs://{}", bucket_name);
+    let builder = GoogleCloudStorageBuilder::new().with_bucket_name(bucket_name);
+    let builder = match (credentials_file, override_gcs_endpoint) {
+        // The endpoint can only be changed through the service account key.
+        (credentials_file, Some(endpoint)) => {
+            let mut service_account = match credentials_file {
+                Some(path) => {
+                    let contents = std::fs::read_to_string(&path)
+                        .map_err(|e| format!("Failed to read GCS credentials file {}: {}", path, e))?;
+                    serde_json::from_str(&contents)
+                        .map_err(|e| format!("Invalid GCS credentials file {}: {}", path, e))?
+                }
+                None => serde_json::json!({
+                    "private_key": "",
+                    "private_key_id": "",
+                    "client_email": "",
+                    "disable_oauth": true,
+                }),
+            };
+            service_account["gcs_base_url"] = serde_json::Value::String(endpoint.trim_end_matches('/').to_string());
+            builder
+                .with_client_options(ClientOptions::new().with_allow_http(endpoint.starts_with("http://")))
+                .with_service_account_key(service_account.to_string())
+        }
+        // Service account keys as well as authorized user credentials.
+        (Some(path), None) => builder.with_application_credentials(path),
+        (None, None) => builder,
+    };
+    let store = builder.build().map_err(|e| e.to_string())?;
+
+    Ok(ObjectStorage::new(Arc::new(store), "Gcs", location, layout, options))
+}
@@ -1,94 +1,147 @@
 use crate::metrics::METRICS_INSTANCE;
 use std::time::Instant;
-use crate::storage::attachment::determine_attachment_name;
-use async_recursion::async_recursion;
+use crate::storage::attachment::collect_numbered_attachments;
 use async_trait::async_trait;
 use mail_parser::Message;
 use opentelemetry::KeyValue;
+use serde::Deserialize;
 use std::path::{Path, PathBuf};
 use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tracing::warn;
 use ulid::Ulid;
 
 use crate::smtp::models::Metadata;
-use crate::storage::Storage;
-use super::NO_BODY_FALLBACK;
+use crate::storage::body::{body_content, describe_bodies, inline_image_sources};
+use crate::storage::{LayoutOptions, Storage, StorageError};
 
 pub struct LocalFileStorage {
     pub base_path: PathBuf,
+    pub layout: LayoutOptions,
+    pub options: LocalOptions,
+}
+
+/// Settings specific to the Local strategy.
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct LocalOptions {
+    /// Flush every file and directory to disk before replying, so accepted messages survive a power loss.
+    #[serde(default)]
+    pub fsync: bool,
 }
 
 #[async_trait]
 impl Storage for LocalFileStorage {
-    async fn save(&self, metadata: &Metadata, message: &Message<'_>) -> Result<(), std::io::Error> {
+    async fn save(&self, metadata: &Metadata, message: &Message<'_>) -> Result<(), StorageError> {
+        self.save_located(metadata, message).await.map(|_| ())
+    }
+
+    async fn save_located(&self, metadata: &Metadata, message: &Message<'_>) -> Result<Option<String>, StorageError> {
         let start_time = Instant::now();
-        let execution = Ulid::new().to_string();
-        let base_folder = &self.base_path.join(&execution);
+        let ulid = Ulid::new();
+        // Messages are written to a staging folder and moved into place once complete, so consumers
+        // never see a partially written message. Staying under the base path keeps the rename atomic.
+        let staging_folder = self.base_path.join(format!(".staging-{}", ulid));
+        let message_folder = self.base_path.join(self.layout.path_template.render(metadata, &ulid));
+
+        let result = match self.write_message(&staging_folder, metadata, message).await {
+            Ok(()) => self.commit(&staging_folder, &message_folder).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            if let Err(cleanup_err) = fs::remove_dir_all(&staging_folder).await {
+                warn!("Failed to remove staging folder {:?}, error is {:?}", staging_folder, cleanup_err);
+            }
+            METRICS_INSTANCE.storage_failures.add(1, &[KeyValue::new("provider", "Local")]);
+            return Err(e.into());
+        }
+
+        METRICS_INSTANCE.data_storage_timing.record(start_time.elapsed().as_secs_f64(), &[KeyValue::new("provider", "Local")]);
+        Ok(Some(message_folder.display().to_string()))
+    }
+}
+
+impl LocalFileStorage {
+    async fn write_message(&self, base_folder: &Path, metadata: &Metadata, message: &Message<'_>) -> Result<(), std::io::Error> {
         fs::create_dir_all(base_folder).await?;
         fs::create_dir_all(base_folder.join("attachments")).await?;
 
         // Save metadata file
-        fs::write(
-            base_folder.join("metadata.json"),
+        self.write_file(
+            &base_folder.join("metadata.json"),
             serde_json::to_string_pretty(&metadata).unwrap().as_bytes(),
         )
         .await?;
 
-        // Save message body file
-        fs::write(
-            base_folder.join("body.html"),
-            message
-                .body_html(0)
-                .unwrap_or(std::borrow::Cow::Owned(NO_BODY_FALLBACK.to_string()))
-                .as_bytes(),
-        )
-        .await?;
+        // Save message body files, pointing inline images to the stored attachments
+        let attachments = collect_numbered_attachments(message);
+        let image_sources = inline_image_sources(&attachments, &self.layout.inline_images, |attachment| {
+            attachment.relative_url()
+        });
+        for body in describe_bodies(message) {
+            self.write_file(
+                &base_folder.join(&body.file_name),
+                body_content(message, &body, &image_sources).as_bytes(),
+            )
+            .await?;
+        }
+
+        // Save the original message
+        if self.layout.store_raw_message {
+            self.write_file(&base_folder.join("message.eml"), message.raw_message()).await?;
+        }
 
         // Save attachments
-        save_attachments_from_message(&message, &base_folder.join("attachments"), 0).await?;
+        for attachment in &attachments {
+            self.write_file(&base_folder.join(attachment.relative_path()), attachment.part.contents()).await?;
+            METRICS_INSTANCE.attachments_stored.add(1, &[KeyValue::new("provider", "Local")]);
+        }
 
-        METRICS_INSTANCE.data_storage_timing.record(start_time.elapsed().as_secs_f64(), &[KeyValue::new("provider", "Local")]);
+        if self.options.fsync {
+            sync_dir(&base_folder.join("attachments")).await?;
+            sync_dir(base_folder).await?;
+        }
         Ok(())
     }
-}
 
-#[async_recursion]
-async fn save_attachments_from_message(
-    msg: &mail_parser::Message<'_>,
-    out_dir: &Path,
-    depth: usize,
-) -> Result<(), std::io::Error> {
-    for (i, part) in msg.attachments().enumerate() {
-        let name = determine_attachment_name(part, &depth, &i);
-
-        let path = dedupe_filename(out_dir.join(&name)).await;
-        fs::write(&path, part.contents()).await?;
-        METRICS_INSTANCE.attachments_stored.add(1, &[KeyValue::new("provider", "Local")]);
-
-        if let Some(nested) = part.message() {
-            save_attachments_from_message(nested, out_dir, depth + 1).await?;
+    async fn write_file(&self, path: &Path, contents: &[u8]) -> Result<(), std::io::Error> {
+        if !self.options.fsync {
+            return fs::write(path, contents).await;
         }
+        let mut file = fs::File::create(path).await?;
+        file.write_all(contents).await?;
+        file.sync_all().await
     }
-    Ok(())
-}
 
-async fn dedupe_filename(path: PathBuf) -> PathBuf {
-    if !tokio::fs::try_exists(&path).await.unwrap_or(false) {
-        return path;
-    }
-    let stem = path
-        .file_stem()
-        .and_then(|s| s.to_str())
-        .unwrap_or("attachment");
-    let ext = path.extension().and_then(|s| s.to_str()).unwrap_or("");
-    for n in 2.. {
-        let candidate = if ext.is_empty() {
-            path.with_file_name(format!("{stem} ({n})"))
-        } else {
-            path.with_file_name(format!("{stem} ({n}).{ext}"))
-        };
-        if !tokio::fs::try_exists(&candidate).await.unwrap_or(false) {
-            return candidate;
+    /// Moves the fully written message folder to its final location.
+    async fn commit(&self, staging_folder: &Path, message_folder: &Path) -> Result<(), std::io::Error> {
+        let parent = message_folder.parent().unwrap_or(&self.base_path);
+        // Every folder the path template adds is a new entry of the folder above it, up to the first
+        // one that already existed, and the staging folder leaves the base path.
+        let mut changed_folders = vec![self.base_path.clone(), parent.to_path_buf()];
+        let mut folder = parent;
+        while !fs::try_exists(folder).await? {
+            match folder.parent() {
+                Some(above) => folder = above,
+                None => break,
+            }
+            changed_folders.push(folder.to_path_buf());
+        }
+        changed_folders.sort();
+        changed_folders.dedup();
+
+        fs::create_dir_all(parent).await?;
+        fs::rename(staging_folder, message_folder).await?;
+        if self.options.fsync {
+            // Deepest first, so a folder is only linked once its own entries are on disk.
+            for folder in changed_folders.iter().rev() {
+                sync_dir(folder).await?;
+            }
         }
+        Ok(())
     }
-    unreachable!()
-}
\ No newline at end of file
+}
+
+/// Persists a directory's entries, which `sync_all` on the files themselves doesn't.
+async fn sync_dir(path: &Path) -> Result<(), std::io::Error> {
+    fs::File::open(path).await?.sync_all().await
+}
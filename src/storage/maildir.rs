@@ -0,0 +1,140 @@
+use crate::metrics::METRICS_INSTANCE;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use mail_parser::Message;
+use opentelemetry::KeyValue;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tracing::{error, info};
+
+use crate::smtp::models::Metadata;
+use crate::storage::attachment::collect_attachments;
+use crate::storage::{percent_encode, Storage, StorageError};
+
+/// Tells apart the files delivered within the same microsecond.
+static DELIVERIES: AtomicU64 = AtomicU64::new(0);
+
+/// Delivers each message into a Maildir, readable by mutt, Dovecot and other standard tools.
+pub struct MaildirStorage {
+    path: PathBuf,
+    /// Delivers into a Maildir per envelope recipient, named after the percent-encoded address, under `path`.
+    per_recipient: bool,
+    hostname: String,
+}
+
+impl MaildirStorage {
+    pub fn new(path: PathBuf, per_recipient: bool) -> Self {
+        Self {
+            path,
+            per_recipient,
+            hostname: hostname(),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for MaildirStorage {
+    async fn save(&self, metadata: &Metadata, message: &Message<'_>) -> Result<(), StorageError> {
+        let start_time = Instant::now();
+        if let Err(e) = self.deliver(metadata, message).await {
+            error!("Failed to deliver message to Maildir, error is {:?}", e);
+            METRICS_INSTANCE.storage_failures.add(1, &[KeyValue::new("provider", "Maildir")]);
+            return Err(e.into());
+        }
+        METRICS_INSTANCE.data_storage_timing.record(start_time.elapsed().as_secs_f64(), &[KeyValue::new("provider", "Maildir")]);
+        Ok(())
+    }
+}
+
+impl MaildirStorage {
+    async fn deliver(&self, metadata: &Metadata, message: &Message<'_>) -> Result<(), std::io::Error> {
+        if self.per_recipient {
+            let mut recipients: Vec<String> = metadata.recipients.iter().map(|recipient| recipient.to_lowercase()).collect();
+            recipients.sort();
+            recipients.dedup();
+            // A failure part way asks the sender to retry, so earlier recipients may get the message twice.
+            for recipient in recipients {
+                let maildir = self.path.join(percent_encode(&recipient));
+                let contents = maildir_message(metadata, Some(&recipient), message.raw_message());
+                self.deliver_to(&maildir, &contents).await?;
+            }
+        } else {
+            let contents = maildir_message(metadata, None, message.raw_message());
+            self.deliver_to(&self.path, &contents).await?;
+        }
+        METRICS_INSTANCE
+            .attachments_stored
+            .add(collect_attachments(message).len() as u64, &[KeyValue::new("provider", "Maildir")]);
+        Ok(())
+    }
+
+    /// Writes the message to `tmp/`, flushed to disk, then moves it to `new/` so readers never see it
+    /// partially written. Both are in the same Maildir, so the move is atomic.
+    async fn deliver_to(&self, maildir: &Path, contents: &[u8]) -> Result<(), std::io::Error> {
+        for folder in ["tmp", "new", "cur"] {
+            fs::create_dir_all(maildir.join(folder)).await?;
+        }
+        let file_name = self.unique_file_name(contents.len());
+        let temporary_path = maildir.join("tmp").join(&file_name);
+        let result = async {
+            let mut file = fs::File::create(&temporary_path).await?;
+            file.write_all(contents).await?;
+            file.sync_all().await?;
+            fs::rename(&temporary_path, maildir.join("new").join(&file_name)).await
+        }
+        .await;
+        if result.is_err() {
+            let _ = fs::remove_file(&temporary_path).await;
+        }
+        result?;
+        info!("Message delivered to {:?} as {}", maildir, file_name);
+        Ok(())
+    }
+
+    /// `<seconds>.M<microseconds>P<pid>Q<delivery>.<hostname>,S=<size>`, as written by Dovecot, which
+    /// reads the size from the name instead of the file.
+    fn unique_file_name(&self, size: usize) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        format!(
+            "{}.M{}P{}Q{}.{},S={}",
+            now.as_secs(),
+            now.subsec_micros(),
+            std::process::id(),
+            DELIVERIES.fetch_add(1, Ordering::Relaxed),
+            self.hostname,
+            size
+        )
+    }
+}
+
+/// The message as received, with LF line endings as Maildir readers expect, after the `Return-Path`
+/// and `Delivered-To` headers a delivery agent adds.
+fn maildir_message(metadata: &Metadata, recipient: Option<&str>, raw_message: &[u8]) -> Vec<u8> {
+    let mut contents = Vec::with_capacity(raw_message.len() + 128);
+    contents.extend_from_slice(format!("Return-Path: <{}>\n", metadata.from).as_bytes());
+    if let Some(recipient) = recipient {
+        contents.extend_from_slice(format!("Delivered-To: {}\n", recipient).as_bytes());
+    }
+    let mut lines = raw_message.split(|byte| *byte == b'\n').peekable();
+    while let Some(line) = lines.next() {
+        contents.extend_from_slice(line.strip_suffix(b"\r").unwrap_or(line));
+        if lines.peek().is_some() {
+            contents.push(b'\n');
+        }
+    }
+    contents
+}
+
+/// `/` and `:` would break the file name, the Maildir specification has them written in octal.
+fn hostname() -> String {
+    let hostname = ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .find_map(|path| std::fs::read_to_string(path).ok())
+        .map(|hostname| hostname.trim().to_string())
+        .filter(|hostname| !hostname.is_empty())
+        .unwrap_or_else(|| "localhost".to_string());
+    hostname.replace('/', "\\057").replace(':', "\\072")
+}
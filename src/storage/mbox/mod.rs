@@ -0,0 +1,135 @@
+use crate::metrics::METRICS_INSTANCE;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use mail_parser::Message;
+use opentelemetry::KeyValue;
+use serde::Deserialize;
+use tracing::{error, info};
+
+use crate::smtp::models::Metadata;
+use crate::storage::attachment::collect_attachments;
+use crate::storage::{percent_encode, Storage, StorageError};
+
+/// Which mbox file each message is appended to.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+pub enum MboxSplit {
+    /// A single file, `path` itself.
+    #[default]
+    Single,
+    /// A file per envelope recipient under the `path` folder, named after the percent-encoded address, e.g.
+    /// `user%40example.com.mbox`.
+    PerRecipient,
+    /// A file per day of reception (UTC) under the `path` folder, e.g. `2026-10-18.mbox`.
+    PerDay,
+}
+
+/// Appends each message to mbox files, in the mboxrd flavour read by desktop mail clients.
+pub struct MboxStorage {
+    path: PathBuf,
+    split: MboxSplit,
+}
+
+impl MboxStorage {
+    pub fn new(path: PathBuf, split: MboxSplit) -> Self {
+        Self { path, split }
+    }
+}
+
+#[async_trait]
+impl Storage for MboxStorage {
+    async fn save(&self, metadata: &Metadata, message: &Message<'_>) -> Result<(), StorageError> {
+        let start_time = Instant::now();
+        if let Err(e) = self.append_message(metadata, message).await {
+            error!("Failed to append message to mbox, error is {:?}", e);
+            METRICS_INSTANCE.storage_failures.add(1, &[KeyValue::new("provider", "Mbox")]);
+            return Err(e.into());
+        }
+        METRICS_INSTANCE.data_storage_timing.record(start_time.elapsed().as_secs_f64(), &[KeyValue::new("provider", "Mbox")]);
+        Ok(())
+    }
+}
+
+impl MboxStorage {
+    async fn append_message(&self, metadata: &Metadata, message: &Message<'_>) -> Result<(), std::io::Error> {
+        let received_at = Utc::now();
+        let entry = mbox_entry(&metadata.from, received_at, message.raw_message());
+        // A failure part way asks the sender to retry, so earlier files may get the message twice.
+        for file in self.files(metadata, received_at) {
+            let entry = entry.clone();
+            let appended_file = file.clone();
+            tokio::task::spawn_blocking(move || append_locked(&appended_file, &entry)).await??;
+            info!("Message appended to {:?}", file);
+        }
+        METRICS_INSTANCE
+            .attachments_stored
+            .add(collect_attachments(message).len() as u64, &[KeyValue::new("provider", "Mbox")]);
+        Ok(())
+    }
+
+    fn files(&self, metadata: &Metadata, received_at: DateTime<Utc>) -> Vec<PathBuf> {
+        match self.split {
+            MboxSplit::Single => vec![self.path.clone()],
+            MboxSplit::PerRecipient => {
+                let mut recipients: Vec<String> = metadata.recipients.iter().map(|recipient| recipient.to_lowercase()).collect();
+                recipients.sort();
+                recipients.dedup();
+                recipients
+                    .iter()
+                    .map(|recipient| self.path.join(format!("{}.mbox", percent_encode(recipient))))
+                    .collect()
+            }
+            MboxSplit::PerDay => vec![self.path.join(format!("{}.mbox", received_at.format("%Y-%m-%d")))],
+        }
+    }
+}
+
+/// Appends under an exclusive `flock`, so entries of concurrent sessions or other processes honouring
+/// the lock never interleave. A partially written entry is cut off, leaving the file as it was.
+fn append_locked(path: &Path, entry: &[u8]) -> Result<(), std::io::Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.lock()?;
+    let length = file.metadata()?.len();
+    let result = file.write_all(entry).and_then(|()| file.sync_data());
+    if result.is_err() {
+        let _ = file.set_len(length);
+    }
+    result
+}
+
+/// The `From ` separator line, then the message with LF line endings and a trailing blank line.
+/// Lines starting with any number of `>` followed by `From ` get one more `>`, which readers remove.
+fn mbox_entry(sender: &str, received_at: DateTime<Utc>, raw_message: &[u8]) -> Vec<u8> {
+    let sender = if sender.is_empty() { "MAILER-DAEMON" } else { sender };
+    let mut entry = format!("From {} {}\n", sender, received_at.format("%a %b %e %H:%M:%S %Y")).into_bytes();
+    let mut lines: Vec<&[u8]> = raw_message.split(|byte| *byte == b'\n').collect();
+    if lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+    for line in lines {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if is_from_line(line) {
+            entry.push(b'>');
+        }
+        entry.extend_from_slice(line);
+        entry.push(b'\n');
+    }
+    entry.push(b'\n');
+    entry
+}
+
+fn is_from_line(line: &[u8]) -> bool {
+    line.iter()
+        .position(|byte| *byte != b'>')
+        .is_some_and(|start| line[start..].starts_with(b"From "))
+}
+
+#[cfg(test)]
+mod tests;
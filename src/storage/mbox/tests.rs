@@ -0,0 +1,60 @@
+use super::*;
+use chrono::TimeZone;
+
+fn received_at() -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2026, 10, 8, 9, 5, 3).unwrap()
+}
+
+#[test]
+fn test_entry_starts_with_the_separator_line() {
+    let entry = mbox_entry("sender@example.com", received_at(), b"Subject: Hi\r\n\r\nHello\r\n");
+
+    assert_eq!(
+        String::from_utf8(entry).unwrap(),
+        "From sender@example.com Thu Oct  8 09:05:03 2026\nSubject: Hi\n\nHello\n\n"
+    );
+}
+
+#[test]
+fn test_from_lines_are_escaped() {
+    let entry = mbox_entry(
+        "",
+        received_at(),
+        b"Subject: Hi\r\n\r\nFrom here\r\n>From there\r\n>>From everywhere\r\nFromage\r\n>\r\n",
+    );
+
+    assert_eq!(
+        String::from_utf8(entry).unwrap(),
+        "From MAILER-DAEMON Thu Oct  8 09:05:03 2026\nSubject: Hi\n\n>From here\n>>From there\n>>>From everywhere\nFromage\n>\n\n"
+    );
+}
+
+#[test]
+fn test_files_follow_the_split() {
+    let metadata = Metadata {
+        recipients: vec!["User@Example.net".to_string(), "copy@example.org".to_string(), "user@example.net".to_string()],
+        ..Default::default()
+    };
+
+    let single = MboxStorage::new(PathBuf::from("archive.mbox"), MboxSplit::Single);
+    assert_eq!(single.files(&metadata, received_at()), vec![PathBuf::from("archive.mbox")]);
+
+    let per_recipient = MboxStorage::new(PathBuf::from("mbox"), MboxSplit::PerRecipient);
+    assert_eq!(
+        per_recipient.files(&metadata, received_at()),
+        vec![PathBuf::from("mbox/copy%40example.org.mbox"), PathBuf::from("mbox/user%40example.net.mbox")]
+    );
+
+    // Addresses only differing by a character a file name can't hold still get files of their own.
+    let metadata_with_slash = Metadata {
+        recipients: vec!["a/b@example.org".to_string(), "a_b@example.org".to_string()],
+        ..Default::default()
+    };
+    assert_eq!(
+        per_recipient.files(&metadata_with_slash, received_at()),
+        vec![PathBuf::from("mbox/a%2Fb%40example.org.mbox"), PathBuf::from("mbox/a_b%40example.org.mbox")]
+    );
+
+    let per_day = MboxStorage::new(PathBuf::from("mbox"), MboxSplit::PerDay);
+    assert_eq!(per_day.files(&metadata, received_at()), vec![PathBuf::from("mbox/2026-10-08.mbox")]);
+}
@@ -0,0 +1,154 @@
+use crate::metrics::METRICS_INSTANCE;
+use std::borrow::Cow;
+
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use mail_parser::Message;
+use opentelemetry::KeyValue;
+use tokio::sync::Semaphore;
+use tracing::error;
+
+use crate::{
+    smtp::models::Metadata,
+    storage::{
+        attachment::StoredAttachment,
+        body::{body_content, describe_bodies, inline_image_sources},
+        pick_failure, LayoutOptions, StorageError, UploadOptions,
+    },
+};
+
+/// One of the objects a message is stored as in a bucket or container.
+pub(crate) struct StoredObject<'a> {
+    pub key: String,
+    pub body: Cow<'a, [u8]>,
+    pub content_type: String,
+    pub content_disposition: Option<String>,
+    pub is_attachment: bool,
+}
+
+impl StoredObject<'_> {
+    fn json(key: String, body: Vec<u8>) -> Self {
+        StoredObject {
+            key,
+            body: Cow::Owned(body),
+            content_type: "application/json".to_string(),
+            content_disposition: None,
+            is_attachment: false,
+        }
+    }
+}
+
+/// The backends storing each message as a set of objects under a common prefix.
+#[async_trait]
+pub(crate) trait ObjectUploader: Sync {
+    /// Describes the message to the store, such as tags or user metadata, and is sent with each of its objects.
+    type Attributes: Sync;
+
+    /// Label of the metrics recorded for this backend.
+    fn provider(&self) -> &'static str;
+
+    /// Each request of the upload, such as a whole object or one of its parts, holds one of the
+    /// `permits` while in flight.
+    async fn upload_object(
+        &self,
+        object: &StoredObject<'_>,
+        attributes: &Self::Attributes,
+        permits: &Semaphore,
+    ) -> Result<(), StorageError>;
+}
+
+/// The objects of a message stored under `prefix`: its metadata, bodies, original message and
+/// attachments, with inline images in the HTML bodies pointing to `link`.
+pub(crate) fn message_objects<'a>(
+    prefix: &str,
+    metadata: &Metadata,
+    message: &'a Message<'a>,
+    attachments: &[StoredAttachment<'a>],
+    layout: &LayoutOptions,
+    link: impl Fn(&StoredAttachment) -> String,
+) -> Vec<StoredObject<'a>> {
+    let mut objects = vec![StoredObject::json(
+        format!("{}/metadata.json", prefix),
+        serde_json::to_vec_pretty(&metadata).unwrap(),
+    )];
+
+    let image_sources = inline_image_sources(attachments, &layout.inline_images, link);
+    for body in describe_bodies(message) {
+        let body_content = match body_content(message, &body, &image_sources) {
+            Cow::Borrowed(content) => Cow::Borrowed(content.as_bytes()),
+            Cow::Owned(content) => Cow::Owned(content.into_bytes()),
+        };
+        objects.push(StoredObject {
+            key: format!("{}/{}", prefix, body.file_name),
+            body: body_content,
+            content_type: format!("{}; charset=utf-8", body.content_type),
+            content_disposition: None,
+            is_attachment: false,
+        });
+    }
+
+    if layout.store_raw_message {
+        objects.push(StoredObject {
+            key: format!("{}/message.eml", prefix),
+            body: Cow::Borrowed(message.raw_message()),
+            content_type: "message/rfc822".to_string(),
+            content_disposition: None,
+            is_attachment: false,
+        });
+    }
+
+    for attachment in attachments {
+        objects.push(StoredObject {
+            key: format!("{}/{}", prefix, attachment.relative_path()),
+            body: Cow::Borrowed(attachment.part.contents()),
+            content_type: attachment.mime_type(),
+            content_disposition: Some(attachment.content_disposition()),
+            is_attachment: true,
+        });
+    }
+    objects
+}
+
+/// Uploads the objects of a message, then the `_COMPLETE` manifest once all of them are stored.
+pub(crate) async fn upload_objects<U: ObjectUploader>(
+    uploader: &U,
+    prefix: &str,
+    objects: &[StoredObject<'_>],
+    attributes: &U::Attributes,
+    options: &UploadOptions,
+) -> Result<(), StorageError> {
+    // Shared by the objects and their parts, so that no more than `max_concurrent_uploads` requests
+    // of the message are in flight however its objects are split.
+    let permits = Semaphore::new(options.concurrent_uploads());
+    let permits = &permits;
+    // Every upload runs to completion, so that each failing object gets logged.
+    let uploads: Vec<_> = objects
+        .iter()
+        .map(|object| async move {
+            uploader.upload_object(object, attributes, permits).await?;
+            if object.is_attachment {
+                METRICS_INSTANCE.attachments_stored.add(1, &[KeyValue::new("provider", uploader.provider())]);
+            }
+            Ok(())
+        })
+        .collect();
+    let results: Vec<Result<(), StorageError>> = stream::iter(uploads)
+        .buffer_unordered(options.concurrent_uploads())
+        .collect()
+        .await;
+    let failures: Vec<StorageError> = results.into_iter().filter_map(Result::err).collect();
+    if !failures.is_empty() {
+        error!("{} of {} objects under {} failed to upload", failures.len(), objects.len(), prefix);
+        return Err(pick_failure(failures));
+    }
+
+    // Written last, so watchers know the message is complete.
+    let manifest = serde_json::json!({
+        "objects": objects
+            .iter()
+            .map(|object| serde_json::json!({ "key": object.key, "size": object.body.len() }))
+            .collect::<Vec<_>>(),
+    });
+    let manifest = StoredObject::json(format!("{}/_COMPLETE", prefix), serde_json::to_vec_pretty(&manifest).unwrap());
+    uploader.upload_object(&manifest, attributes, permits).await
+}
@@ -0,0 +1,83 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use mail_parser::{Message, MimeHeaders};
+use ulid::Ulid;
+
+use crate::smtp::models::Metadata;
+use crate::storage::attachment::collect_attachments;
+use crate::storage::body::{body_content, describe_bodies};
+
+/// A message as rows of the `messages`, `recipients`, `bodies` and `attachments` tables the SQL
+/// backends share. Each backend inserts them with its own placeholders, in a single transaction
+/// so a message is stored either whole or not at all.
+pub(crate) struct MessageRows<'a> {
+    pub id: String,
+    pub received_at: DateTime<Utc>,
+    /// Client attributes relayed through XCLIENT or XFORWARD, as JSON.
+    pub forwarded: Option<String>,
+    pub raw_message: &'a [u8],
+    /// The `kind` of each address, `envelope` for RCPT TO addresses or the `to`, `cc` and `bcc` headers.
+    pub recipients: Vec<(&'static str, &'a str)>,
+    pub bodies: Vec<BodyRow<'a>>,
+    pub attachments: Vec<AttachmentRow<'a>>,
+}
+
+pub(crate) struct BodyRow<'a> {
+    pub file_name: String,
+    pub content_type: &'static str,
+    pub synthesized: bool,
+    pub content: Cow<'a, str>,
+}
+
+pub(crate) struct AttachmentRow<'a> {
+    pub name: String,
+    pub content_type: String,
+    pub content_id: Option<&'a str>,
+    pub content: &'a [u8],
+}
+
+impl<'a> MessageRows<'a> {
+    pub fn new(metadata: &'a Metadata, message: &'a Message<'a>) -> Self {
+        let ulid = Ulid::new();
+        let recipients = [
+            ("envelope", &metadata.recipients),
+            ("to", &metadata.to),
+            ("cc", &metadata.cc),
+            ("bcc", &metadata.bcc),
+        ]
+        .into_iter()
+        .flat_map(|(kind, addresses)| addresses.iter().map(move |address| (kind, address.as_str())))
+        .collect();
+        // cid: references are kept, they match the content_id of the attachments.
+        let bodies = describe_bodies(message)
+            .into_iter()
+            .map(|body| BodyRow {
+                content: body_content(message, &body, &HashMap::new()),
+                file_name: body.file_name,
+                content_type: body.content_type,
+                synthesized: body.synthesized,
+            })
+            .collect();
+        let attachments = collect_attachments(message)
+            .into_iter()
+            .map(|attachment| AttachmentRow {
+                content_type: attachment.mime_type(),
+                content_id: attachment.part.content_id(),
+                content: attachment.part.contents(),
+                name: attachment.name,
+            })
+            .collect();
+
+        MessageRows {
+            id: ulid.to_string(),
+            received_at: ulid.datetime().into(),
+            forwarded: metadata.forwarded.as_ref().map(|forwarded| serde_json::to_string(forwarded).unwrap()),
+            raw_message: message.raw_message(),
+            recipients,
+            bodies,
+            attachments,
+        }
+    }
+}
@@ -1,11 +1,29 @@
 mod attachment;
+mod message_objects;
+mod message_rows;
+pub mod azure;
+pub mod body;
+pub mod bus;
+pub mod gcs;
 
 pub mod local;
+pub mod maildir;
+pub mod mbox;
+pub mod multi;
+pub mod object_storage;
+pub mod path_template;
+pub mod postgres;
+pub mod routing;
 pub mod s3;
+pub mod sqlite;
+pub mod webhook;
 use crate::smtp::models::Metadata;
 use mail_parser::Message;
 
 use async_trait::async_trait;
+use path_template::PathTemplate;
+use serde::Deserialize;
+use std::fmt;
 
 pub const NO_BODY_FALLBACK: &str = r#"
 <html>
@@ -14,7 +32,167 @@ pub const NO_BODY_FALLBACK: &str = r#"
 </html>
 "#;
 
+/// Settings shared by the backends that write each message as a set of files.
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct LayoutOptions {
+    /// Where each message is stored, relative to the base path or bucket root.
+    #[serde(default)]
+    pub path_template: PathTemplate,
+    /// Also store the original message, exactly as received, as `message.eml`.
+    #[serde(default)]
+    pub store_raw_message: bool,
+    /// How `cid:` references to inline images are written in the stored HTML bodies.
+    #[serde(default)]
+    pub inline_images: InlineImages,
+}
+
+/// Settings shared by the backends that upload each message as a set of objects.
+#[derive(Deserialize, Debug, Clone)]
+pub struct UploadOptions {
+    /// Objects of at least this size are uploaded in parts.
+    #[serde(default = "default_multipart_threshold_bytes")]
+    pub multipart_threshold_bytes: u64,
+    /// Size of each part of a multipart upload, raised to `MIN_PART_SIZE_BYTES` when smaller.
+    #[serde(default = "default_multipart_part_size_bytes")]
+    pub multipart_part_size_bytes: u64,
+    /// How many requests uploading the objects of a message, or the parts of its large ones, are in
+    /// flight at the same time.
+    #[serde(default = "default_max_concurrent_uploads")]
+    pub max_concurrent_uploads: usize,
+}
+
+/// S3 and Google Cloud Storage reject multipart uploads whose parts, other than the last one, are smaller than this.
+pub(crate) const MIN_PART_SIZE_BYTES: u64 = 5 * 1024 * 1024;
+
+fn default_multipart_threshold_bytes() -> u64 {
+    16 * 1024 * 1024
+}
+
+fn default_multipart_part_size_bytes() -> u64 {
+    8 * 1024 * 1024
+}
+
+fn default_max_concurrent_uploads() -> usize {
+    8
+}
+
+impl Default for UploadOptions {
+    fn default() -> Self {
+        Self {
+            multipart_threshold_bytes: default_multipart_threshold_bytes(),
+            multipart_part_size_bytes: default_multipart_part_size_bytes(),
+            max_concurrent_uploads: default_max_concurrent_uploads(),
+        }
+    }
+}
+
+impl UploadOptions {
+    pub(crate) fn is_multipart(&self, size: usize) -> bool {
+        size as u64 >= self.multipart_threshold_bytes
+    }
+
+    pub(crate) fn part_size(&self) -> usize {
+        self.multipart_part_size_bytes.max(MIN_PART_SIZE_BYTES) as usize
+    }
+
+    pub(crate) fn concurrent_uploads(&self) -> usize {
+        self.max_concurrent_uploads.max(1)
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+pub enum InlineImages {
+    /// Point to the stored attachment.
+    #[default]
+    Link,
+    /// Embed the image contents as a `data:` URI.
+    DataUri,
+    /// Leave the `cid:` references untouched.
+    Keep,
+}
+
+/// Percent-encodes everything but the RFC 3986 unreserved characters.
+pub(crate) fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Why a message could not be stored, telling the sender whether retrying may help.
+#[derive(Debug)]
+pub enum StorageError {
+    /// The backend may accept the message later, e.g. timeouts, throttling or 5xx responses.
+    Transient(BoxError),
+    /// Retrying won't help, e.g. a missing bucket or denied access.
+    Permanent(BoxError),
+    /// The message was refused on purpose, e.g. by a routing rule.
+    Rejected(String),
+}
+
+impl StorageError {
+    pub fn transient(error: impl Into<BoxError>) -> Self {
+        StorageError::Transient(error.into())
+    }
+
+    pub fn permanent(error: impl Into<BoxError>) -> Self {
+        StorageError::Permanent(error.into())
+    }
+
+    pub fn is_transient(&self) -> bool {
+        matches!(self, StorageError::Transient(_))
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Transient(e) => write!(f, "transient storage failure: {}", e),
+            StorageError::Permanent(e) => write!(f, "permanent storage failure: {}", e),
+            StorageError::Rejected(reason) => write!(f, "message rejected: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StorageError::Transient(e) | StorageError::Permanent(e) => Some(e.as_ref()),
+            StorageError::Rejected(_) => None,
+        }
+    }
+}
+
+/// Local I/O failures such as a full disk or a permission problem are usually fixed by an
+/// operator, so the sender is asked to retry rather than the message being bounced.
+impl From<std::io::Error> for StorageError {
+    fn from(error: std::io::Error) -> Self {
+        StorageError::Transient(error.into())
+    }
+}
+
+/// Picks the error reported for a message that failed in several places. The message can only be
+/// retried as a whole, which is pointless if any failure is permanent.
+pub(crate) fn pick_failure(mut failures: Vec<StorageError>) -> StorageError {
+    match failures.iter().position(|e| !e.is_transient()) {
+        Some(i) => failures.swap_remove(i),
+        None => failures.pop().unwrap_or_else(|| StorageError::permanent("No storage backend configured")),
+    }
+}
+
 #[async_trait]
 pub trait Storage: Send + Sync {
-    async fn save(&self, metadata: &Metadata, message: &Message<'_>) -> Result<(), std::io::Error>;
+    async fn save(&self, metadata: &Metadata, message: &Message<'_>) -> Result<(), StorageError>;
+
+    /// Stores the message and tells where, as a path or URL, for the backends whose messages can be
+    /// read back from a single location.
+    async fn save_located(&self, metadata: &Metadata, message: &Message<'_>) -> Result<Option<String>, StorageError> {
+        self.save(metadata, message).await.map(|()| None)
+    }
 }
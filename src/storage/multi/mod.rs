@@ -0,0 +1,127 @@
+use crate::metrics::METRICS_INSTANCE;
+use std::sync::Arc;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use futures::future::join_all;
+use mail_parser::Message;
+use opentelemetry::KeyValue;
+use serde::Deserialize;
+use tracing::{error, warn};
+
+use crate::smtp::models::Metadata;
+use crate::storage::{pick_failure, Storage, StorageError};
+
+/// When a message stored by several backends counts as stored.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum MultiPolicy {
+    /// Every backend has to store the message.
+    #[serde(rename = "all")]
+    All,
+    /// At least one backend has to store the message.
+    #[serde(rename = "any")]
+    Any,
+    /// The first backend has to store the message, failures of the others are only logged. The reply
+    /// doesn't wait for the others, which finish in the background.
+    #[serde(rename = "primary+best-effort")]
+    PrimaryBestEffort,
+}
+
+/// A backend of a `MultiStorage`, named for logs and metric labels.
+pub struct NamedStorage {
+    pub name: String,
+    pub storage: Arc<dyn Storage>,
+}
+
+/// Stores each message in several backends at the same time.
+pub struct MultiStorage {
+    backends: Arc<Vec<NamedStorage>>,
+    policy: MultiPolicy,
+}
+
+impl MultiStorage {
+    pub fn new(backends: Vec<NamedStorage>, policy: MultiPolicy) -> Self {
+        Self {
+            backends: Arc::new(backends),
+            policy,
+        }
+    }
+}
+
+impl NamedStorage {
+    /// Saves with timing and failure metrics labelled with the backend name, so that two backends
+    /// of the same provider can be told apart.
+    async fn save_located(&self, metadata: &Metadata, message: &Message<'_>) -> Result<Option<String>, StorageError> {
+        let start_time = Instant::now();
+        let result = self.storage.save_located(metadata, message).await;
+        let labels = [KeyValue::new("provider", "Multi"), KeyValue::new("backend", self.name.clone())];
+        match &result {
+            Ok(_) => METRICS_INSTANCE.data_storage_timing.record(start_time.elapsed().as_secs_f64(), &labels),
+            Err(e) => {
+                error!(error.message = %e, backend = self.name, "Backend failed to save message");
+                METRICS_INSTANCE.storage_failures.add(1, &labels);
+            }
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl Storage for MultiStorage {
+    async fn save(&self, metadata: &Metadata, message: &Message<'_>) -> Result<(), StorageError> {
+        self.save_located(metadata, message).await.map(|_| ())
+    }
+
+    /// The location is the one given by the first backend, in config order, that stored the message.
+    async fn save_located(&self, metadata: &Metadata, message: &Message<'_>) -> Result<Option<String>, StorageError> {
+        match self.policy {
+            MultiPolicy::All => {
+                let results = self.save_everywhere(metadata, message).await;
+                if results.iter().all(Result::is_ok) {
+                    Ok(results.into_iter().filter_map(Result::ok).flatten().next())
+                } else {
+                    Err(pick_failure(results.into_iter().filter_map(Result::err).collect()))
+                }
+            }
+            MultiPolicy::Any => {
+                let results = self.save_everywhere(metadata, message).await;
+                let stored = results.iter().filter(|result| result.is_ok()).count();
+                if stored > 0 {
+                    if stored < results.len() {
+                        warn!("Message stored by {} of {} backends", stored, results.len());
+                    }
+                    return Ok(results.into_iter().filter_map(Result::ok).flatten().next());
+                }
+                Err(pick_failure(results.into_iter().filter_map(Result::err).collect()))
+            }
+            MultiPolicy::PrimaryBestEffort => self.save_primary(metadata, message).await,
+        }
+    }
+}
+
+impl MultiStorage {
+    /// The result of each backend, in config order.
+    async fn save_everywhere(&self, metadata: &Metadata, message: &Message<'_>) -> Vec<Result<Option<String>, StorageError>> {
+        join_all(self.backends.iter().map(|backend| backend.save_located(metadata, message))).await
+    }
+
+    /// Saves to the primary, while the other backends save a copy of the message in a task of their
+    /// own, so a slow best-effort backend doesn't hold up the reply.
+    async fn save_primary(&self, metadata: &Metadata, message: &Message<'_>) -> Result<Option<String>, StorageError> {
+        if self.backends.len() > 1 {
+            let backends = self.backends.clone();
+            let metadata = metadata.clone();
+            let message = message.clone().into_owned();
+            tokio::spawn(async move {
+                join_all(backends[1..].iter().map(|backend| backend.save_located(&metadata, &message))).await;
+            });
+        }
+        match self.backends.first() {
+            Some(primary) => primary.save_located(metadata, message).await,
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
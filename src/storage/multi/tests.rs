@@ -0,0 +1,169 @@
+use super::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use mail_parser::MessageParser;
+
+// A mock storage implementation that either always stores, at the given location if any, or always fails.
+struct MockStorage {
+    failure: Option<fn() -> StorageError>,
+    location: Option<&'static str>,
+}
+
+#[async_trait]
+impl Storage for MockStorage {
+    async fn save(&self, metadata: &Metadata, message: &Message<'_>) -> Result<(), StorageError> {
+        self.save_located(metadata, message).await.map(|_| ())
+    }
+
+    async fn save_located(&self, _metadata: &Metadata, _message: &Message<'_>) -> Result<Option<String>, StorageError> {
+        match self.failure {
+            Some(failure) => Err(failure()),
+            None => Ok(self.location.map(String::from)),
+        }
+    }
+}
+
+// A mock storage that takes a while to store.
+#[derive(Default)]
+struct SlowStorage {
+    stored: AtomicBool,
+}
+
+#[async_trait]
+impl Storage for SlowStorage {
+    async fn save(&self, _metadata: &Metadata, _message: &Message<'_>) -> Result<(), StorageError> {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        self.stored.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+fn backend(name: &str, failure: Option<fn() -> StorageError>) -> NamedStorage {
+    NamedStorage {
+        name: name.to_string(),
+        storage: Arc::new(MockStorage { failure, location: None }),
+    }
+}
+
+fn located_backend(name: &str, location: &'static str) -> NamedStorage {
+    NamedStorage {
+        name: name.to_string(),
+        storage: Arc::new(MockStorage {
+            failure: None,
+            location: Some(location),
+        }),
+    }
+}
+
+fn transient() -> StorageError {
+    StorageError::transient("request timed out")
+}
+
+fn permanent() -> StorageError {
+    StorageError::permanent("bucket does not exist")
+}
+
+async fn save(storage: &MultiStorage) -> Result<(), StorageError> {
+    save_located(storage).await.map(|_| ())
+}
+
+async fn save_located(storage: &MultiStorage) -> Result<Option<String>, StorageError> {
+    let message = MessageParser::default()
+        .parse("From: <sender@example.com>\r\nSubject: Test\r\n\r\nBody\r\n")
+        .unwrap();
+    storage.save_located(&Metadata::default(), &message).await
+}
+
+#[tokio::test]
+async fn test_all_policy_fails_when_any_backend_fails() {
+    let storage = MultiStorage::new(
+        vec![backend("local", None), backend("s3", Some(transient)), backend("archive", Some(permanent))],
+        MultiPolicy::All,
+    );
+
+    let result = save(&storage).await;
+
+    // A permanent failure wins, retrying would fail again.
+    assert!(matches!(result, Err(StorageError::Permanent(_))));
+}
+
+#[tokio::test]
+async fn test_any_policy_succeeds_when_one_backend_succeeds() {
+    let storage = MultiStorage::new(vec![backend("local", Some(permanent)), backend("s3", None)], MultiPolicy::Any);
+    assert!(save(&storage).await.is_ok());
+
+    let storage = MultiStorage::new(
+        vec![backend("local", Some(transient)), backend("s3", Some(transient))],
+        MultiPolicy::Any,
+    );
+    assert!(matches!(save(&storage).await, Err(StorageError::Transient(_))));
+}
+
+#[tokio::test]
+async fn test_primary_best_effort_policy_only_depends_on_the_primary() {
+    let storage = MultiStorage::new(
+        vec![backend("s3", None), backend("local", Some(permanent))],
+        MultiPolicy::PrimaryBestEffort,
+    );
+    assert!(save(&storage).await.is_ok());
+
+    let storage = MultiStorage::new(
+        vec![backend("s3", Some(transient)), backend("local", None)],
+        MultiPolicy::PrimaryBestEffort,
+    );
+    assert!(matches!(save(&storage).await, Err(StorageError::Transient(_))));
+}
+
+#[tokio::test]
+async fn test_primary_best_effort_policy_does_not_wait_for_the_others() {
+    let slow = Arc::new(SlowStorage::default());
+    let storage = MultiStorage::new(
+        vec![
+            backend("s3", None),
+            NamedStorage {
+                name: "archive".to_string(),
+                storage: slow.clone(),
+            },
+        ],
+        MultiPolicy::PrimaryBestEffort,
+    );
+
+    tokio::time::timeout(Duration::from_millis(100), save(&storage)).await.unwrap().unwrap();
+    assert!(!slow.stored.load(Ordering::SeqCst));
+
+    // The best-effort copy is still stored in the background.
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(slow.stored.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_policies_are_parsed_from_config() {
+    let policies: Vec<MultiPolicy> = serde_json::from_str(r#"["all", "any", "primary+best-effort"]"#).unwrap();
+
+    assert_eq!(policies, vec![MultiPolicy::All, MultiPolicy::Any, MultiPolicy::PrimaryBestEffort]);
+}
+
+#[tokio::test]
+async fn test_location_is_the_first_one_reported() {
+    let storage = MultiStorage::new(
+        vec![backend("webhook", None), located_backend("local", "/mail/1"), located_backend("s3", "s3://bucket/1")],
+        MultiPolicy::All,
+    );
+    assert_eq!(save_located(&storage).await.unwrap().as_deref(), Some("/mail/1"));
+
+    let storage = MultiStorage::new(
+        vec![
+            NamedStorage {
+                name: "local".to_string(),
+                storage: Arc::new(MockStorage {
+                    failure: Some(transient),
+                    location: Some("/mail/1"),
+                }),
+            },
+            located_backend("s3", "s3://bucket/1"),
+        ],
+        MultiPolicy::Any,
+    );
+    assert_eq!(save_located(&storage).await.unwrap().as_deref(), Some("s3://bucket/1"));
+}
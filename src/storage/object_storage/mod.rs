@@ -0,0 +1,238 @@
+use crate::metrics::METRICS_INSTANCE;
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use mail_parser::Message;
+use object_store::{path::Path, Attribute, Attributes, ObjectStore, PutMultipartOptions, PutOptions, PutPayload};
+use opentelemetry::KeyValue;
+use sha2::{Digest, Sha256};
+use tokio::sync::Semaphore;
+use tracing::{error, info};
+use ulid::Ulid;
+
+use crate::{
+    smtp::models::Metadata,
+    storage::{
+        attachment::collect_attachments,
+        message_objects::{message_objects, upload_objects, ObjectUploader, StoredObject},
+        percent_encode, LayoutOptions, Storage, StorageError, UploadOptions,
+    },
+};
+
+/// Stores messages with the same object layout as `S3FileStorage`, in any store supported by the
+/// `object_store` crate such as Azure Blob Storage or Google Cloud Storage.
+pub struct ObjectStorage {
+    store: Arc<dyn ObjectStore>,
+    /// Label of the metrics recorded for this backend.
+    provider: &'static str,
+    /// URL of the bucket or container, such as `gs://bucket`, prepended to the prefix of each message.
+    location: String,
+    layout: LayoutOptions,
+    options: UploadOptions,
+}
+
+impl ObjectStorage {
+    pub fn new(
+        store: Arc<dyn ObjectStore>,
+        provider: &'static str,
+        location: String,
+        layout: LayoutOptions,
+        options: UploadOptions,
+    ) -> Self {
+        Self {
+            store,
+            provider,
+            location,
+            layout,
+            options,
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for ObjectStorage {
+    async fn save(&self, metadata: &Metadata, message: &Message<'_>) -> Result<(), StorageError> {
+        self.save_located(metadata, message).await.map(|_| ())
+    }
+
+    async fn save_located(&self, metadata: &Metadata, message: &Message<'_>) -> Result<Option<String>, StorageError> {
+        let start_time = Instant::now();
+        let prefix = match self.upload_message(metadata, message).await {
+            Ok(prefix) => prefix,
+            Err(e) => {
+                METRICS_INSTANCE.storage_failures.add(1, &[KeyValue::new("provider", self.provider)]);
+                return Err(e);
+            }
+        };
+        METRICS_INSTANCE
+            .data_storage_timing
+            .record(start_time.elapsed().as_secs_f64(), &[KeyValue::new("provider", self.provider)]);
+        Ok(Some(format!("{}/{}", self.location, prefix)))
+    }
+}
+
+impl ObjectStorage {
+    /// Returns the prefix under which the objects were stored.
+    async fn upload_message(&self, metadata: &Metadata, message: &Message<'_>) -> Result<String, StorageError> {
+        let prefix = self.layout.path_template.render(metadata, &Ulid::new());
+        let attachments = collect_attachments(message);
+        let objects = message_objects(&prefix, metadata, message, &attachments, &self.layout, |attachment| {
+            attachment.relative_url()
+        });
+        upload_objects(self, &prefix, &objects, &user_metadata(metadata), &self.options).await?;
+        Ok(prefix)
+    }
+
+    /// Uploads a large object in parts, so that no more than a few parts are copied into memory at a time.
+    async fn upload_multipart(
+        &self,
+        path: &Path,
+        body: &[u8],
+        attributes: Attributes,
+        permits: &Semaphore,
+    ) -> object_store::Result<()> {
+        let options = PutMultipartOptions {
+            attributes,
+            ..Default::default()
+        };
+        let permit = permits.acquire().await.expect("upload permits are never closed");
+        let mut upload = self.store.put_multipart_opts(path, options).await?;
+        drop(permit);
+        // The store numbers the parts as they are created, so they are created in order. Sending
+        // one waits for a permit.
+        let part_size = self.options.part_size();
+        let parts: object_store::Result<Vec<()>> = stream::iter((0..body.len()).step_by(part_size))
+            .map(|start| {
+                let chunk = &body[start..body.len().min(start + part_size)];
+                let part = upload.put_part(PutPayload::from(chunk.to_vec()));
+                async move {
+                    let _permit = permits.acquire().await.expect("upload permits are never closed");
+                    part.await
+                }
+            })
+            .buffer_unordered(self.options.concurrent_uploads())
+            .try_collect()
+            .await;
+        let _permit = permits.acquire().await.expect("upload permits are never closed");
+        if let Err(err) = parts {
+            // Otherwise the parts already uploaded are kept until the store expires them.
+            if let Err(abort_err) = upload.abort().await {
+                error!("Failed to abort multipart upload of {}, error is {:?}", path, abort_err);
+            }
+            return Err(err);
+        }
+        upload.complete().await.map(|_| ())
+    }
+}
+
+#[async_trait]
+impl ObjectUploader for ObjectStorage {
+    type Attributes = Vec<(&'static str, String)>;
+
+    fn provider(&self) -> &'static str {
+        self.provider
+    }
+
+    async fn upload_object(
+        &self,
+        object: &StoredObject<'_>,
+        user_metadata: &Self::Attributes,
+        permits: &Semaphore,
+    ) -> Result<(), StorageError> {
+        let key = &object.key;
+        info!("About to upload {} to {}", key, self.store);
+
+        let mut attributes = Attributes::new();
+        attributes.insert(Attribute::ContentType, object.content_type.clone().into());
+        if let Some(content_disposition) = &object.content_disposition {
+            attributes.insert(Attribute::ContentDisposition, content_disposition.clone().into());
+        }
+        for (name, value) in user_metadata {
+            attributes.insert(Attribute::Metadata(Cow::Borrowed(*name)), value.clone().into());
+        }
+
+        let path = Path::from(key.as_str());
+        let upload = if self.options.is_multipart(object.body.len()) {
+            self.upload_multipart(&path, &object.body, attributes, permits).await
+        } else {
+            let options = PutOptions {
+                attributes,
+                ..Default::default()
+            };
+            let _permit = permits.acquire().await.expect("upload permits are never closed");
+            self.store
+                .put_opts(&path, PutPayload::from(object.body.to_vec()), options)
+                .await
+                .map(|_| ())
+        };
+
+        match upload {
+            Ok(()) => {
+                info!("{} uploaded successfully", key);
+                Ok(())
+            }
+            Err(err) => {
+                error!("Failed to upload {}, error is {:?}", key, err);
+                Err(classify_error(err))
+            }
+        }
+    }
+}
+
+/// Metadata describing the message, shared by all of its objects. Unlike S3, Azure only accepts
+/// names that are valid C# identifiers, hence the underscores.
+fn user_metadata(metadata: &Metadata) -> Vec<(&'static str, String)> {
+    let subject_hash = hex::encode(Sha256::digest(metadata.subject.as_bytes()));
+    [
+        ("from", metadata.from.clone()),
+        ("subject_sha256", subject_hash),
+        ("recipient_domain", metadata.recipient_domain().unwrap_or_default()),
+    ]
+    .into_iter()
+    // Header values must be ASCII, anything else is percent-encoded.
+    .map(|(name, value)| if value.is_ascii() { (name, value) } else { (name, percent_encode(&value)) })
+    .collect()
+}
+
+/// Failed requests surface as `Generic` errors once the store's own retries are exhausted. Those
+/// the store answered are retried later on 5xx, 408 and 429 responses only, a bad request or
+/// denied access won't go away by retrying. The other variants, such as a missing container, are
+/// permanent as well.
+fn classify_error(err: object_store::Error) -> StorageError {
+    let transient = match &err {
+        object_store::Error::Generic { source, .. } => match http_status(source.as_ref()) {
+            Some(status) => status >= 500 || status == 408 || status == 429,
+            // Network failures and timeouts.
+            None => true,
+        },
+        object_store::Error::JoinError { .. } => true,
+        _ => false,
+    };
+    if transient {
+        StorageError::transient(err)
+    } else {
+        StorageError::permanent(err)
+    }
+}
+
+/// The status of the response a request failed with, read from the description of the error, e.g.
+/// `Server returned non-2xx status code: 400 Bad Request: ...`. `object_store` does have a
+/// `RetryError::status`, but `RetryError` lives in a crate-private module, so the error can't be
+/// downcast to it. The tests read the status from real errors of the pinned version, so a release
+/// rewording them fails there instead of making every rejection look like a network failure.
+fn http_status(err: &(dyn std::error::Error + 'static)) -> Option<u16> {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some((_, status)) = err.to_string().split_once("status code: ") {
+            return status.get(..3).and_then(|status| status.parse().ok());
+        }
+        source = err.source();
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests;
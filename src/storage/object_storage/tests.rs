@@ -0,0 +1,119 @@
+use super::*;
+use mail_parser::MessageParser;
+use object_store::gcp::GoogleCloudStorageBuilder;
+use object_store::{ClientOptions, RetryConfig};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use crate::storage::gcs::build_gcs_storage;
+
+fn generic_error(description: &str) -> object_store::Error {
+    object_store::Error::Generic {
+        store: "Test",
+        source: description.into(),
+    }
+}
+
+#[test]
+fn test_server_errors_throttling_and_timeouts_are_transient() {
+    for status in ["500 Internal Server Error", "503 Service Unavailable", "408 Request Timeout", "429 Too Many Requests"] {
+        let err = generic_error(&format!("Error performing PUT in 1s - Server returned non-2xx status code: {}: ", status));
+        assert!(classify_error(err).is_transient(), "{}", status);
+    }
+}
+
+#[test]
+fn test_other_client_errors_are_permanent() {
+    for status in ["400 Bad Request", "403 Forbidden", "413 Payload Too Large"] {
+        let err = generic_error(&format!("Error performing PUT in 1s - Server returned non-2xx status code: {}: ", status));
+        assert!(!classify_error(err).is_transient(), "{}", status);
+    }
+}
+
+#[test]
+fn test_network_failures_are_transient() {
+    let err = generic_error("Error performing PUT in 30s - HTTP error: error sending request");
+    assert!(classify_error(err).is_transient());
+}
+
+#[test]
+fn test_store_errors_are_permanent() {
+    let err = object_store::Error::NotFound {
+        path: "bucket/key".to_string(),
+        source: "not found".into(),
+    };
+    assert!(!classify_error(err).is_transient());
+}
+
+/// Starts a server answering every request with the given status, returning its endpoint.
+async fn start_store_answering(status: &'static str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut request = [0; 8192];
+                let _ = stream.read(&mut request).await;
+                let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+    endpoint
+}
+
+/// The error `object_store` itself returns when a request is answered with the given status.
+async fn store_error(status: &'static str) -> object_store::Error {
+    let endpoint = start_store_answering(status).await;
+    let service_account = serde_json::json!({
+        "private_key": "",
+        "private_key_id": "",
+        "client_email": "",
+        "disable_oauth": true,
+        "gcs_base_url": endpoint,
+    });
+    let store = GoogleCloudStorageBuilder::new()
+        .with_bucket_name("bucket")
+        .with_client_options(ClientOptions::new().with_allow_http(true))
+        .with_service_account_key(service_account.to_string())
+        .with_retry(RetryConfig {
+            max_retries: 0,
+            ..Default::default()
+        })
+        .build()
+        .unwrap();
+    store.put(&Path::from("key"), "body".into()).await.unwrap_err()
+}
+
+#[tokio::test]
+async fn test_status_is_read_from_store_errors() {
+    for (status, expected) in [("503 Service Unavailable", 503), ("400 Bad Request", 400)] {
+        let err = store_error(status).await;
+        let object_store::Error::Generic { source, .. } = &err else {
+            panic!("unexpected error for {}: {:?}", status, err);
+        };
+        assert_eq!(http_status(source.as_ref()), Some(expected), "{}", err);
+    }
+}
+
+#[tokio::test]
+async fn test_bad_request_from_the_store_is_permanent() {
+    // Answers every request with a 400, which the store doesn't retry.
+    let endpoint = start_store_answering("400 Bad Request").await;
+    let storage = build_gcs_storage(
+        "bucket".to_string(),
+        None,
+        Some(endpoint),
+        LayoutOptions::default(),
+        UploadOptions::default(),
+    )
+    .unwrap();
+    let message = MessageParser::default()
+        .parse(b"From: <sender@example.com>\r\nSubject: Rejected\r\n\r\nBody\r\n")
+        .unwrap();
+
+    let err = storage.save(&Metadata::default(), &message).await.unwrap_err();
+
+    assert!(matches!(err, StorageError::Permanent(_)));
+}
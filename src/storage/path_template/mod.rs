@@ -0,0 +1,162 @@
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use serde::Deserialize;
+use ulid::Ulid;
+
+use crate::smtp::models::Metadata;
+
+const MAX_VALUE_LENGTH: usize = 128;
+const MISSING_VALUE: &str = "unknown";
+
+/// Where each message is stored, relative to the base path or bucket root, e.g.
+/// `{yyyy}/{mm}/{dd}/{recipient_domain}/{ulid}`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "String")]
+pub struct PathTemplate {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Year,
+    Month,
+    Day,
+    Hour,
+    RecipientDomain,
+    AuthenticatedUser,
+    From,
+    MessageId,
+    Ulid,
+}
+
+impl Default for PathTemplate {
+    fn default() -> Self {
+        PathTemplate {
+            segments: vec![Segment::Ulid],
+        }
+    }
+}
+
+impl TryFrom<String> for PathTemplate {
+    type Error = String;
+
+    fn try_from(template: String) -> Result<Self, Self::Error> {
+        let segments = tokenize(&template, "path")?
+            .into_iter()
+            .map(|token| match token {
+                Token::Literal(literal) => Ok(Segment::Literal(literal.to_string())),
+                Token::Placeholder("yyyy") => Ok(Segment::Year),
+                Token::Placeholder("mm") => Ok(Segment::Month),
+                Token::Placeholder("dd") => Ok(Segment::Day),
+                Token::Placeholder("hh") => Ok(Segment::Hour),
+                Token::Placeholder("recipient_domain") => Ok(Segment::RecipientDomain),
+                Token::Placeholder("authenticated_user") => Ok(Segment::AuthenticatedUser),
+                Token::Placeholder("from") => Ok(Segment::From),
+                Token::Placeholder("message_id") => Ok(Segment::MessageId),
+                Token::Placeholder("ulid") => Ok(Segment::Ulid),
+                Token::Placeholder(other) => Err(format!("Unknown placeholder {{{}}} in path template", other)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for segment in &segments {
+            if let Segment::Literal(literal) = segment {
+                if literal.contains('\\') || literal.split('/').any(|part| part == "..") {
+                    return Err(format!("Invalid path template \"{}\"", template));
+                }
+            }
+        }
+        // Nothing else tells messages apart, the same Message-ID may well be received twice.
+        if !segments.contains(&Segment::Ulid) {
+            return Err(format!("Path template \"{}\" must contain {{ulid}}", template));
+        }
+        Ok(PathTemplate { segments })
+    }
+}
+
+/// A piece of a template, the text around placeholders or the name of a `{placeholder}`.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Token<'a> {
+    Literal(&'a str),
+    Placeholder(&'a str),
+}
+
+/// Splits a template into literals and placeholders, `kind` naming the template in errors.
+pub(crate) fn tokenize<'a>(template: &'a str, kind: &str) -> Result<Vec<Token<'a>>, String> {
+    let mut tokens = vec![];
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            tokens.push(Token::Literal(&rest[..start]));
+        }
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("Unclosed placeholder in {} template \"{}\"", kind, template))?;
+        tokens.push(Token::Placeholder(&rest[start + 1..start + end]));
+        rest = &rest[start + end + 1..];
+    }
+    if !rest.is_empty() {
+        tokens.push(Token::Literal(rest));
+    }
+    if tokens.iter().any(|token| matches!(token, Token::Literal(literal) if literal.contains('}'))) {
+        return Err(format!("Invalid {} template \"{}\"", kind, template));
+    }
+    Ok(tokens)
+}
+
+impl PathTemplate {
+    /// Renders the template into a relative, `/`-separated path. Values coming from the message are
+    /// sanitized, so they can never add path segments nor escape the base path.
+    pub fn render(&self, metadata: &Metadata, ulid: &Ulid) -> String {
+        let received_at: DateTime<Utc> = ulid.datetime().into();
+        let mut path = String::new();
+        for segment in &self.segments {
+            let value = match segment {
+                Segment::Literal(literal) => {
+                    path.push_str(literal);
+                    continue;
+                }
+                Segment::Year => format!("{:04}", received_at.year()),
+                Segment::Month => format!("{:02}", received_at.month()),
+                Segment::Day => format!("{:02}", received_at.day()),
+                Segment::Hour => format!("{:02}", received_at.hour()),
+                Segment::RecipientDomain => sanitize_value(metadata.recipient_domain().as_deref()),
+                Segment::AuthenticatedUser => sanitize_value(metadata.authenticated_user.as_deref()),
+                Segment::From => sanitize_value(Some(metadata.from.as_str())),
+                Segment::MessageId => sanitize_value(metadata.message_id.as_deref()),
+                Segment::Ulid => ulid.to_string(),
+            };
+            path.push_str(&value);
+        }
+        path.split('/')
+            .filter(|part| !part.is_empty() && *part != ".")
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+/// Keeps a value to a single, portable path segment, using `unknown` for missing values.
+pub(crate) fn sanitize_value(value: Option<&str>) -> String {
+    sanitize_value_keeping(value, |c| matches!(c, '-' | '_' | '.' | '@' | '+' | '='))
+}
+
+/// Replaces every character but ASCII letters, digits and the punctuation `keep` accepts with `_`,
+/// using `unknown` for missing values.
+pub(crate) fn sanitize_value_keeping(value: Option<&str>, keep: impl Fn(char) -> bool) -> String {
+    let sanitized: String = value
+        .unwrap_or_default()
+        .trim_matches(|c| c == '<' || c == '>')
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || keep(c) { c } else { '_' })
+        .take(MAX_VALUE_LENGTH)
+        .collect();
+    // Leading dots would make hidden files or the ".." segment.
+    let sanitized = sanitized.trim_start_matches('.');
+    if sanitized.is_empty() {
+        MISSING_VALUE.to_string()
+    } else {
+        sanitized.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,77 @@
+use super::*;
+
+fn metadata() -> Metadata {
+    Metadata {
+        from: "sender@example.com".to_string(),
+        recipients: vec!["someone@Tenant.Example".to_string()],
+        authenticated_user: Some("relay@example.com".to_string()),
+        message_id: Some("<abc.123@example.com>".to_string()),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_default_template_is_the_ulid() {
+    let ulid = Ulid::new();
+
+    let path = PathTemplate::default().render(&metadata(), &ulid);
+
+    assert_eq!(path, ulid.to_string());
+}
+
+#[test]
+fn test_placeholders_are_rendered() {
+    // 2025-09-12T01:02:03Z
+    let ulid = Ulid::from_parts(1_757_638_923_000, 0);
+    let template =
+        PathTemplate::try_from("{yyyy}/{mm}/{dd}/{hh}/{recipient_domain}/{authenticated_user}/{from}/{message_id}/{ulid}".to_string())
+            .unwrap();
+
+    let path = template.render(&metadata(), &ulid);
+
+    assert_eq!(
+        path,
+        format!(
+            "2025/09/12/01/tenant.example/relay@example.com/sender@example.com/abc.123@example.com/{}",
+            ulid
+        )
+    );
+}
+
+#[test]
+fn test_values_cannot_escape_their_segment() {
+    let ulid = Ulid::new();
+    let metadata = Metadata {
+        from: "../../etc/passwd".to_string(),
+        message_id: Some("..".to_string()),
+        ..Default::default()
+    };
+    let template = PathTemplate::try_from("mail/{from}/{message_id}/{authenticated_user}/{ulid}".to_string()).unwrap();
+
+    let path = template.render(&metadata, &ulid);
+
+    assert_eq!(path, format!("mail/_.._etc_passwd/unknown/unknown/{}", ulid));
+}
+
+#[test]
+fn test_invalid_templates_are_rejected() {
+    assert!(PathTemplate::try_from("{yyyy}/{mm}".to_string()).is_err());
+    assert!(PathTemplate::try_from("{unknown}/{ulid}".to_string()).is_err());
+    assert!(PathTemplate::try_from("{ulid".to_string()).is_err());
+    assert!(PathTemplate::try_from("../{ulid}".to_string()).is_err());
+}
+
+#[test]
+fn test_templates_are_split_into_literals_and_placeholders() {
+    assert_eq!(
+        tokenize("mail.{recipient_domain}/{ulid}", "path").unwrap(),
+        vec![
+            Token::Literal("mail."),
+            Token::Placeholder("recipient_domain"),
+            Token::Literal("/"),
+            Token::Placeholder("ulid")
+        ]
+    );
+    assert_eq!(tokenize("{from", "topic").unwrap_err(), "Unclosed placeholder in topic template \"{from\"");
+    assert!(tokenize("mail}.{from}", "topic").is_err());
+}
@@ -0,0 +1,166 @@
+use crate::metrics::METRICS_INSTANCE;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use mail_parser::Message;
+use opentelemetry::KeyValue;
+use serde::Deserialize;
+use sqlx::migrate::Migrator;
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use tracing::{error, info};
+
+use crate::smtp::models::Metadata;
+use crate::storage::message_rows::MessageRows;
+use crate::storage::{Storage, StorageError};
+
+/// Read when the config doesn't set a URL, so the password can stay out of it.
+pub const DATABASE_URL_VARIABLE: &str = "DATABASE_URL";
+
+/// Applied on startup, each migration runs once per database.
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations/postgres");
+
+/// Settings specific to the Postgres strategy.
+#[derive(Deserialize, Debug, Clone)]
+pub struct PostgresOptions {
+    /// Upper bound of the connection pool, shared by all sessions.
+    #[serde(default = "default_max_connections")]
+    pub max_connections: u32,
+    /// How long a session waits for a free connection before the message is refused with a temporary failure.
+    #[serde(default = "default_acquire_timeout_seconds")]
+    pub acquire_timeout_seconds: u64,
+}
+
+fn default_max_connections() -> u32 {
+    10
+}
+
+fn default_acquire_timeout_seconds() -> u64 {
+    30
+}
+
+impl Default for PostgresOptions {
+    fn default() -> Self {
+        Self {
+            max_connections: default_max_connections(),
+            acquire_timeout_seconds: default_acquire_timeout_seconds(),
+        }
+    }
+}
+
+/// Stores messages in PostgreSQL tables managed with migrations: one row per message in
+/// `messages` with its recipients, bodies and attachments in child tables.
+pub struct PostgresStorage {
+    pool: PgPool,
+}
+
+impl PostgresStorage {
+    /// Connects to the database and brings its schema up to date.
+    pub async fn connect(url: &str, options: &PostgresOptions) -> Result<Self, sqlx::Error> {
+        let pool = PgPoolOptions::new()
+            .max_connections(options.max_connections)
+            .acquire_timeout(Duration::from_secs(options.acquire_timeout_seconds))
+            .connect(url)
+            .await?;
+        MIGRATOR.run(&pool).await?;
+        info!("Connected to PostgreSQL, schema is up to date");
+        Ok(Self { pool })
+    }
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    async fn save(&self, metadata: &Metadata, message: &Message<'_>) -> Result<(), StorageError> {
+        let start_time = Instant::now();
+        if let Err(e) = self.insert_message(metadata, message).await {
+            error!("Failed to insert message, error is {:?}", e);
+            METRICS_INSTANCE.storage_failures.add(1, &[KeyValue::new("provider", "Postgres")]);
+            return Err(classify_error(e));
+        }
+        METRICS_INSTANCE.data_storage_timing.record(start_time.elapsed().as_secs_f64(), &[KeyValue::new("provider", "Postgres")]);
+        Ok(())
+    }
+}
+
+impl PostgresStorage {
+    /// Dropping the transaction on error rolls it back.
+    async fn insert_message(&self, metadata: &Metadata, message: &Message<'_>) -> Result<(), sqlx::Error> {
+        let rows = MessageRows::new(metadata, message);
+        let mut transaction = self.pool.begin().await?;
+
+        sqlx::query(
+            "INSERT INTO messages (id, received_at, client, client_address, authenticated_user, mail_from, subject, date, message_id, forwarded, raw_message)
+             VALUES ($1, $2::timestamptz, $3, $4, $5, $6, $7, $8, $9, $10::jsonb, $11)",
+        )
+        .bind(&rows.id)
+        .bind(rows.received_at.to_rfc3339())
+        .bind(&metadata.client)
+        .bind(&metadata.client_address)
+        .bind(&metadata.authenticated_user)
+        .bind(&metadata.from)
+        .bind(&metadata.subject)
+        .bind(&metadata.date)
+        .bind(&metadata.message_id)
+        .bind(&rows.forwarded)
+        .bind(rows.raw_message)
+        .execute(&mut *transaction)
+        .await?;
+
+        for (kind, address) in &rows.recipients {
+            sqlx::query("INSERT INTO recipients (message_id, kind, address) VALUES ($1, $2, $3)")
+                .bind(&rows.id)
+                .bind(kind)
+                .bind(address)
+                .execute(&mut *transaction)
+                .await?;
+        }
+
+        for body in &rows.bodies {
+            sqlx::query("INSERT INTO bodies (message_id, file_name, content_type, synthesized, content) VALUES ($1, $2, $3, $4, $5)")
+                .bind(&rows.id)
+                .bind(&body.file_name)
+                .bind(body.content_type)
+                .bind(body.synthesized)
+                .bind(body.content.as_ref())
+                .execute(&mut *transaction)
+                .await?;
+        }
+
+        for attachment in &rows.attachments {
+            sqlx::query("INSERT INTO attachments (message_id, name, content_type, content_id, size, content) VALUES ($1, $2, $3, $4, $5, $6)")
+                .bind(&rows.id)
+                .bind(&attachment.name)
+                .bind(&attachment.content_type)
+                .bind(attachment.content_id)
+                .bind(attachment.content.len() as i64)
+                .bind(attachment.content)
+                .execute(&mut *transaction)
+                .await?;
+        }
+
+        transaction.commit().await?;
+        METRICS_INSTANCE
+            .attachments_stored
+            .add(rows.attachments.len() as u64, &[KeyValue::new("provider", "Postgres")]);
+        info!("Message {} stored", rows.id);
+        Ok(())
+    }
+}
+
+/// SQLSTATE classes that may go away: connection exceptions, serialization failures and deadlocks,
+/// insufficient resources, operator intervention (e.g. a restart) and system errors.
+const TRANSIENT_SQLSTATE_CLASSES: &[&str] = &["08", "40", "53", "57", "58"];
+
+fn classify_error(err: sqlx::Error) -> StorageError {
+    let transient = match &err {
+        sqlx::Error::Io(_) | sqlx::Error::Tls(_) | sqlx::Error::PoolTimedOut => true,
+        sqlx::Error::Database(e) => e
+            .code()
+            .is_some_and(|code| TRANSIENT_SQLSTATE_CLASSES.iter().any(|class| code.starts_with(class))),
+        _ => false,
+    };
+    if transient {
+        StorageError::transient(err)
+    } else {
+        StorageError::permanent(err)
+    }
+}
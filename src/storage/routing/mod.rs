@@ -0,0 +1,156 @@
+use crate::metrics::METRICS_INSTANCE;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use mail_parser::Message;
+use opentelemetry::KeyValue;
+use regex::Regex;
+use serde::Deserialize;
+use tracing::info;
+
+use crate::smtp::models::Metadata;
+use crate::storage::{Storage, StorageError};
+
+/// A rule matching a message when all of its conditions do. A rule without conditions matches
+/// every message.
+#[derive(Deserialize, Debug, Clone)]
+pub struct RoutingRule {
+    /// Domain of any of the envelope recipients, case-insensitive.
+    pub recipient_domain: Option<String>,
+    pub authenticated_user: Option<String>,
+    /// Envelope sender, case-insensitive.
+    pub sender: Option<String>,
+    pub subject: Option<Pattern>,
+    pub header: Option<HeaderCondition>,
+    pub action: RouteAction,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HeaderCondition {
+    pub name: String,
+    pub value: Pattern,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum RouteAction {
+    /// Store the message in the named backend.
+    Store { backend: String },
+    /// Accept the message without storing it.
+    Drop,
+    /// Refuse the message with a permanent error.
+    Reject,
+}
+
+/// A regular expression read from the config.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "String")]
+pub struct Pattern(Regex);
+
+impl TryFrom<String> for Pattern {
+    type Error = regex::Error;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        Regex::new(&pattern).map(Pattern)
+    }
+}
+
+impl RoutingRule {
+    fn matches(&self, metadata: &Metadata, message: &Message<'_>) -> bool {
+        let recipient_domain_matches = self.recipient_domain.as_ref().is_none_or(|domain| {
+            metadata.recipients.iter().any(|recipient| {
+                recipient
+                    .rsplit_once('@')
+                    .is_some_and(|(_, recipient_domain)| recipient_domain.eq_ignore_ascii_case(domain))
+            })
+        });
+        let authenticated_user_matches = self
+            .authenticated_user
+            .as_ref()
+            .is_none_or(|user| metadata.authenticated_user.as_ref() == Some(user));
+        let sender_matches = self
+            .sender
+            .as_ref()
+            .is_none_or(|sender| metadata.from.eq_ignore_ascii_case(sender));
+        let subject_matches = self
+            .subject
+            .as_ref()
+            .is_none_or(|Pattern(subject)| subject.is_match(&metadata.subject));
+        let header_matches = self.header.as_ref().is_none_or(|header| {
+            message
+                .header_raw(header.name.as_str())
+                .is_some_and(|value| header.value.0.is_match(value.trim()))
+        });
+
+        recipient_domain_matches && authenticated_user_matches && sender_matches && subject_matches && header_matches
+    }
+}
+
+/// Picks the backend of each message from rules evaluated in order, falling back to a default action.
+pub struct RoutingStorage {
+    backends: HashMap<String, Arc<dyn Storage>>,
+    rules: Vec<RoutingRule>,
+    default_action: RouteAction,
+}
+
+impl RoutingStorage {
+    pub fn new(
+        backends: HashMap<String, Arc<dyn Storage>>,
+        rules: Vec<RoutingRule>,
+        default_action: RouteAction,
+    ) -> Result<Self, String> {
+        let actions = rules.iter().map(|rule| &rule.action).chain([&default_action]);
+        for action in actions {
+            if let RouteAction::Store { backend } = action {
+                if !backends.contains_key(backend) {
+                    return Err(format!("Routing rule refers to unknown backend {}", backend));
+                }
+            }
+        }
+        Ok(Self {
+            backends,
+            rules,
+            default_action,
+        })
+    }
+
+    fn route(&self, metadata: &Metadata, message: &Message<'_>) -> &RouteAction {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(metadata, message))
+            .map(|rule| &rule.action)
+            .unwrap_or(&self.default_action)
+    }
+}
+
+#[async_trait]
+impl Storage for RoutingStorage {
+    async fn save(&self, metadata: &Metadata, message: &Message<'_>) -> Result<(), StorageError> {
+        self.save_located(metadata, message).await.map(|_| ())
+    }
+
+    async fn save_located(&self, metadata: &Metadata, message: &Message<'_>) -> Result<Option<String>, StorageError> {
+        match self.route(metadata, message) {
+            RouteAction::Store { backend } => {
+                METRICS_INSTANCE
+                    .messages_routed
+                    .add(1, &[KeyValue::new("action", "Store"), KeyValue::new("backend", backend.clone())]);
+                self.backends[backend].save_located(metadata, message).await
+            }
+            RouteAction::Drop => {
+                info!(from = metadata.from, "Message dropped by routing rules");
+                METRICS_INSTANCE.messages_routed.add(1, &[KeyValue::new("action", "Drop")]);
+                Ok(None)
+            }
+            RouteAction::Reject => {
+                info!(from = metadata.from, "Message rejected by routing rules");
+                METRICS_INSTANCE.messages_routed.add(1, &[KeyValue::new("action", "Reject")]);
+                Err(StorageError::Rejected("Message rejected by routing rules".to_string()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
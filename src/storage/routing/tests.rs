@@ -0,0 +1,126 @@
+use super::*;
+use mail_parser::MessageParser;
+use std::sync::Mutex;
+
+// A mock storage implementation that records how many messages it stored, locating each by its number.
+#[derive(Default)]
+struct CountingStorage {
+    saved: Mutex<usize>,
+}
+
+#[async_trait]
+impl Storage for CountingStorage {
+    async fn save(&self, metadata: &Metadata, message: &Message<'_>) -> Result<(), StorageError> {
+        self.save_located(metadata, message).await.map(|_| ())
+    }
+
+    async fn save_located(&self, _metadata: &Metadata, _message: &Message<'_>) -> Result<Option<String>, StorageError> {
+        let mut saved = self.saved.lock().unwrap();
+        *saved += 1;
+        Ok(Some(format!("message-{}", saved)))
+    }
+}
+
+fn rules(json: &str) -> Vec<RoutingRule> {
+    serde_json::from_str(json).unwrap()
+}
+
+fn metadata(recipient: &str, subject: &str) -> Metadata {
+    Metadata {
+        from: "sender@example.com".to_string(),
+        recipients: vec![recipient.to_string()],
+        subject: subject.to_string(),
+        ..Default::default()
+    }
+}
+
+const RAW_MESSAGE: &str = "From: <sender@example.com>\r\nX-Tenant: billing\r\nSubject: Test\r\n\r\nBody\r\n";
+
+#[tokio::test]
+async fn test_first_matching_rule_picks_the_backend() {
+    let team_a = Arc::new(CountingStorage::default());
+    let team_b = Arc::new(CountingStorage::default());
+    let backends: HashMap<String, Arc<dyn Storage>> = HashMap::from([
+        ("team-a".to_string(), team_a.clone() as Arc<dyn Storage>),
+        ("team-b".to_string(), team_b.clone() as Arc<dyn Storage>),
+    ]);
+    let storage = RoutingStorage::new(
+        backends,
+        rules(
+            r#"[
+                { "recipient_domain": "a.example", "action": { "type": "Store", "backend": "team-a" } },
+                { "header": { "name": "X-Tenant", "value": "^billing$" }, "action": { "type": "Store", "backend": "team-b" } },
+                { "action": { "type": "Store", "backend": "team-a" } }
+            ]"#,
+        ),
+        RouteAction::Reject,
+    )
+    .unwrap();
+    let message = MessageParser::default().parse(RAW_MESSAGE).unwrap();
+
+    storage.save(&metadata("user@A.example", "Test"), &message).await.unwrap();
+    storage.save(&metadata("user@b.example", "Test"), &message).await.unwrap();
+
+    assert_eq!(*team_a.saved.lock().unwrap(), 1);
+    assert_eq!(*team_b.saved.lock().unwrap(), 1);
+}
+
+#[tokio::test]
+async fn test_messages_can_be_dropped_or_rejected() {
+    let archive = Arc::new(CountingStorage::default());
+    let backends: HashMap<String, Arc<dyn Storage>> =
+        HashMap::from([("archive".to_string(), archive.clone() as Arc<dyn Storage>)]);
+    let storage = RoutingStorage::new(
+        backends,
+        rules(
+            r#"[
+                { "subject": "(?i)^\\[spam\\]", "action": { "type": "Drop" } },
+                { "sender": "sender@example.com", "authenticated_user": "relay@example.com", "action": { "type": "Store", "backend": "archive" } }
+            ]"#,
+        ),
+        RouteAction::Reject,
+    )
+    .unwrap();
+    let message = MessageParser::default().parse(RAW_MESSAGE).unwrap();
+
+    let dropped = storage.save(&metadata("user@example.net", "[SPAM] Buy now"), &message).await;
+    // Not authenticated as relay@example.com, so only the default action applies.
+    let rejected = storage.save(&metadata("user@example.net", "Hello"), &message).await;
+
+    assert!(dropped.is_ok());
+    assert!(matches!(rejected, Err(StorageError::Rejected(_))));
+    assert_eq!(*archive.saved.lock().unwrap(), 0);
+}
+
+#[test]
+fn test_unknown_backends_are_refused() {
+    let result = RoutingStorage::new(
+        HashMap::new(),
+        vec![],
+        RouteAction::Store {
+            backend: "missing".to_string(),
+        },
+    );
+
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn test_location_of_the_chosen_backend_is_forwarded() {
+    let backends: HashMap<String, Arc<dyn Storage>> =
+        HashMap::from([("archive".to_string(), Arc::new(CountingStorage::default()) as Arc<dyn Storage>)]);
+    let storage = RoutingStorage::new(
+        backends,
+        rules(r#"[{ "subject": "^Drop", "action": { "type": "Drop" } }]"#),
+        RouteAction::Store {
+            backend: "archive".to_string(),
+        },
+    )
+    .unwrap();
+    let message = MessageParser::default().parse(RAW_MESSAGE).unwrap();
+
+    let location = storage.save_located(&metadata("user@example.com", "Test"), &message).await.unwrap();
+    assert_eq!(location.as_deref(), Some("message-1"));
+    let location = storage.save_located(&metadata("user@example.com", "Drop me"), &message).await.unwrap();
+    assert_eq!(location, None);
+}
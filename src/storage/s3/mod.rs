@@ -0,0 +1,494 @@
+use crate::metrics::METRICS_INSTANCE;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use aws_sdk_s3::{
+    config::{retry::RetryConfig, timeout::TimeoutConfig},
+    error::{ProvideErrorMetadata, SdkError},
+    presigning::PresigningConfig,
+    primitives::ByteStream,
+    types::{ChecksumAlgorithm, CompletedMultipartUpload, CompletedPart, ServerSideEncryption, StorageClass},
+    Client,
+};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use mail_parser::Message;
+use opentelemetry::KeyValue;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::Semaphore;
+use tracing::{error, info, warn};
+use ulid::Ulid;
+
+use crate::{
+    smtp::models::Metadata,
+    storage::{
+        attachment::{collect_attachments, StoredAttachment},
+        message_objects::{message_objects, upload_objects, ObjectUploader, StoredObject},
+        percent_encode, LayoutOptions, Storage, StorageError, UploadOptions,
+    },
+};
+
+/// Tags and user metadata describing the message, shared by all of its objects.
+pub(crate) struct MessageAttributes {
+    tagging: String,
+    metadata: HashMap<String, String>,
+}
+
+impl MessageAttributes {
+    fn new(metadata: &Metadata) -> Self {
+        let subject_hash = hex::encode(Sha256::digest(metadata.subject.as_bytes()));
+        let attributes = [
+            ("from", metadata.from.clone()),
+            ("subject-sha256", subject_hash),
+            ("recipient-domain", metadata.recipient_domain().unwrap_or_default()),
+        ];
+        let tagging = attributes
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, percent_encode(&tag_value(value))))
+            .collect::<Vec<_>>()
+            .join("&");
+        // Header values must be ASCII, anything else is percent-encoded.
+        let metadata = attributes
+            .into_iter()
+            .map(|(key, value)| {
+                let value = if value.is_ascii() { value } else { percent_encode(&value) };
+                (key.to_string(), value)
+            })
+            .collect();
+        MessageAttributes { tagging, metadata }
+    }
+}
+
+/// S3 limits tag values to 256 characters.
+const MAX_TAG_VALUE_LENGTH: usize = 256;
+
+/// Replaces the characters S3 doesn't allow in tag values, which would fail the upload.
+fn tag_value(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | ' ' | '+' | '-' | '=' | '.' | '_' | ':' | '/' | '@' => c,
+            _ => '_',
+        })
+        .take(MAX_TAG_VALUE_LENGTH)
+        .collect()
+}
+
+/// Settings specific to the S3 strategy.
+#[derive(Deserialize, Debug, Clone)]
+pub struct S3Options {
+    /// Link inline images with presigned URLs valid for this long, instead of relative keys.
+    pub presigned_url_expiration_seconds: Option<u64>,
+    /// Attempts per object, including the first one.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled on each following attempt and randomized with jitter.
+    #[serde(default = "default_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    /// Upper bound for the delay between attempts.
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+    /// Time limit for each attempt, after which it is cancelled and retried.
+    #[serde(default = "default_request_timeout_seconds")]
+    pub request_timeout_seconds: u64,
+    /// Checksum sent with each object so S3 rejects corrupted uploads.
+    #[serde(default)]
+    pub checksum_algorithm: S3Checksum,
+    #[serde(flatten)]
+    pub uploads: UploadOptions,
+    /// Server-side encryption requested for every object, leaving it to the bucket default when unset.
+    pub server_side_encryption: Option<S3Encryption>,
+    /// KMS key used with `Kms` encryption, the AWS managed key when unset.
+    pub kms_key_id: Option<String>,
+    /// Storage class of every object, such as `STANDARD_IA` or `GLACIER_IR`, the bucket default when unset.
+    pub storage_class: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub enum S3Encryption {
+    /// SSE-S3, keys managed by S3.
+    S3,
+    /// SSE-KMS, keys managed by AWS KMS.
+    Kms,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+pub enum S3Checksum {
+    #[default]
+    Crc32C,
+    Sha256,
+}
+
+impl From<&S3Checksum> for ChecksumAlgorithm {
+    fn from(checksum: &S3Checksum) -> Self {
+        match checksum {
+            S3Checksum::Crc32C => ChecksumAlgorithm::Crc32C,
+            S3Checksum::Sha256 => ChecksumAlgorithm::Sha256,
+        }
+    }
+}
+
+fn default_max_attempts() -> u32 {
+    3
+}
+
+fn default_initial_backoff_ms() -> u64 {
+    200
+}
+
+fn default_max_backoff_ms() -> u64 {
+    20_000
+}
+
+fn default_request_timeout_seconds() -> u64 {
+    30
+}
+
+impl Default for S3Options {
+    fn default() -> Self {
+        Self {
+            presigned_url_expiration_seconds: None,
+            max_attempts: default_max_attempts(),
+            initial_backoff_ms: default_initial_backoff_ms(),
+            max_backoff_ms: default_max_backoff_ms(),
+            request_timeout_seconds: default_request_timeout_seconds(),
+            checksum_algorithm: S3Checksum::default(),
+            uploads: UploadOptions::default(),
+            server_side_encryption: None,
+            kms_key_id: None,
+            storage_class: None,
+        }
+    }
+}
+
+pub struct S3FileStorage {
+    client: Client,
+    bucket_name: String,
+    layout: LayoutOptions,
+    options: S3Options,
+}
+
+impl S3FileStorage {
+    pub fn new(client: Client, bucket: String, layout: LayoutOptions, options: S3Options) -> Self {
+        let config = client_config(client.config(), &options);
+
+        if let Some(storage_class) = &options.storage_class {
+            if !StorageClass::values().contains(&storage_class.as_str()) {
+                warn!("Unknown S3 storage class {}, uploads will likely be rejected", storage_class);
+            }
+        }
+
+        Self {
+            client: Client::from_conf(config),
+            bucket_name: bucket,
+            layout,
+            options,
+        }
+    }
+}
+
+/// The client configuration with the retry and timeout settings of `options` applied, keeping the
+/// other timeouts already configured.
+fn client_config(config: &aws_sdk_s3::Config, options: &S3Options) -> aws_sdk_s3::Config {
+    // Throttling, 5xx responses and timeouts are retried by the SDK with exponential backoff and jitter.
+    let retry_config = RetryConfig::standard()
+        .with_max_attempts(options.max_attempts.max(1))
+        .with_initial_backoff(Duration::from_millis(options.initial_backoff_ms))
+        .with_max_backoff(Duration::from_millis(options.max_backoff_ms));
+    let timeout_config = config
+        .timeout_config()
+        .map(TimeoutConfig::to_builder)
+        .unwrap_or_default()
+        .operation_attempt_timeout(Duration::from_secs(options.request_timeout_seconds))
+        .build();
+    config
+        .to_builder()
+        .retry_config(retry_config)
+        .timeout_config(timeout_config)
+        .build()
+}
+
+#[async_trait]
+impl Storage for S3FileStorage {
+    async fn save(&self, metadata: &Metadata, message: &Message<'_>) -> Result<(), StorageError> {
+        self.save_located(metadata, message).await.map(|_| ())
+    }
+
+    async fn save_located(&self, metadata: &Metadata, message: &Message<'_>) -> Result<Option<String>, StorageError> {
+        let start_time = Instant::now();
+        let prefix = match self.upload_message(metadata, message).await {
+            Ok(prefix) => prefix,
+            Err(e) => {
+                METRICS_INSTANCE.storage_failures.add(1, &[KeyValue::new("provider", "S3")]);
+                return Err(e);
+            }
+        };
+        METRICS_INSTANCE.data_storage_timing.record(start_time.elapsed().as_secs_f64(), &[KeyValue::new("provider", "S3")]);
+        Ok(Some(format!("s3://{}/{}", self.bucket_name, prefix)))
+    }
+}
+
+impl S3FileStorage {
+    /// Returns the prefix under which the objects were stored.
+    async fn upload_message(&self, metadata: &Metadata, message: &Message<'_>) -> Result<String, StorageError> {
+        let prefix = self.layout.path_template.render(metadata, &Ulid::new());
+        let attachments = collect_attachments(message);
+        let attachment_links = self.attachment_links(&prefix, &attachments).await?;
+        let objects = message_objects(&prefix, metadata, message, &attachments, &self.layout, |attachment| {
+            attachment_links
+                .get(&attachment.name)
+                .cloned()
+                .unwrap_or_else(|| attachment.relative_url())
+        });
+        upload_objects(self, &prefix, &objects, &MessageAttributes::new(metadata), &self.options.uploads).await?;
+        Ok(prefix)
+    }
+
+    fn server_side_encryption(&self) -> Option<ServerSideEncryption> {
+        self.options.server_side_encryption.as_ref().map(|encryption| match encryption {
+            S3Encryption::S3 => ServerSideEncryption::Aes256,
+            S3Encryption::Kms => ServerSideEncryption::AwsKms,
+        })
+    }
+
+    fn kms_key_id(&self) -> Option<String> {
+        match self.options.server_side_encryption {
+            Some(S3Encryption::Kms) => self.options.kms_key_id.clone(),
+            _ => None,
+        }
+    }
+
+    fn storage_class(&self) -> Option<StorageClass> {
+        self.options.storage_class.as_deref().map(StorageClass::from)
+    }
+
+    /// Presigned URLs for the attachments, when configured. Otherwise relative keys are used.
+    async fn attachment_links(
+        &self,
+        prefix: &str,
+        attachments: &[StoredAttachment<'_>],
+    ) -> Result<HashMap<String, String>, StorageError> {
+        let mut links = HashMap::new();
+        let Some(expiration) = self.options.presigned_url_expiration_seconds else {
+            return Ok(links);
+        };
+        let presigning_config = PresigningConfig::expires_in(Duration::from_secs(expiration))
+            .map_err(StorageError::permanent)?;
+        for attachment in attachments {
+            let presigned = self
+                .client
+                .get_object()
+                .bucket(self.bucket_name.clone())
+                .key(format!("{}/{}", prefix, attachment.relative_path()))
+                .presigned(presigning_config.clone())
+                .await
+                .map_err(StorageError::permanent)?;
+            links.insert(attachment.name.clone(), presigned.uri().to_string());
+        }
+        Ok(links)
+    }
+
+    async fn put_object(
+        &self,
+        object: &StoredObject<'_>,
+        attributes: &MessageAttributes,
+        permits: &Semaphore,
+    ) -> Result<(), StorageError> {
+        let _permit = permits.acquire().await.expect("upload permits are never closed");
+        self.client
+            .put_object()
+            .bucket(self.bucket_name.clone())
+            .key(&object.key)
+            .checksum_algorithm((&self.options.checksum_algorithm).into())
+            .content_type(&object.content_type)
+            .set_content_disposition(object.content_disposition.clone())
+            .set_server_side_encryption(self.server_side_encryption())
+            .set_ssekms_key_id(self.kms_key_id())
+            .set_storage_class(self.storage_class())
+            .tagging(&attributes.tagging)
+            .set_metadata(Some(attributes.metadata.clone()))
+            .body(ByteStream::from(object.body.to_vec()))
+            .send()
+            .await
+            .map_err(classify_sdk_error)?;
+        Ok(())
+    }
+
+    async fn upload_multipart(
+        &self,
+        object: &StoredObject<'_>,
+        attributes: &MessageAttributes,
+        permits: &Semaphore,
+    ) -> Result<(), StorageError> {
+        let key = &object.key;
+        let permit = permits.acquire().await.expect("upload permits are never closed");
+        let upload = self
+            .client
+            .create_multipart_upload()
+            .bucket(self.bucket_name.clone())
+            .key(key)
+            .checksum_algorithm((&self.options.checksum_algorithm).into())
+            .content_type(&object.content_type)
+            .set_content_disposition(object.content_disposition.clone())
+            .set_server_side_encryption(self.server_side_encryption())
+            .set_ssekms_key_id(self.kms_key_id())
+            .set_storage_class(self.storage_class())
+            .tagging(&attributes.tagging)
+            .set_metadata(Some(attributes.metadata.clone()))
+            .send()
+            .await
+            .map_err(classify_sdk_error)?;
+        let upload_id = upload
+            .upload_id()
+            .ok_or_else(|| StorageError::permanent("S3 returned no multipart upload id"))?;
+        drop(permit);
+
+        let parts = self.upload_parts(key, upload_id, &object.body, permits).await;
+        let _permit = permits.acquire().await.expect("upload permits are never closed");
+        match parts {
+            Ok(parts) => {
+                self.client
+                    .complete_multipart_upload()
+                    .bucket(self.bucket_name.clone())
+                    .key(key)
+                    .upload_id(upload_id)
+                    .multipart_upload(CompletedMultipartUpload::builder().set_parts(Some(parts)).build())
+                    .send()
+                    .await
+                    .map_err(classify_sdk_error)?;
+                Ok(())
+            }
+            Err(err) => {
+                // Otherwise the parts already uploaded are kept, and billed, until a lifecycle rule removes them.
+                if let Err(abort_err) = self
+                    .client
+                    .abort_multipart_upload()
+                    .bucket(self.bucket_name.clone())
+                    .key(key)
+                    .upload_id(upload_id)
+                    .send()
+                    .await
+                {
+                    error!("Failed to abort multipart upload of {}, error is {:?}", key, abort_err);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Uploads the parts concurrently. A part is only copied into memory once it holds a permit.
+    async fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        body: &[u8],
+        permits: &Semaphore,
+    ) -> Result<Vec<CompletedPart>, StorageError> {
+        let part_size = self.options.uploads.part_size();
+        let uploads: Vec<_> = body
+            .chunks(part_size)
+            .enumerate()
+            .map(|(i, chunk)| async move {
+                let part_number = i as i32 + 1;
+                let _permit = permits.acquire().await.expect("upload permits are never closed");
+                let part = self
+                    .client
+                    .upload_part()
+                    .bucket(self.bucket_name.clone())
+                    .key(key)
+                    .upload_id(upload_id)
+                    .part_number(part_number)
+                    .checksum_algorithm((&self.options.checksum_algorithm).into())
+                    .body(ByteStream::from(chunk.to_vec()))
+                    .send()
+                    .await
+                    .map_err(classify_sdk_error)?;
+                Ok(CompletedPart::builder()
+                    .part_number(part_number)
+                    .set_e_tag(part.e_tag().map(String::from))
+                    .set_checksum_crc32_c(part.checksum_crc32_c().map(String::from))
+                    .set_checksum_sha256(part.checksum_sha256().map(String::from))
+                    .build())
+            })
+            .collect();
+        // Completing the upload needs the parts in order, the first failure cancels the others.
+        stream::iter(uploads)
+            .buffered(self.options.uploads.concurrent_uploads())
+            .try_collect()
+            .await
+    }
+}
+
+#[async_trait]
+impl ObjectUploader for S3FileStorage {
+    type Attributes = MessageAttributes;
+
+    fn provider(&self) -> &'static str {
+        "S3"
+    }
+
+    /// Uploads an object, in parts when it is larger than the multipart threshold, so that only the
+    /// parts being sent are copied into memory.
+    async fn upload_object(
+        &self,
+        object: &StoredObject<'_>,
+        attributes: &Self::Attributes,
+        permits: &Semaphore,
+    ) -> Result<(), StorageError> {
+        let key = &object.key;
+        info!("About to upload {} to bucket {}", key, self.bucket_name);
+
+        let upload = if self.options.uploads.is_multipart(object.body.len()) {
+            self.upload_multipart(object, attributes, permits).await
+        } else {
+            self.put_object(object, attributes, permits).await
+        };
+
+        match upload {
+            Ok(()) => {
+                info!("{} uploaded successfully", key);
+                Ok(())
+            }
+            Err(err) => {
+                error!("Failed to upload {}, error is {:?}", key, err);
+                Err(err)
+            }
+        }
+    }
+}
+
+/// S3 error codes that may succeed when the sender retries later.
+const TRANSIENT_ERROR_CODES: &[&str] = &[
+    "RequestTimeout",
+    "RequestTimeTooSkewed",
+    "SlowDown",
+    "Throttling",
+    "ThrottlingException",
+    "InternalError",
+    "ServiceUnavailable",
+    // The checksum didn't match what was sent, the object got corrupted in transit.
+    "BadDigest",
+];
+
+fn classify_sdk_error<E, R>(err: SdkError<E, R>) -> StorageError
+where
+    E: ProvideErrorMetadata + std::error::Error + Send + Sync + 'static,
+    R: std::fmt::Debug + Send + Sync + 'static,
+{
+    let transient = match &err {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => true,
+        SdkError::ServiceError(_) => err
+            .code()
+            .is_some_and(|code| TRANSIENT_ERROR_CODES.contains(&code)),
+        _ => false,
+    };
+    if transient {
+        StorageError::transient(err)
+    } else {
+        StorageError::permanent(err)
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,320 @@
+use super::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use aws_sdk_s3::config::http::{HttpRequest, HttpResponse};
+use aws_sdk_s3::config::{BehaviorVersion, Credentials, Region};
+use aws_smithy_runtime_api::client::http::{http_client_fn, HttpConnector, HttpConnectorFuture, SharedHttpConnector};
+use aws_smithy_runtime_api::http::StatusCode;
+use aws_smithy_types::body::SdkBody;
+use mail_parser::MessageParser;
+use tokio::sync::Semaphore;
+
+use crate::storage::MIN_PART_SIZE_BYTES;
+
+fn base_config() -> aws_sdk_s3::Config {
+    aws_sdk_s3::Config::builder()
+        .behavior_version(BehaviorVersion::latest())
+        .timeout_config(TimeoutConfig::builder().connect_timeout(Duration::from_secs(2)).build())
+        .build()
+}
+
+#[test]
+fn test_client_config_applies_retry_settings() {
+    let options = S3Options {
+        max_attempts: 5,
+        initial_backoff_ms: 100,
+        max_backoff_ms: 1_000,
+        ..Default::default()
+    };
+
+    let config = client_config(&base_config(), &options);
+
+    let retry_config = config.retry_config().unwrap();
+    assert_eq!(retry_config.max_attempts(), 5);
+    assert_eq!(retry_config.initial_backoff(), Duration::from_millis(100));
+    assert_eq!(retry_config.max_backoff(), Duration::from_millis(1_000));
+}
+
+#[test]
+fn test_client_config_makes_at_least_one_attempt() {
+    let options = S3Options {
+        max_attempts: 0,
+        ..Default::default()
+    };
+
+    let config = client_config(&base_config(), &options);
+
+    assert_eq!(config.retry_config().unwrap().max_attempts(), 1);
+}
+
+#[test]
+fn test_client_config_keeps_other_timeouts() {
+    let options = S3Options {
+        request_timeout_seconds: 7,
+        ..Default::default()
+    };
+
+    let config = client_config(&base_config(), &options);
+
+    let timeout_config = config.timeout_config().unwrap();
+    assert_eq!(timeout_config.operation_attempt_timeout(), Some(Duration::from_secs(7)));
+    assert_eq!(timeout_config.connect_timeout(), Some(Duration::from_secs(2)));
+}
+
+#[test]
+fn test_checksum_algorithm_option() {
+    let options: S3Options = serde_json::from_str("{}").unwrap();
+    assert_eq!(options.checksum_algorithm, S3Checksum::Crc32C);
+    assert_eq!(ChecksumAlgorithm::from(&options.checksum_algorithm), ChecksumAlgorithm::Crc32C);
+
+    let options: S3Options = serde_json::from_str(r#"{"checksum_algorithm": "Sha256"}"#).unwrap();
+    assert_eq!(ChecksumAlgorithm::from(&options.checksum_algorithm), ChecksumAlgorithm::Sha256);
+}
+
+// A mock S3 endpoint that records the requests it gets and how many were in flight at once.
+#[derive(Debug, Default)]
+struct MockS3 {
+    requests: std::sync::Mutex<Vec<String>>,
+    in_flight: AtomicUsize,
+    max_in_flight: AtomicUsize,
+    /// Requests for keys containing one of these get the matching status and error code.
+    failures: Vec<(&'static str, u16, &'static str)>,
+}
+
+#[derive(Debug, Clone)]
+struct MockConnector(Arc<MockS3>);
+
+impl HttpConnector for MockConnector {
+    fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+        let s3 = self.0.clone();
+        let uri = request.uri().to_string();
+        let method = request.method().to_string();
+        HttpConnectorFuture::new(async move {
+            let in_flight = s3.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            s3.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            s3.in_flight.fetch_sub(1, Ordering::SeqCst);
+            s3.requests.lock().unwrap().push(format!("{} {}", method, uri));
+            Ok(s3.respond(&method, &uri))
+        })
+    }
+}
+
+impl MockS3 {
+    fn respond(&self, method: &str, uri: &str) -> HttpResponse {
+        if let Some((_, status, code)) = self.failures.iter().find(|(key, _, _)| uri.contains(key)) {
+            let body = format!("<Error><Code>{}</Code><Message>mock failure</Message></Error>", code);
+            return HttpResponse::new(StatusCode::try_from(*status).unwrap(), SdkBody::from(body));
+        }
+        let body = if method == "POST" && uri.contains("uploads") {
+            "<InitiateMultipartUploadResult><Bucket>bucket</Bucket><Key>key</Key><UploadId>upload-1</UploadId></InitiateMultipartUploadResult>"
+        } else if method == "POST" {
+            "<CompleteMultipartUploadResult><Bucket>bucket</Bucket><Key>key</Key><ETag>\"etag\"</ETag></CompleteMultipartUploadResult>"
+        } else {
+            ""
+        };
+        let status = if method == "DELETE" { 204 } else { 200 };
+        let mut response = HttpResponse::new(StatusCode::try_from(status).unwrap(), SdkBody::from(body));
+        response.headers_mut().insert("ETag", "\"etag\"");
+        response
+    }
+
+    fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn mock_storage(s3: Arc<MockS3>, options: S3Options) -> S3FileStorage {
+    let connector = MockConnector(s3);
+    let config = aws_sdk_s3::Config::builder()
+        .behavior_version(BehaviorVersion::latest())
+        .region(Region::new("us-east-1"))
+        .credentials_provider(Credentials::new("access", "secret", None, None, "test"))
+        .force_path_style(true)
+        .http_client(http_client_fn(move |_, _| SharedHttpConnector::new(connector.clone())))
+        .build();
+    let options = S3Options {
+        max_attempts: 1,
+        ..options
+    };
+    S3FileStorage::new(Client::from_conf(config), "bucket".to_string(), LayoutOptions::default(), options)
+}
+
+fn message_with_attachments(sizes: &[usize]) -> String {
+    let mut raw = String::from("From: <sender@example.com>\r\nSubject: Attachments\r\nMIME-Version: 1.0\r\n");
+    raw.push_str("Content-Type: multipart/mixed; boundary=\"b\"\r\n\r\n--b\r\nContent-Type: text/plain\r\n\r\nBody\r\n");
+    for (i, size) in sizes.iter().enumerate() {
+        raw.push_str(&format!(
+            "--b\r\nContent-Type: application/octet-stream\r\nContent-Disposition: attachment; filename=\"file{}.bin\"\r\n\r\n{}\r\n",
+            i,
+            "a".repeat(*size)
+        ));
+    }
+    raw.push_str("--b--\r\n");
+    raw
+}
+
+fn metadata() -> Metadata {
+    Metadata {
+        from: "sender@example.com".to_string(),
+        recipients: vec!["user@example.com".to_string()],
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_uploads_are_limited_to_max_concurrent_uploads() {
+    let s3 = Arc::new(MockS3::default());
+    let storage = mock_storage(
+        s3.clone(),
+        S3Options {
+            uploads: UploadOptions {
+                max_concurrent_uploads: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    );
+    let raw = message_with_attachments(&[10; 6]);
+    let message = MessageParser::default().parse(&raw).unwrap();
+
+    storage.save(&metadata(), &message).await.unwrap();
+
+    // Metadata, the two bodies, six attachments and the manifest.
+    assert_eq!(s3.requests().len(), 10);
+    assert_eq!(s3.max_in_flight.load(Ordering::SeqCst), 2);
+    assert!(s3.requests().last().unwrap().contains("/_COMPLETE"));
+}
+
+#[tokio::test]
+async fn test_every_object_is_attempted_and_the_permanent_failure_is_reported() {
+    let s3 = Arc::new(MockS3 {
+        failures: vec![("file1.bin", 503, "SlowDown"), ("file3.bin", 403, "AccessDenied")],
+        ..Default::default()
+    });
+    let storage = mock_storage(s3.clone(), S3Options::default());
+    let raw = message_with_attachments(&[10; 5]);
+    let message = MessageParser::default().parse(&raw).unwrap();
+
+    let err = storage.save(&metadata(), &message).await.unwrap_err();
+
+    assert!(matches!(err, StorageError::Permanent(_)));
+    let requests = s3.requests();
+    for i in 0..5 {
+        assert!(requests.iter().any(|request| request.contains(&format!("file{}.bin", i))));
+    }
+    // The message is incomplete, so the manifest must not be written.
+    assert!(!requests.iter().any(|request| request.contains("_COMPLETE")));
+}
+
+#[tokio::test]
+async fn test_transient_object_failure_is_reported_as_transient() {
+    let s3 = Arc::new(MockS3 {
+        failures: vec![("file0.bin", 503, "SlowDown")],
+        ..Default::default()
+    });
+    let storage = mock_storage(s3.clone(), S3Options::default());
+    let raw = message_with_attachments(&[10; 2]);
+    let message = MessageParser::default().parse(&raw).unwrap();
+
+    let err = storage.save(&metadata(), &message).await.unwrap_err();
+
+    assert!(err.is_transient());
+}
+
+#[tokio::test]
+async fn test_upload_parts_splits_the_body() {
+    let s3 = Arc::new(MockS3::default());
+    let storage = mock_storage(
+        s3.clone(),
+        S3Options {
+            uploads: UploadOptions {
+                // Below the minimum S3 accepts, so parts are 5 MiB.
+                multipart_part_size_bytes: 1024,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    );
+    let body = vec![b'a'; 2 * MIN_PART_SIZE_BYTES as usize + 1];
+
+    let parts = storage.upload_parts("key", "upload-1", &body, &Semaphore::new(8)).await.unwrap();
+
+    assert_eq!(parts.len(), 3);
+    assert_eq!(parts[2].part_number(), Some(3));
+    assert!(s3.requests().iter().all(|request| request.starts_with("PUT") && request.contains("uploadId=upload-1")));
+}
+
+#[tokio::test]
+async fn test_parts_are_uploaded_concurrently() {
+    let s3 = Arc::new(MockS3::default());
+    let storage = mock_storage(
+        s3.clone(),
+        S3Options {
+            uploads: UploadOptions {
+                multipart_part_size_bytes: MIN_PART_SIZE_BYTES,
+                max_concurrent_uploads: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    );
+    let body = vec![b'a'; 3 * MIN_PART_SIZE_BYTES as usize];
+
+    let parts = storage.upload_parts("key", "upload-1", &body, &Semaphore::new(2)).await.unwrap();
+
+    assert_eq!(parts.iter().map(|part| part.part_number().unwrap()).collect::<Vec<_>>(), vec![1, 2, 3]);
+    assert_eq!(s3.max_in_flight.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn test_parts_and_objects_share_the_concurrent_uploads() {
+    let s3 = Arc::new(MockS3::default());
+    let storage = mock_storage(
+        s3.clone(),
+        S3Options {
+            uploads: UploadOptions {
+                multipart_threshold_bytes: 1024,
+                max_concurrent_uploads: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    );
+    let raw = message_with_attachments(&[2 * MIN_PART_SIZE_BYTES as usize; 2]);
+    let message = MessageParser::default().parse(&raw).unwrap();
+
+    storage.save(&metadata(), &message).await.unwrap();
+
+    // Both attachments are uploaded in two parts, with never more than two requests at a time.
+    assert_eq!(s3.requests().iter().filter(|request| request.contains("partNumber")).count(), 4);
+    assert_eq!(s3.max_in_flight.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn test_failed_part_aborts_the_multipart_upload() {
+    let s3 = Arc::new(MockS3 {
+        failures: vec![("partNumber=2", 403, "AccessDenied")],
+        ..Default::default()
+    });
+    let storage = mock_storage(
+        s3.clone(),
+        S3Options {
+            uploads: UploadOptions {
+                multipart_threshold_bytes: 1024,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    );
+    let raw = message_with_attachments(&[2 * MIN_PART_SIZE_BYTES as usize]);
+    let message = MessageParser::default().parse(&raw).unwrap();
+
+    let err = storage.save(&metadata(), &message).await.unwrap_err();
+
+    assert!(matches!(err, StorageError::Permanent(_)));
+    let requests = s3.requests();
+    assert!(requests.iter().any(|request| request.starts_with("DELETE") && request.contains("file0.bin")));
+    assert!(!requests.iter().any(|request| request.starts_with("POST") && request.contains("file0.bin?uploadId")));
+}
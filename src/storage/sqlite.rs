@@ -0,0 +1,181 @@
+use crate::metrics::METRICS_INSTANCE;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use mail_parser::Message;
+use opentelemetry::KeyValue;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions};
+use tracing::{error, info};
+
+use crate::smtp::models::Metadata;
+use crate::storage::message_rows::MessageRows;
+use crate::storage::{Storage, StorageError};
+
+/// Created when the database is opened, statements are idempotent so existing databases are kept.
+const SCHEMA: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS messages (
+        id TEXT PRIMARY KEY,
+        received_at TEXT NOT NULL,
+        client TEXT NOT NULL,
+        client_address TEXT NOT NULL,
+        authenticated_user TEXT,
+        mail_from TEXT NOT NULL,
+        subject TEXT NOT NULL,
+        date TEXT,
+        message_id TEXT,
+        forwarded TEXT,
+        raw_message BLOB NOT NULL
+    )",
+    "CREATE INDEX IF NOT EXISTS messages_received_at ON messages (received_at)",
+    "CREATE INDEX IF NOT EXISTS messages_message_id ON messages (message_id)",
+    // kind is 'envelope' for RCPT TO addresses, or the 'to', 'cc' and 'bcc' headers.
+    "CREATE TABLE IF NOT EXISTS recipients (
+        message_id TEXT NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+        kind TEXT NOT NULL,
+        address TEXT NOT NULL
+    )",
+    "CREATE INDEX IF NOT EXISTS recipients_message_id ON recipients (message_id)",
+    "CREATE INDEX IF NOT EXISTS recipients_address ON recipients (address)",
+    "CREATE TABLE IF NOT EXISTS bodies (
+        message_id TEXT NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+        file_name TEXT NOT NULL,
+        content_type TEXT NOT NULL,
+        synthesized INTEGER NOT NULL,
+        content TEXT NOT NULL,
+        PRIMARY KEY (message_id, file_name)
+    )",
+    "CREATE TABLE IF NOT EXISTS attachments (
+        message_id TEXT NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+        name TEXT NOT NULL,
+        content_type TEXT NOT NULL,
+        content_id TEXT,
+        size INTEGER NOT NULL,
+        content BLOB NOT NULL,
+        PRIMARY KEY (message_id, name)
+    )",
+];
+
+/// Stores messages in a single SQLite database file, one row per message in `messages` with its
+/// recipients, bodies and attachments in tables of their own.
+pub struct SqliteStorage {
+    pool: SqlitePool,
+}
+
+impl SqliteStorage {
+    /// Opens the database, creating the file and the schema when missing.
+    pub async fn connect(path: &Path) -> Result<Self, sqlx::Error> {
+        let options = SqliteConnectOptions::from_str("sqlite://")?
+            .filename(path)
+            .create_if_missing(true)
+            // Readers don't block the writer, so the database can be queried while messages come in.
+            .journal_mode(SqliteJournalMode::Wal)
+            .foreign_keys(true)
+            .busy_timeout(Duration::from_secs(5));
+        let pool = SqlitePoolOptions::new().connect_with(options).await?;
+        for statement in SCHEMA {
+            sqlx::query(statement).execute(&pool).await?;
+        }
+        info!("Opened SQLite database {:?}", path);
+        Ok(Self { pool })
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn save(&self, metadata: &Metadata, message: &Message<'_>) -> Result<(), StorageError> {
+        let start_time = Instant::now();
+        if let Err(e) = self.insert_message(metadata, message).await {
+            error!("Failed to insert message, error is {:?}", e);
+            METRICS_INSTANCE.storage_failures.add(1, &[KeyValue::new("provider", "Sqlite")]);
+            return Err(classify_error(e));
+        }
+        METRICS_INSTANCE.data_storage_timing.record(start_time.elapsed().as_secs_f64(), &[KeyValue::new("provider", "Sqlite")]);
+        Ok(())
+    }
+}
+
+impl SqliteStorage {
+    async fn insert_message(&self, metadata: &Metadata, message: &Message<'_>) -> Result<(), sqlx::Error> {
+        let rows = MessageRows::new(metadata, message);
+        let mut transaction = self.pool.begin().await?;
+
+        sqlx::query(
+            "INSERT INTO messages (id, received_at, client, client_address, authenticated_user, mail_from, subject, date, message_id, forwarded, raw_message)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&rows.id)
+        .bind(rows.received_at.to_rfc3339())
+        .bind(&metadata.client)
+        .bind(&metadata.client_address)
+        .bind(&metadata.authenticated_user)
+        .bind(&metadata.from)
+        .bind(&metadata.subject)
+        .bind(&metadata.date)
+        .bind(&metadata.message_id)
+        .bind(&rows.forwarded)
+        .bind(rows.raw_message)
+        .execute(&mut *transaction)
+        .await?;
+
+        for (kind, address) in &rows.recipients {
+            sqlx::query("INSERT INTO recipients (message_id, kind, address) VALUES (?, ?, ?)")
+                .bind(&rows.id)
+                .bind(kind)
+                .bind(address)
+                .execute(&mut *transaction)
+                .await?;
+        }
+
+        for body in &rows.bodies {
+            sqlx::query("INSERT INTO bodies (message_id, file_name, content_type, synthesized, content) VALUES (?, ?, ?, ?, ?)")
+                .bind(&rows.id)
+                .bind(&body.file_name)
+                .bind(body.content_type)
+                .bind(body.synthesized)
+                .bind(body.content.as_ref())
+                .execute(&mut *transaction)
+                .await?;
+        }
+
+        for attachment in &rows.attachments {
+            sqlx::query("INSERT INTO attachments (message_id, name, content_type, content_id, size, content) VALUES (?, ?, ?, ?, ?, ?)")
+                .bind(&rows.id)
+                .bind(&attachment.name)
+                .bind(&attachment.content_type)
+                .bind(attachment.content_id)
+                .bind(attachment.content.len() as i64)
+                .bind(attachment.content)
+                .execute(&mut *transaction)
+                .await?;
+        }
+
+        transaction.commit().await?;
+        METRICS_INSTANCE
+            .attachments_stored
+            .add(rows.attachments.len() as u64, &[KeyValue::new("provider", "Sqlite")]);
+        info!("Message {} stored", rows.id);
+        Ok(())
+    }
+}
+
+/// SQLite result codes that may go away: busy, locked and a full disk.
+const TRANSIENT_RESULT_CODES: &[i32] = &[5, 6, 13];
+
+fn classify_error(err: sqlx::Error) -> StorageError {
+    let transient = match &err {
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut => true,
+        // Extended result codes keep the primary one in their lowest byte.
+        sqlx::Error::Database(e) => e
+            .code()
+            .and_then(|code| code.parse::<i32>().ok())
+            .is_some_and(|code| TRANSIENT_RESULT_CODES.contains(&(code & 0xff))),
+        _ => false,
+    };
+    if transient {
+        StorageError::transient(err)
+    } else {
+        StorageError::permanent(err)
+    }
+}
@@ -0,0 +1,279 @@
+use crate::metrics::METRICS_INSTANCE;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use mail_parser::Message;
+use opentelemetry::KeyValue;
+use rand::Rng;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use sha2::Sha256;
+use tracing::{error, info, warn};
+use ulid::Ulid;
+
+use crate::smtp::models::Metadata;
+use crate::storage::attachment::{collect_attachments, StoredAttachment};
+use crate::storage::body::{body_content, describe_bodies, message_document};
+use crate::storage::{Storage, StorageError};
+
+/// Sent with every attempt, so the receiver can tell retries of the same message apart from new ones.
+pub const DELIVERY_ID_HEADER: &str = "X-Smtp2s-Delivery-Id";
+
+/// Settings specific to the Webhook strategy.
+#[derive(Deserialize, Debug, Clone)]
+pub struct WebhookOptions {
+    #[serde(default)]
+    pub format: WebhookFormat,
+    /// Added to every request, e.g. an `Authorization` header.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Signs each request body with HMAC-SHA256, sent as `sha256=<hex>` in `signature_header`.
+    pub signing_secret: Option<String>,
+    #[serde(default = "default_signature_header")]
+    pub signature_header: String,
+    /// Time limit for each attempt, including reading the response.
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+    /// Attempts per message, including the first one.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled on each following attempt and randomized with jitter.
+    #[serde(default = "default_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    /// Upper bound for the delay between attempts.
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+pub enum WebhookFormat {
+    /// A JSON document holding the metadata, the bodies and the base64 encoded attachments.
+    #[default]
+    Json,
+    /// A multipart/form-data request with a `metadata` field and a file per body and attachment.
+    Multipart,
+}
+
+fn default_signature_header() -> String {
+    "X-Smtp2s-Signature".to_string()
+}
+
+fn default_timeout_seconds() -> u64 {
+    30
+}
+
+fn default_max_attempts() -> u32 {
+    3
+}
+
+fn default_initial_backoff_ms() -> u64 {
+    200
+}
+
+fn default_max_backoff_ms() -> u64 {
+    20_000
+}
+
+impl Default for WebhookOptions {
+    fn default() -> Self {
+        Self {
+            format: WebhookFormat::default(),
+            headers: HashMap::new(),
+            signing_secret: None,
+            signature_header: default_signature_header(),
+            timeout_seconds: default_timeout_seconds(),
+            max_attempts: default_max_attempts(),
+            initial_backoff_ms: default_initial_backoff_ms(),
+            max_backoff_ms: default_max_backoff_ms(),
+        }
+    }
+}
+
+/// Pushes every message to an HTTP endpoint instead of storing it.
+pub struct WebhookStorage {
+    client: Client,
+    url: String,
+    headers: HeaderMap,
+    signature_header: HeaderName,
+    options: WebhookOptions,
+}
+
+impl WebhookStorage {
+    /// Fails on header names or values HTTP doesn't allow, rather than on every message.
+    pub fn new(url: String, options: WebhookOptions) -> Result<Self, String> {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(options.timeout_seconds))
+            .build()
+            .map_err(|e| format!("Unable to build the webhook client: {}", e))?;
+        let mut headers = HeaderMap::new();
+        for (name, value) in &options.headers {
+            let name = header_name(name)?;
+            let value = HeaderValue::from_str(value).map_err(|_| format!("Invalid value for webhook header {}", name))?;
+            headers.insert(name, value);
+        }
+        let signature_header = header_name(&options.signature_header)?;
+        Ok(Self {
+            client,
+            url,
+            headers,
+            signature_header,
+            options,
+        })
+    }
+}
+
+fn header_name(name: &str) -> Result<HeaderName, String> {
+    HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("Invalid webhook header name \"{}\"", name))
+}
+
+#[async_trait]
+impl Storage for WebhookStorage {
+    async fn save(&self, metadata: &Metadata, message: &Message<'_>) -> Result<(), StorageError> {
+        let start_time = Instant::now();
+        if let Err(e) = self.deliver(metadata, message).await {
+            METRICS_INSTANCE.storage_failures.add(1, &[KeyValue::new("provider", "Webhook")]);
+            return Err(e);
+        }
+        METRICS_INSTANCE.data_storage_timing.record(start_time.elapsed().as_secs_f64(), &[KeyValue::new("provider", "Webhook")]);
+        Ok(())
+    }
+}
+
+impl WebhookStorage {
+    async fn deliver(&self, metadata: &Metadata, message: &Message<'_>) -> Result<(), StorageError> {
+        let delivery_id = Ulid::new();
+        let attachments = collect_attachments(message);
+        let (content_type, body) = match self.options.format {
+            WebhookFormat::Json => ("application/json".to_string(), json_document(metadata, message, &attachments)),
+            WebhookFormat::Multipart => {
+                let boundary = format!("smtp2s-{}", delivery_id);
+                (
+                    format!("multipart/form-data; boundary={}", boundary),
+                    multipart_document(&boundary, metadata, message, &attachments),
+                )
+            }
+        };
+        let signature = self.options.signing_secret.as_ref().map(|secret| sign(secret, &body));
+        let body = Bytes::from(body);
+
+        let mut backoff = Duration::from_millis(self.options.initial_backoff_ms);
+        let max_attempts = self.options.max_attempts.max(1);
+        let mut attempt = 1;
+        loop {
+            let mut request = self
+                .client
+                .post(&self.url)
+                .headers(self.headers.clone())
+                .header(CONTENT_TYPE, &content_type)
+                .header(DELIVERY_ID_HEADER, delivery_id.to_string());
+            if let Some(signature) = &signature {
+                request = request.header(&self.signature_header, signature);
+            }
+
+            let result = match request.body(body.clone()).send().await {
+                Ok(response) => classify_response(response.status()),
+                Err(e) => Err(classify_request_error(e)),
+            };
+            match result {
+                Ok(()) => {
+                    info!("Message {} delivered to {}", delivery_id, self.url);
+                    return Ok(());
+                }
+                Err(e) if e.is_transient() && attempt < max_attempts => {
+                    let delay = with_jitter(backoff);
+                    warn!("Attempt {} to deliver message {} failed, retrying in {:?}: {}", attempt, delivery_id, delay, e);
+                    tokio::time::sleep(delay).await;
+                    backoff = (backoff * 2).min(Duration::from_millis(self.options.max_backoff_ms));
+                    attempt += 1;
+                }
+                Err(e) => {
+                    error!("Failed to deliver message {} to {}, error is {}", delivery_id, self.url, e);
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+/// Waits between half and all of the backoff, so senders failing together don't retry in lockstep.
+fn with_jitter(backoff: Duration) -> Duration {
+    backoff.mul_f64(rand::rng().random_range(0.5..=1.0))
+}
+
+/// Throttling, timeouts and server errors may go away, any other refusal is final.
+fn classify_response(status: StatusCode) -> Result<(), StorageError> {
+    if status.is_success() {
+        return Ok(());
+    }
+    let error = format!("webhook responded with {}", status);
+    if status.is_server_error() || status == StatusCode::REQUEST_TIMEOUT || status == StatusCode::TOO_MANY_REQUESTS {
+        Err(StorageError::transient(error))
+    } else {
+        Err(StorageError::permanent(error))
+    }
+}
+
+fn classify_request_error(err: reqwest::Error) -> StorageError {
+    if err.is_builder() {
+        StorageError::permanent(err)
+    } else {
+        StorageError::transient(err)
+    }
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+fn json_document(metadata: &Metadata, message: &Message<'_>, attachments: &[StoredAttachment<'_>]) -> Vec<u8> {
+    serde_json::to_vec(&message_document(metadata, message, attachments)).unwrap()
+}
+
+fn multipart_document(
+    boundary: &str,
+    metadata: &Metadata,
+    message: &Message<'_>,
+    attachments: &[StoredAttachment<'_>],
+) -> Vec<u8> {
+    let mut document = vec![];
+    let metadata = serde_json::to_vec(metadata).unwrap();
+    push_form_part(&mut document, boundary, "metadata", None, "application/json", &metadata);
+    for body in describe_bodies(message) {
+        let content = body_content(message, &body, &HashMap::new());
+        let content_type = format!("{}; charset=utf-8", body.content_type);
+        push_form_part(&mut document, boundary, "body", Some(&body.file_name), &content_type, content.as_bytes());
+    }
+    for attachment in attachments {
+        let contents = attachment.part.contents();
+        push_form_part(&mut document, boundary, "attachment", Some(&attachment.name), &attachment.mime_type(), contents);
+    }
+    document.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    document
+}
+
+fn push_form_part(document: &mut Vec<u8>, boundary: &str, name: &str, file_name: Option<&str>, content_type: &str, contents: &[u8]) {
+    let mut disposition = format!("form-data; name=\"{}\"", name);
+    if let Some(file_name) = file_name {
+        // Quotes and line breaks would end the header early, RFC 7578 leaves other characters as is.
+        let file_name: String = file_name
+            .chars()
+            .map(|c| if matches!(c, '"' | '\\' | '\r' | '\n') { '_' } else { c })
+            .collect();
+        disposition.push_str(&format!("; filename=\"{}\"", file_name));
+    }
+    document.extend_from_slice(
+        format!(
+            "--{}\r\nContent-Disposition: {}\r\nContent-Type: {}\r\n\r\n",
+            boundary, disposition, content_type
+        )
+        .as_bytes(),
+    );
+    document.extend_from_slice(contents);
+    document.extend_from_slice(b"\r\n");
+}
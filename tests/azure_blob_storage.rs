@@ -0,0 +1,96 @@
+use std::sync::Arc;
+
+use futures::TryStreamExt;
+use lettre::Message;
+use object_store::{azure::MicrosoftAzureBuilder, path::Path, Attribute, ObjectStore};
+use smtp2s::storage::azure::build_azure_blob_storage;
+use smtp2s::storage::{LayoutOptions, UploadOptions};
+
+mod common;
+
+// Created by the azurite-init service of docker-compose.yml.
+const TEST_CONTAINER_NAME: &str = "smtp2s-data-storage";
+const AZURITE_CONNECTION_STRING: &str = "UseDevelopmentStorage=true";
+
+fn get_azure_client() -> impl ObjectStore {
+    MicrosoftAzureBuilder::new()
+        .with_use_emulator(true)
+        .with_container_name(TEST_CONTAINER_NAME)
+        .build()
+        .unwrap()
+}
+
+async fn cleanup_container(client: &impl ObjectStore) {
+    let objects: Vec<_> = client.list(None).try_collect().await.unwrap();
+    for object in objects {
+        client.delete(&object.location).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_email_delivery_to_azure_blob_storage() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+    let azure_client = get_azure_client();
+
+    // Ensure the container is clean before running the test
+    cleanup_container(&azure_client).await;
+
+    let email = Message::builder()
+        .from("test@example.com".parse().unwrap())
+        .to("user@example.net".parse().unwrap())
+        .subject("Test Email")
+        .body("Hello, world!".to_string())
+        .unwrap();
+
+    let storage = Arc::new(
+        build_azure_blob_storage(
+            TEST_CONTAINER_NAME.to_string(),
+            AZURITE_CONNECTION_STRING,
+            LayoutOptions::default(),
+            UploadOptions::default(),
+        )
+        .unwrap(),
+    );
+    common::send_message(storage, email).await.unwrap();
+
+    // Check the container for the results
+    let objects: Vec<_> = azure_client.list(None).try_collect().await.unwrap();
+    assert_eq!(objects.len(), 4, "Should be four blobs in the container");
+
+    let keys: Vec<String> = objects.iter().map(|o| o.location.to_string()).collect();
+    let ulid_prefix = keys[0].split('/').next().unwrap();
+
+    let metadata_key = format!("{}/metadata.json", ulid_prefix);
+    let content_key = format!("{}/body.html", ulid_prefix);
+    let text_content_key = format!("{}/body.txt", ulid_prefix);
+    let manifest_key = format!("{}/_COMPLETE", ulid_prefix);
+
+    assert!(keys.contains(&metadata_key));
+    assert!(keys.contains(&content_key));
+    assert!(keys.contains(&text_content_key));
+    assert!(keys.contains(&manifest_key));
+
+    let metadata_object = azure_client.get(&Path::from(metadata_key)).await.unwrap();
+    assert_eq!(
+        metadata_object.attributes.get(&Attribute::ContentType).map(|v| v.as_ref()),
+        Some("application/json")
+    );
+
+    let metadata_bytes = metadata_object.bytes().await.unwrap();
+    let metadata_json: serde_json::Value = serde_json::from_slice(&metadata_bytes).unwrap();
+
+    assert_eq!(metadata_json["from"], "test@example.com");
+    assert_eq!(metadata_json["to"][0], "user@example.net");
+    assert_eq!(metadata_json["subject"], "Test Email");
+
+    let content_bytes = azure_client
+        .get(&Path::from(content_key))
+        .await
+        .unwrap()
+        .bytes()
+        .await
+        .unwrap();
+    let content_str = String::from_utf8(content_bytes.to_vec()).unwrap();
+
+    assert!(content_str.contains("Hello, world!"));
+}
@@ -0,0 +1,144 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use lettre::{
+    message::{header::ContentType, Attachment, MultiPart, SinglePart},
+    Message,
+};
+use smtp2s::storage::bus::{BusContent, BusOptions, BusStorage, Envelope, Publisher, TopicTemplate};
+use smtp2s::storage::local::{LocalFileStorage, LocalOptions};
+use smtp2s::storage::{LayoutOptions, StorageError};
+use tempfile::tempdir;
+
+mod common;
+
+struct PublishedEnvelope {
+    topic: String,
+    payload: Vec<u8>,
+}
+
+/// Records every envelope with its topic, or refuses them all with a transient error.
+#[derive(Clone, Default)]
+struct RecordingPublisher {
+    published: Arc<Mutex<Vec<PublishedEnvelope>>>,
+    unavailable: bool,
+}
+
+#[async_trait]
+impl Publisher for RecordingPublisher {
+    fn provider(&self) -> &'static str {
+        "Recording"
+    }
+
+    async fn publish(&self, topic: &str, envelope: &Envelope) -> Result<(), StorageError> {
+        if self.unavailable {
+            return Err(StorageError::transient("broker unavailable"));
+        }
+        self.published.lock().unwrap().push(PublishedEnvelope {
+            topic: topic.to_string(),
+            payload: envelope.payload.clone(),
+        });
+        Ok(())
+    }
+}
+
+/// Sends a message through a server publishing with the given publisher, returning the SMTP outcome.
+async fn send_through_bus(
+    publisher: RecordingPublisher,
+    content: BusContent,
+    email: Message,
+) -> Result<(), lettre::transport::smtp::Error> {
+    let topic = TopicTemplate::try_from("mail.{recipient_domain}".to_string()).unwrap();
+    let storage = Arc::new(BusStorage::new(Box::new(publisher), topic, content, BusOptions::default()));
+    common::send_message(storage, email).await
+}
+
+fn email_with_attachment() -> Message {
+    Message::builder()
+        .from("test@example.com".parse().unwrap())
+        .to("user@example.net".parse().unwrap())
+        .subject("Test Email")
+        .multipart(
+            MultiPart::mixed()
+                .singlepart(SinglePart::plain("Hello, world!".to_string()))
+                .singlepart(
+                    Attachment::new("report.pdf".to_string())
+                        .body(b"%PDF-1.4".to_vec(), ContentType::parse("application/pdf").unwrap()),
+                ),
+        )
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_inline_envelope_is_published() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+    let publisher = RecordingPublisher::default();
+
+    send_through_bus(publisher.clone(), BusContent::Inline, email_with_attachment())
+        .await
+        .unwrap();
+
+    let published = publisher.published.lock().unwrap();
+    assert_eq!(published.len(), 1);
+    assert_eq!(published[0].topic, "mail.example_net");
+
+    let envelope: serde_json::Value = serde_json::from_slice(&published[0].payload).unwrap();
+    assert!(envelope["id"].is_string());
+    assert!(envelope["received_at"].is_string());
+    assert_eq!(envelope["metadata"]["from"], "test@example.com");
+    assert!(envelope["bodies"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|body| body["file_name"] == "body.txt" && body["content"].as_str().unwrap().contains("Hello, world!")));
+    assert_eq!(envelope["attachments"][0]["name"], "report.pdf");
+    let contents = BASE64_STANDARD
+        .decode(envelope["attachments"][0]["content_base64"].as_str().unwrap())
+        .unwrap();
+    assert_eq!(contents, b"%PDF-1.4");
+}
+
+#[tokio::test]
+async fn test_stored_envelope_points_to_the_stored_message() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+    let storage_dir = tempdir().unwrap();
+    let publisher = RecordingPublisher::default();
+    let backend = Arc::new(LocalFileStorage {
+        base_path: storage_dir.path().to_path_buf(),
+        layout: LayoutOptions::default(),
+        options: LocalOptions::default(),
+    });
+
+    send_through_bus(publisher.clone(), BusContent::Stored(backend), email_with_attachment())
+        .await
+        .unwrap();
+
+    let published = publisher.published.lock().unwrap();
+    assert_eq!(published.len(), 1);
+    let envelope: serde_json::Value = serde_json::from_slice(&published[0].payload).unwrap();
+    assert_eq!(envelope["metadata"]["subject"], "Test Email");
+    assert!(envelope.get("bodies").is_none());
+    let location = Path::new(envelope["location"].as_str().unwrap());
+    assert!(location.starts_with(storage_dir.path()));
+    assert!(location.join("metadata.json").exists());
+    assert!(location.join("attachments").join("report.pdf").exists());
+}
+
+#[tokio::test]
+async fn test_unacknowledged_envelope_asks_sender_to_retry() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+    let publisher = RecordingPublisher {
+        unavailable: true,
+        ..Default::default()
+    };
+
+    let error = send_through_bus(publisher, BusContent::Inline, email_with_attachment())
+        .await
+        .unwrap_err();
+
+    assert!(error.is_transient());
+    assert_eq!(error.status().map(|code| code.to_string()), Some("451".to_string()));
+}
@@ -0,0 +1,92 @@
+// Each test binary only uses some of these helpers.
+#![allow(dead_code)]
+
+use std::path::Path;
+use std::sync::Arc;
+
+use lettre::{
+    transport::smtp::{
+        authentication::{Credentials, Mechanism},
+        Error,
+    },
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use smtp2s::listener::Listener;
+use smtp2s::storage::local::{LocalFileStorage, LocalOptions};
+use smtp2s::storage::{LayoutOptions, Storage};
+use smtp2s::{run_server, ServerOptions};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// Runs a server accepting sessions from `listener` until `shutdown` is cancelled.
+pub fn spawn_server<L: Listener + 'static>(
+    listener: L,
+    storage: Arc<dyn Storage>,
+    options: ServerOptions,
+    shutdown: CancellationToken,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        run_server(listener, storage, options, shutdown).await.unwrap();
+    })
+}
+
+/// Stores messages as local files under `path`, with the default layout.
+pub fn local_storage(path: &Path) -> Arc<dyn Storage> {
+    Arc::new(LocalFileStorage {
+        base_path: path.to_path_buf(),
+        layout: LayoutOptions::default(),
+        options: LocalOptions::default(),
+    })
+}
+
+/// Reads a whole reply, including the continuation lines of a multi-line reply.
+pub async fn read_reply<R: AsyncBufRead + Unpin>(reader: &mut R) -> String {
+    let mut reply = String::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        reply.push_str(&line);
+        // Multi-line replies use '-' after the code, the last line uses a space.
+        if line.len() < 4 || line.as_bytes()[3] != b'-' {
+            return reply;
+        }
+    }
+}
+
+/// Sends the messages in a single session, authenticated as `test@example.com`, to a server storing
+/// them with `storage`. Returns the SMTP outcome of each message once the server has shut down.
+pub async fn send_messages(storage: Arc<dyn Storage>, emails: Vec<Message>) -> Vec<Result<(), Error>> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let options = ServerOptions {
+        allowed_addresses: vec!["test@example.com".to_string()],
+        ..Default::default()
+    };
+    let shutdown = CancellationToken::new();
+    let server_handle = spawn_server(listener, storage, options, shutdown.clone());
+
+    let client = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(addr.ip().to_string())
+        .port(addr.port())
+        .credentials(Credentials::new(
+            "test@example.com".to_string(),
+            "password".to_string(),
+        ))
+        .authentication(vec![Mechanism::Login])
+        .build();
+
+    let mut results = vec![];
+    for email in emails {
+        results.push(client.send(email).await.map(|_| ()));
+    }
+
+    shutdown.cancel();
+    server_handle.await.unwrap();
+    results
+}
+
+/// Same as `send_messages`, with a single message.
+pub async fn send_message(storage: Arc<dyn Storage>, email: Message) -> Result<(), Error> {
+    send_messages(storage, vec![email]).await.remove(0)
+}
@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use futures::TryStreamExt;
+use lettre::Message;
+use object_store::{gcp::GoogleCloudStorageBuilder, path::Path, Attribute, ClientOptions, ObjectStore};
+use smtp2s::storage::gcs::build_gcs_storage;
+use smtp2s::storage::{LayoutOptions, UploadOptions};
+
+mod common;
+
+// Created by the fake-gcs-server-init service of docker-compose.yml.
+const TEST_BUCKET_NAME: &str = "smtp2s-data-storage";
+const FAKE_GCS_SERVER_ENDPOINT: &str = "http://localhost:4443";
+
+fn get_gcs_client() -> impl ObjectStore {
+    let service_account = serde_json::json!({
+        "private_key": "",
+        "private_key_id": "",
+        "client_email": "",
+        "gcs_base_url": FAKE_GCS_SERVER_ENDPOINT,
+        "disable_oauth": true,
+    });
+    GoogleCloudStorageBuilder::new()
+        .with_bucket_name(TEST_BUCKET_NAME)
+        .with_service_account_key(service_account.to_string())
+        .with_client_options(ClientOptions::new().with_allow_http(true))
+        .build()
+        .unwrap()
+}
+
+async fn cleanup_bucket(client: &impl ObjectStore) {
+    let objects: Vec<_> = client.list(None).try_collect().await.unwrap();
+    for object in objects {
+        client.delete(&object.location).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_email_delivery_to_gcs_storage() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+    let gcs_client = get_gcs_client();
+
+    // Ensure the bucket is clean before running the test
+    cleanup_bucket(&gcs_client).await;
+
+    let email = Message::builder()
+        .from("test@example.com".parse().unwrap())
+        .to("user@example.net".parse().unwrap())
+        .subject("Test Email")
+        .body("Hello, world!".to_string())
+        .unwrap();
+
+    let storage = Arc::new(
+        build_gcs_storage(
+            TEST_BUCKET_NAME.to_string(),
+            None,
+            Some(FAKE_GCS_SERVER_ENDPOINT.to_string()),
+            LayoutOptions::default(),
+            UploadOptions::default(),
+        )
+        .unwrap(),
+    );
+    common::send_message(storage, email).await.unwrap();
+
+    // Check the bucket for the results
+    let objects: Vec<_> = gcs_client.list(None).try_collect().await.unwrap();
+    assert_eq!(objects.len(), 4, "Should be four objects in the bucket");
+
+    let keys: Vec<String> = objects.iter().map(|o| o.location.to_string()).collect();
+    let ulid_prefix = keys[0].split('/').next().unwrap();
+
+    let metadata_key = format!("{}/metadata.json", ulid_prefix);
+    let content_key = format!("{}/body.html", ulid_prefix);
+    let text_content_key = format!("{}/body.txt", ulid_prefix);
+    let manifest_key = format!("{}/_COMPLETE", ulid_prefix);
+
+    assert!(keys.contains(&metadata_key));
+    assert!(keys.contains(&content_key));
+    assert!(keys.contains(&text_content_key));
+    assert!(keys.contains(&manifest_key));
+
+    let metadata_object = gcs_client.get(&Path::from(metadata_key)).await.unwrap();
+    assert_eq!(
+        metadata_object.attributes.get(&Attribute::ContentType).map(|v| v.as_ref()),
+        Some("application/json")
+    );
+
+    let metadata_bytes = metadata_object.bytes().await.unwrap();
+    let metadata_json: serde_json::Value = serde_json::from_slice(&metadata_bytes).unwrap();
+
+    assert_eq!(metadata_json["from"], "test@example.com");
+    assert_eq!(metadata_json["to"][0], "user@example.net");
+    assert_eq!(metadata_json["subject"], "Test Email");
+
+    let content_bytes = gcs_client
+        .get(&Path::from(content_key))
+        .await
+        .unwrap()
+        .bytes()
+        .await
+        .unwrap();
+    let content_str = String::from_utf8(content_bytes.to_vec()).unwrap();
+
+    assert!(content_str.contains("Hello, world!"));
+}
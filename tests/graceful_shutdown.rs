@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+use common::{local_storage, read_reply, spawn_server};
+use smtp2s::ServerOptions;
+use tempfile::tempdir;
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+
+mod common;
+
+#[tokio::test]
+async fn test_idle_session_is_closed_on_shutdown() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+    let storage_dir = tempdir().unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let options = ServerOptions {
+        allowed_addresses: vec!["*".to_string()],
+        ..Default::default()
+    };
+    let shutdown = CancellationToken::new();
+    let server_handle = spawn_server(listener, local_storage(storage_dir.path()), options, shutdown.clone());
+
+    let mut client = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    assert!(read_reply(&mut client).await.starts_with("220"));
+    client.get_mut().write_all(b"EHLO test.client\r\n").await.unwrap();
+    assert!(read_reply(&mut client).await.starts_with("250"));
+
+    shutdown.cancel();
+
+    assert!(read_reply(&mut client).await.starts_with("421"));
+    tokio::time::timeout(Duration::from_secs(5), server_handle)
+        .await
+        .expect("Server should stop once idle sessions are closed")
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_in_flight_data_is_stored_before_shutdown() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+    let storage_dir = tempdir().unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let options = ServerOptions {
+        allowed_addresses: vec!["*".to_string()],
+        ..Default::default()
+    };
+    let shutdown = CancellationToken::new();
+    let server_handle = spawn_server(listener, local_storage(storage_dir.path()), options, shutdown.clone());
+
+    let mut client = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    assert!(read_reply(&mut client).await.starts_with("220"));
+    for (command, expected) in [
+        ("EHLO test.client\r\n", "250"),
+        ("AUTH LOGIN\r\n", "334"),
+        ("dGVzdEBleGFtcGxlLmNvbQ==\r\n", "334"),
+        ("cGFzc3dvcmQ=\r\n", "235"),
+        ("MAIL FROM:<test@example.com>\r\n", "250"),
+        ("RCPT TO:<user@example.net>\r\n", "250"),
+        ("DATA\r\n", "354"),
+    ] {
+        client.get_mut().write_all(command.as_bytes()).await.unwrap();
+        assert!(read_reply(&mut client).await.starts_with(expected));
+    }
+
+    client
+        .get_mut()
+        .write_all(b"From: <test@example.com>\r\nTo: <user@example.net>\r\nSubject: Draining\r\n\r\n")
+        .await
+        .unwrap();
+    shutdown.cancel();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    client.get_mut().write_all(b"Body\r\n.\r\n").await.unwrap();
+
+    assert!(read_reply(&mut client).await.starts_with("250"));
+    assert!(read_reply(&mut client).await.starts_with("421"));
+    server_handle.await.unwrap();
+
+    let entries: Vec<_> = std::fs::read_dir(storage_dir.path()).unwrap().collect();
+    assert_eq!(entries.len(), 1, "Message in flight during shutdown should be stored");
+}
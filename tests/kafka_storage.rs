@@ -0,0 +1,79 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use lettre::Message;
+use rskafka::client::partition::{OffsetAt, UnknownTopicHandling};
+use rskafka::client::ClientBuilder;
+use rskafka::BackoffConfig;
+use smtp2s::storage::bus::kafka::KafkaPublisher;
+use smtp2s::storage::bus::{BusContent, BusOptions, BusStorage, TopicTemplate};
+use ulid::Ulid;
+
+mod common;
+
+// Matches the kafka service of docker-compose.yml and the topic kafka-init creates.
+const TEST_KAFKA_BROKER: &str = "localhost:9092";
+const TEST_TOPIC: &str = "smtp2s-messages";
+
+#[tokio::test]
+async fn test_email_delivery_to_kafka() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+
+    let backoff_config = BackoffConfig {
+        deadline: Some(Duration::from_secs(10)),
+        ..Default::default()
+    };
+    let client = ClientBuilder::new(vec![TEST_KAFKA_BROKER.to_string()])
+        .backoff_config(backoff_config)
+        .build()
+        .await
+        .unwrap();
+    let topic = client
+        .list_topics()
+        .await
+        .unwrap()
+        .into_iter()
+        .find(|topic| topic.name == TEST_TOPIC)
+        .unwrap();
+    // Records of previous runs are kept, only the ones after the current end of each partition are read.
+    let mut partitions = vec![];
+    for partition in topic.partitions {
+        let partition_client = client
+            .partition_client(TEST_TOPIC, partition, UnknownTopicHandling::Retry)
+            .await
+            .unwrap();
+        let offset = partition_client.get_offset(OffsetAt::Latest).await.unwrap();
+        partitions.push((partition_client, offset));
+    }
+
+    // A unique subject tells this run's envelope apart.
+    let subject = format!("Test Email {}", Ulid::new());
+    let email = Message::builder()
+        .from("test@example.com".parse().unwrap())
+        .to("user@example.net".parse().unwrap())
+        .subject(subject.clone())
+        .body("Hello, world!".to_string())
+        .unwrap();
+
+    let publisher = KafkaPublisher::connect(vec![TEST_KAFKA_BROKER.to_string()], Duration::from_secs(10))
+        .await
+        .unwrap();
+    let topic = TopicTemplate::try_from(TEST_TOPIC.to_string()).unwrap();
+    let storage = Arc::new(BusStorage::new(Box::new(publisher), topic, BusContent::Inline, BusOptions::default()));
+    common::send_message(storage, email).await.unwrap();
+
+    let mut envelopes = vec![];
+    for (partition_client, offset) in partitions {
+        let (records, _) = partition_client.fetch_records(offset, 1..10_000_000, 1_000).await.unwrap();
+        for record in records {
+            let payload = record.record.value.unwrap();
+            let envelope: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+            if envelope["metadata"]["subject"] == subject.as_str() {
+                envelopes.push((record.record.key.unwrap(), envelope));
+            }
+        }
+    }
+    assert_eq!(envelopes.len(), 1);
+    let (key, envelope) = &envelopes[0];
+    assert_eq!(envelope["id"], String::from_utf8(key.clone()).unwrap().as_str());
+}
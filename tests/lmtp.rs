@@ -0,0 +1,69 @@
+use common::{local_storage, read_reply, spawn_server};
+use smtp2s::smtp::models::Protocol;
+use smtp2s::ServerOptions;
+use tempfile::tempdir;
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+
+mod common;
+
+#[tokio::test]
+async fn test_pipelined_lmtp_transaction() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+    let storage_dir = tempdir().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let options = ServerOptions {
+        protocol: Protocol::Lmtp,
+        ..Default::default()
+    };
+    let shutdown = CancellationToken::new();
+    let server_handle = spawn_server(listener, local_storage(storage_dir.path()), options, shutdown.clone());
+
+    let mut client = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    assert!(read_reply(&mut client).await.starts_with("220"));
+    client.get_mut().write_all(b"LHLO mta.example.com\r\n").await.unwrap();
+    let capabilities = read_reply(&mut client).await;
+    assert!(capabilities.contains("250-PIPELINING\r\n"));
+    assert!(capabilities.contains("250-ENHANCEDSTATUSCODES\r\n"));
+
+    // The whole envelope in a single write, as RFC 2920 allows.
+    client
+        .get_mut()
+        .write_all(b"MAIL FROM:<sender@example.com>\r\nRCPT TO:<first@example.com>\r\nRCPT TO:<second@example.com>\r\nDATA\r\n")
+        .await
+        .unwrap();
+    for expected in ["250 2.1.0", "250 2.1.5", "250 2.1.5", "354"] {
+        assert!(read_reply(&mut client).await.starts_with(expected));
+    }
+
+    client
+        .get_mut()
+        .write_all(b"From: <sender@example.com>\r\nSubject: Pipelined\r\n\r\nBody\r\n.\r\n")
+        .await
+        .unwrap();
+    assert!(read_reply(&mut client).await.starts_with("250 2.1.5 <first@example.com>"));
+    assert!(read_reply(&mut client).await.starts_with("250 2.1.5 <second@example.com>"));
+
+    // The session goes on with the next message.
+    client
+        .get_mut()
+        .write_all(b"MAIL FROM:<sender@example.com>\r\nRCPT TO:<third@example.com>\r\nDATA\r\n")
+        .await
+        .unwrap();
+    for expected in ["250 2.1.0", "250 2.1.5", "354"] {
+        assert!(read_reply(&mut client).await.starts_with(expected));
+    }
+    client
+        .get_mut()
+        .write_all(b"From: <sender@example.com>\r\nSubject: Next\r\n\r\nBody\r\n.\r\n")
+        .await
+        .unwrap();
+    assert!(read_reply(&mut client).await.starts_with("250 2.1.5 <third@example.com>"));
+
+    shutdown.cancel();
+    server_handle.await.unwrap();
+    assert_eq!(std::fs::read_dir(storage_dir.path()).unwrap().count(), 3);
+}
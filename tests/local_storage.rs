@@ -1,16 +1,25 @@
 use std::fs;
+use std::sync::Arc;
 
 use lettre::{
+    message::{header::ContentType, Attachment, MultiPart, SinglePart},
     transport::smtp::authentication::{Credentials, Mechanism},
     AsyncSmtpTransport,
     AsyncTransport,
     Message,
     Tokio1Executor,
 };
-use smtp2s::run_server;
-use smtp2s::storage::local::LocalFileStorage;
+use mail_parser::MessageParser;
+use smtp2s::smtp::models::Metadata;
+use smtp2s::{run_server, ServerOptions};
+use smtp2s::storage::local::{LocalFileStorage, LocalOptions};
+use smtp2s::storage::path_template::PathTemplate;
+use smtp2s::storage::{LayoutOptions, Storage};
 use tempfile::tempdir;
 use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+mod common;
 
 #[tokio::test]
 async fn test_email_delivery_to_local_storage() {
@@ -26,16 +35,22 @@ async fn test_email_delivery_to_local_storage() {
     let allowed_addresses = vec!["test@example.com".to_string()];
 
     let server_storage_path = storage_path.clone();
-    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+    let shutdown = CancellationToken::new();
+    let server_shutdown = shutdown.clone();
     let server_handle = tokio::spawn(async move {
-        let storage = Box::new(LocalFileStorage {
+        let storage = Arc::new(LocalFileStorage {
             base_path: server_storage_path,
+            layout: LayoutOptions::default(),
+            options: LocalOptions::default(),
         });
         run_server(
             listener,
             storage,
-            &allowed_addresses,
-            shutdown_rx,
+            ServerOptions {
+                allowed_addresses,
+                ..Default::default()
+            },
+            server_shutdown,
         )
         .await
         .unwrap();
@@ -49,7 +64,7 @@ async fn test_email_delivery_to_local_storage() {
         .body("Hello, world!".to_string())
         .unwrap();
 
-    let client = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&addr.ip().to_string())
+    let client = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(addr.ip().to_string())
         .port(addr.port())
         .credentials(Credentials::new("test@example.com".to_string(), "password".to_string()))
         .authentication(vec![Mechanism::Login])
@@ -58,7 +73,7 @@ async fn test_email_delivery_to_local_storage() {
     client.send(email).await.unwrap();
 
     // 4. Shutdown the server and wait for it to complete
-    let _ = shutdown_tx.send(());
+    shutdown.cancel();
     server_handle.await.unwrap();
 
     // 5. NOW, assert that the email was correctly saved to storage
@@ -71,25 +86,196 @@ async fn test_email_delivery_to_local_storage() {
     let mut message_files: Vec<_> = fs::read_dir(&message_dir_path).unwrap().map(|r| r.unwrap()).collect();
     message_files.sort_by_key(|f| f.path());
     
-    assert_eq!(message_files.len(), 3, "Should be three items: metadata, body, and attachments dir");
+    assert_eq!(message_files.len(), 4, "Should be four items: metadata, bodies, and attachments dir");
 
     let attachments_dir = &message_files[0];
     let body_file = &message_files[1];
-    let metadata_file = &message_files[2];
+    let text_body_file = &message_files[2];
+    let metadata_file = &message_files[3];
 
     assert_eq!(attachments_dir.file_name(), "attachments");
     assert!(attachments_dir.path().is_dir());
 
     assert_eq!(body_file.file_name(), "body.html");
+    assert_eq!(text_body_file.file_name(), "body.txt");
     assert_eq!(metadata_file.file_name(), "metadata.json");
 
     let metadata_content = fs::read_to_string(metadata_file.path()).unwrap();
     let metadata_json: serde_json::Value = serde_json::from_str(&metadata_content).unwrap();
 
+    assert!(metadata_json["client_address"].as_str().unwrap().starts_with("127.0.0.1:"));
     assert_eq!(metadata_json["from"], "test@example.com");
     assert_eq!(metadata_json["to"][0], "user@example.net");
     assert_eq!(metadata_json["subject"], "Test Email");
 
+    assert_eq!(metadata_json["bodies"][0]["file_name"], "body.html");
+    assert_eq!(metadata_json["bodies"][0]["synthesized"], true);
+    assert_eq!(metadata_json["bodies"][1]["file_name"], "body.txt");
+    assert_eq!(metadata_json["bodies"][1]["synthesized"], false);
+
     let body_file_content = fs::read_to_string(body_file.path()).unwrap();
     assert!(body_file_content.contains("Hello, world!"));
-}
\ No newline at end of file
+
+    let text_body_file_content = fs::read_to_string(text_body_file.path()).unwrap();
+    assert_eq!(text_body_file_content, "Hello, world!\r\n");
+}
+
+#[tokio::test]
+async fn test_raw_message_is_stored_when_enabled() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+
+    let storage_dir = tempdir().unwrap();
+    let storage_path = storage_dir.path().to_path_buf();
+
+    let storage = Arc::new(LocalFileStorage {
+        base_path: storage_path.clone(),
+        layout: LayoutOptions {
+            store_raw_message: true,
+            ..Default::default()
+        },
+        options: LocalOptions::default(),
+    });
+
+    // Lines starting with a dot are dot-stuffed by the client and must be restored.
+    let email = Message::builder()
+        .from("test@example.com".parse().unwrap())
+        .to("user@example.net".parse().unwrap())
+        .subject("Raw Email")
+        .body(".hidden line\r\nTo be continued...\r\n".to_string())
+        .unwrap();
+    common::send_message(storage, email.clone()).await.unwrap();
+
+    let entries: Vec<_> = fs::read_dir(&storage_path).unwrap().map(|r| r.unwrap()).collect();
+    assert_eq!(entries.len(), 1, "Should be one new directory in the storage path");
+
+    let raw_message = fs::read(entries[0].path().join("message.eml")).unwrap();
+    // lettre sends "<CRLF>.<CRLF>" right after the message, whose leading CRLF ends the last line.
+    let mut expected = email.formatted();
+    expected.extend_from_slice(b"\r\n");
+    assert_eq!(raw_message, expected, "message.eml should match the sent message byte-for-byte");
+}
+
+#[tokio::test]
+async fn test_inline_images_are_linked_to_stored_attachments() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+
+    let storage_dir = tempdir().unwrap();
+    let storage_path = storage_dir.path().to_path_buf();
+
+    let storage = Arc::new(LocalFileStorage {
+        base_path: storage_path.clone(),
+        layout: LayoutOptions::default(),
+        options: LocalOptions::default(),
+    });
+
+    let email = Message::builder()
+        .from("test@example.com".parse().unwrap())
+        .to("user@example.net".parse().unwrap())
+        .subject("Inline Image")
+        .multipart(
+            MultiPart::related()
+                .singlepart(SinglePart::html(
+                    r#"<p>Logo: <img src="cid:logo@example.com"></p>"#.to_string(),
+                ))
+                .singlepart(
+                    Attachment::new_inline("logo@example.com".to_string())
+                        .body(vec![0x89, b'P', b'N', b'G'], ContentType::parse("image/png").unwrap()),
+                ),
+        )
+        .unwrap();
+    common::send_message(storage, email).await.unwrap();
+
+    let entries: Vec<_> = fs::read_dir(&storage_path).unwrap().map(|r| r.unwrap()).collect();
+    assert_eq!(entries.len(), 1, "Should be one new directory in the storage path");
+    let message_dir_path = entries[0].path();
+
+    let attachments: Vec<_> = fs::read_dir(message_dir_path.join("attachments"))
+        .unwrap()
+        .map(|r| r.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert_eq!(attachments.len(), 1, "The inline image should be stored as an attachment");
+
+    let body = fs::read_to_string(message_dir_path.join("body.html")).unwrap();
+    assert!(!body.contains("cid:"), "cid: references should be rewritten, got {body}");
+    assert!(body.contains(&format!(r#"src="attachments/{}""#, attachments[0])));
+}
+
+#[tokio::test]
+async fn test_messages_are_stored_under_the_path_template() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+
+    let storage_dir = tempdir().unwrap();
+    let storage_path = storage_dir.path().to_path_buf();
+
+    let storage = Arc::new(templated_storage(storage_path.clone(), "mail/{recipient_domain}/{ulid}", false));
+
+    let email = Message::builder()
+        .from("test@example.com".parse().unwrap())
+        .to("user@example.net".parse().unwrap())
+        .subject("Templated Email")
+        .body("Hello, world!".to_string())
+        .unwrap();
+    common::send_message(storage, email).await.unwrap();
+
+    let domain_dir = storage_path.join("mail").join("example.net");
+    let entries: Vec<_> = fs::read_dir(&domain_dir).unwrap().map(|r| r.unwrap()).collect();
+    assert_eq!(entries.len(), 1, "Should be one message directory under the recipient domain");
+    assert!(entries[0].path().join("metadata.json").is_file());
+}
+
+fn templated_storage(base_path: std::path::PathBuf, template: &str, fsync: bool) -> LocalFileStorage {
+    LocalFileStorage {
+        base_path,
+        layout: LayoutOptions {
+            path_template: PathTemplate::try_from(template.to_string()).unwrap(),
+            ..Default::default()
+        },
+        options: LocalOptions { fsync },
+    }
+}
+
+fn staging_folders(path: &std::path::Path) -> Vec<String> {
+    fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.starts_with(".staging-"))
+        .collect()
+}
+
+#[tokio::test]
+async fn test_messages_are_moved_out_of_staging_once_written() {
+    let storage_dir = tempdir().unwrap();
+    let storage = templated_storage(storage_dir.path().to_path_buf(), "mail/{recipient_domain}/{ulid}", true);
+    let metadata = Metadata {
+        recipients: vec!["user@example.net".to_string()],
+        ..Default::default()
+    };
+    let message = MessageParser::default()
+        .parse("Subject: Staged\r\n\r\nHello, world!\r\n")
+        .unwrap();
+
+    let location = storage.save_located(&metadata, &message).await.unwrap().unwrap();
+
+    let message_folder = std::path::Path::new(&location);
+    assert!(message_folder.starts_with(storage_dir.path().join("mail").join("example.net")));
+    assert!(message_folder.join("metadata.json").is_file());
+    assert!(message_folder.join("body.txt").is_file());
+    assert!(staging_folders(storage_dir.path()).is_empty());
+}
+
+#[tokio::test]
+async fn test_staging_folder_is_removed_when_the_message_cannot_be_moved() {
+    let storage_dir = tempdir().unwrap();
+    // A file where the path template needs a folder makes the final move fail.
+    fs::write(storage_dir.path().join("mail"), b"not a folder").unwrap();
+    let storage = templated_storage(storage_dir.path().to_path_buf(), "mail/{ulid}", false);
+    let message = MessageParser::default()
+        .parse("Subject: Staged\r\n\r\nHello, world!\r\n")
+        .unwrap();
+
+    let result = storage.save(&Metadata::default(), &message).await;
+
+    assert!(result.is_err());
+    assert!(staging_folders(storage_dir.path()).is_empty());
+    assert_eq!(fs::read_dir(storage_dir.path()).unwrap().count(), 1);
+}
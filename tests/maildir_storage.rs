@@ -0,0 +1,78 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use lettre::Message;
+use smtp2s::storage::maildir::MaildirStorage;
+use tempfile::tempdir;
+
+mod common;
+
+async fn send_to_maildir(path: PathBuf, per_recipient: bool, email: Message) {
+    let storage = Arc::new(MaildirStorage::new(path, per_recipient));
+    common::send_message(storage, email).await.unwrap();
+}
+
+/// Contents of the messages in `new/`, checking nothing was left in `tmp/`.
+fn new_messages(maildir: &Path) -> Vec<String> {
+    assert_eq!(fs::read_dir(maildir.join("tmp")).unwrap().count(), 0);
+    assert!(maildir.join("cur").is_dir());
+    fs::read_dir(maildir.join("new"))
+        .unwrap()
+        .map(|entry| fs::read_to_string(entry.unwrap().path()).unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_email_delivery_to_maildir() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+    let maildir = tempdir().unwrap();
+
+    let email = Message::builder()
+        .from("test@example.com".parse().unwrap())
+        .to("user@example.net".parse().unwrap())
+        .subject("Test Email")
+        .body("Hello, world!".to_string())
+        .unwrap();
+    send_to_maildir(maildir.path().to_path_buf(), false, email).await;
+
+    let messages = new_messages(maildir.path());
+    assert_eq!(messages.len(), 1);
+    let message = &messages[0];
+    assert!(message.starts_with("Return-Path: <test@example.com>\n"));
+    assert!(message.contains("Subject: Test Email\n"));
+    assert!(message.contains("Hello, world!"));
+    assert!(!message.contains('\r'));
+}
+
+#[tokio::test]
+async fn test_email_delivery_to_a_maildir_per_recipient() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+    let base_path = tempdir().unwrap();
+
+    let email = Message::builder()
+        .from("test@example.com".parse().unwrap())
+        .to("User@Example.net".parse().unwrap())
+        .cc("copy@example.org".parse().unwrap())
+        .cc("a/b@example.org".parse().unwrap())
+        .cc("a_b@example.org".parse().unwrap())
+        .subject("Test Email")
+        .body("Hello, world!".to_string())
+        .unwrap();
+    send_to_maildir(base_path.path().to_path_buf(), true, email).await;
+
+    let mut maildirs: Vec<String> = fs::read_dir(base_path.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    maildirs.sort();
+    // Addresses only differing by a character a file name can't hold still get Maildirs of their own.
+    assert_eq!(maildirs, vec!["a%2Fb%40example.org", "a_b%40example.org", "copy%40example.org", "user%40example.net"]);
+
+    for (maildir, recipient) in maildirs.iter().zip(["a/b@example.org", "a_b@example.org", "copy@example.org", "user@example.net"]) {
+        let messages = new_messages(&base_path.path().join(maildir));
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].contains(&format!("Delivered-To: {}\n", recipient)));
+        assert!(messages[0].contains("Subject: Test Email\n"));
+    }
+}
@@ -0,0 +1,41 @@
+use std::fs;
+use std::sync::Arc;
+
+use lettre::Message;
+use smtp2s::storage::mbox::{MboxSplit, MboxStorage};
+use tempfile::tempdir;
+
+mod common;
+
+#[tokio::test]
+async fn test_email_delivery_to_mbox() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+
+    let storage_dir = tempdir().unwrap();
+    let mbox_path = storage_dir.path().join("archive.mbox");
+
+    let emails = ["First Email", "Second Email"]
+        .into_iter()
+        .map(|subject| {
+            Message::builder()
+                .from("test@example.com".parse().unwrap())
+                .to("user@example.net".parse().unwrap())
+                .subject(subject)
+                .body("Hello,\r\nFrom now on, this is archived.\r\n".to_string())
+                .unwrap()
+        })
+        .collect();
+    let storage = Arc::new(MboxStorage::new(mbox_path.clone(), MboxSplit::Single));
+    for result in common::send_messages(storage, emails).await {
+        result.unwrap();
+    }
+
+    let mbox = fs::read_to_string(&mbox_path).unwrap();
+    let separators: Vec<&str> = mbox.lines().filter(|line| line.starts_with("From ")).collect();
+    assert_eq!(separators.len(), 2);
+    assert!(separators.iter().all(|line| line.starts_with("From test@example.com ")));
+    assert!(mbox.find("Subject: First Email").unwrap() < mbox.find("Subject: Second Email").unwrap());
+    assert_eq!(mbox.matches("\n>From now on, this is archived.\n").count(), 2);
+    assert!(!mbox.contains('\r'));
+    assert!(mbox.ends_with("\n\n"));
+}
@@ -0,0 +1,41 @@
+use std::sync::Arc;
+
+use lettre::Message;
+use smtp2s::storage::bus::nats::NatsPublisher;
+use smtp2s::storage::bus::{BusContent, BusOptions, BusStorage, TopicTemplate};
+use ulid::Ulid;
+
+mod common;
+
+// Matches the nats service of docker-compose.yml and the stream nats-init creates.
+const TEST_NATS_URL: &str = "nats://localhost:4222";
+const TEST_STREAM: &str = "smtp2s-messages";
+
+#[tokio::test]
+async fn test_email_delivery_to_nats_jetstream() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+
+    // Messages of previous runs are kept, a unique subject tells this run's envelope apart.
+    let subject = format!("smtp2s.messages.test-{}", Ulid::new());
+
+    let email = Message::builder()
+        .from("test@example.com".parse().unwrap())
+        .to("user@example.net".parse().unwrap())
+        .subject("Test Email")
+        .body("Hello, world!".to_string())
+        .unwrap();
+
+    let topic = TopicTemplate::try_from(subject.clone()).unwrap();
+    let publisher = NatsPublisher::connect(TEST_NATS_URL).await.unwrap();
+    let storage = Arc::new(BusStorage::new(Box::new(publisher), topic, BusContent::Inline, BusOptions::default()));
+    common::send_message(storage, email).await.unwrap();
+
+    let client = async_nats::connect(TEST_NATS_URL).await.unwrap();
+    let stream = async_nats::jetstream::new(client).get_stream(TEST_STREAM).await.unwrap();
+    let message = stream.get_last_raw_message_by_subject(&subject).await.unwrap();
+    let envelope: serde_json::Value = serde_json::from_slice(&message.payload).unwrap();
+    let id = message.headers.get("Nats-Msg-Id").unwrap();
+    assert_eq!(envelope["id"], id.as_str());
+    assert_eq!(envelope["metadata"]["subject"], "Test Email");
+    stream.purge().filter(subject.as_str()).await.unwrap();
+}
@@ -0,0 +1,84 @@
+use std::fs;
+
+use common::{local_storage, read_reply, spawn_server};
+use smtp2s::ServerOptions;
+use tempfile::tempdir;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+
+mod common;
+
+#[tokio::test]
+async fn test_client_address_is_taken_from_proxy_header() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+    let storage_dir = tempdir().unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let options = ServerOptions {
+        allowed_addresses: vec!["*".to_string()],
+        proxy_protocol: true,
+        ..Default::default()
+    };
+    let shutdown = CancellationToken::new();
+    let server_handle = spawn_server(listener, local_storage(storage_dir.path()), options, shutdown.clone());
+
+    let mut client = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    client
+        .get_mut()
+        .write_all(b"PROXY TCP4 203.0.113.7 10.0.0.1 4000 25\r\n")
+        .await
+        .unwrap();
+    assert!(read_reply(&mut client).await.starts_with("220"));
+    for (command, expected) in [
+        ("EHLO test.client\r\n", "250"),
+        ("AUTH LOGIN\r\n", "334"),
+        ("dGVzdEBleGFtcGxlLmNvbQ==\r\n", "334"),
+        ("cGFzc3dvcmQ=\r\n", "235"),
+        ("MAIL FROM:<test@example.com>\r\n", "250"),
+        ("RCPT TO:<user@example.net>\r\n", "250"),
+        ("DATA\r\n", "354"),
+        ("Subject: Proxied\r\n\r\nBody\r\n.\r\n", "250"),
+        ("QUIT\r\n", "221"),
+    ] {
+        client.get_mut().write_all(command.as_bytes()).await.unwrap();
+        assert!(read_reply(&mut client).await.starts_with(expected));
+    }
+
+    shutdown.cancel();
+    server_handle.await.unwrap();
+
+    let message_dir = fs::read_dir(storage_dir.path()).unwrap().next().unwrap().unwrap();
+    let metadata_content = fs::read_to_string(message_dir.path().join("metadata.json")).unwrap();
+    let metadata_json: serde_json::Value = serde_json::from_str(&metadata_content).unwrap();
+    assert_eq!(metadata_json["client_address"], "203.0.113.7:4000");
+}
+
+#[tokio::test]
+async fn test_connection_without_proxy_header_is_dropped() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+    let storage_dir = tempdir().unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let options = ServerOptions {
+        allowed_addresses: vec!["*".to_string()],
+        proxy_protocol: true,
+        ..Default::default()
+    };
+    let shutdown = CancellationToken::new();
+    let server_handle = spawn_server(listener, local_storage(storage_dir.path()), options, shutdown.clone());
+
+    let mut client = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    client.get_mut().write_all(b"EHLO test.client\r\n").await.unwrap();
+    let mut reply = String::new();
+    // Unread client data may turn the close into a reset, either way no greeting is sent.
+    let closed = matches!(client.read_line(&mut reply).await, Ok(0) | Err(_));
+    assert!(closed, "Connection should be closed without a greeting");
+
+    shutdown.cancel();
+    server_handle.await.unwrap();
+}
@@ -0,0 +1,49 @@
+use std::sync::Arc;
+
+use lettre::Message;
+use redis::streams::StreamRangeReply;
+use redis::AsyncCommands;
+use smtp2s::storage::bus::redis_streams::RedisStreamsPublisher;
+use smtp2s::storage::bus::{BusContent, BusOptions, BusStorage, TopicTemplate};
+use ulid::Ulid;
+
+mod common;
+
+// Matches the redis service of docker-compose.yml.
+const TEST_REDIS_URL: &str = "redis://localhost:6379";
+
+#[tokio::test]
+async fn test_email_delivery_to_redis_streams() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+
+    // Entries of previous runs are kept, a unique stream tells this run's envelope apart.
+    let prefix = format!("smtp2s-test-{}", Ulid::new());
+    let stream = format!("{}.example_net", prefix);
+
+    let email = Message::builder()
+        .from("test@example.com".parse().unwrap())
+        .to("user@example.net".parse().unwrap())
+        .subject("Test Email")
+        .body("Hello, world!".to_string())
+        .unwrap();
+
+    let topic = TopicTemplate::try_from(format!("{}.{{recipient_domain}}", prefix)).unwrap();
+    let publisher = RedisStreamsPublisher::connect(TEST_REDIS_URL).await.unwrap();
+    let storage = Arc::new(BusStorage::new(Box::new(publisher), topic, BusContent::Inline, BusOptions::default()));
+    common::send_message(storage, email).await.unwrap();
+
+    let mut connection = redis::Client::open(TEST_REDIS_URL)
+        .unwrap()
+        .get_multiplexed_async_connection()
+        .await
+        .unwrap();
+    let reply: StreamRangeReply = connection.xrange_all(&stream).await.unwrap();
+    assert_eq!(reply.ids.len(), 1);
+    let entry = &reply.ids[0];
+    let id: String = entry.get("id").unwrap();
+    let payload: Vec<u8> = entry.get("envelope").unwrap();
+    let envelope: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+    assert_eq!(envelope["id"], id.as_str());
+    assert_eq!(envelope["metadata"]["subject"], "Test Email");
+    let _: () = connection.del(&stream).await.unwrap();
+}
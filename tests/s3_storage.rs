@@ -1,15 +1,22 @@
+use std::sync::Arc;
+
 use aws_config::meta::region::RegionProviderChain;
 use aws_config::BehaviorVersion;
 use aws_sdk_s3::{config::Credentials as S3Credentials, types::Delete, Client, Config};
 use lettre::{
+    message::{header::ContentType, Attachment, MultiPart, SinglePart},
     transport::smtp::authentication::{Credentials, Mechanism},
     AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
 };
-use smtp2s::run_server;
-use smtp2s::storage::s3::S3FileStorage;
+use smtp2s::{run_server, ServerOptions};
+use smtp2s::storage::s3::{S3FileStorage, S3Options};
+use smtp2s::storage::{LayoutOptions, UploadOptions};
 use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
 
 const TEST_BUCKET_NAME: &str = "smtp2s-data-storage";
+// A bucket of its own, so that tests running in parallel don't clean up each other's objects.
+const MULTIPART_TEST_BUCKET_NAME: &str = "smtp2s-multipart-storage";
 
 async fn get_s3_client() -> Client {
     let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
@@ -28,10 +35,10 @@ async fn get_s3_client() -> Client {
     Client::from_conf(config)
 }
 
-async fn cleanup_bucket(s3_client: &Client) {
+async fn cleanup_bucket(s3_client: &Client, bucket: &str) {
     let objects_output = s3_client
         .list_objects_v2()
-        .bucket(TEST_BUCKET_NAME)
+        .bucket(bucket)
         .send()
         .await
         .unwrap();
@@ -58,7 +65,7 @@ async fn cleanup_bucket(s3_client: &Client) {
 
     s3_client
         .delete_objects()
-        .bucket(TEST_BUCKET_NAME)
+        .bucket(bucket)
         .delete(delete_list)
         .send()
         .await
@@ -71,7 +78,7 @@ async fn test_email_delivery_to_s3_storage() {
     let s3_client = get_s3_client().await;
 
     // Ensure the bucket is clean before running the test
-    cleanup_bucket(&s3_client).await;
+    cleanup_bucket(&s3_client, TEST_BUCKET_NAME).await;
 
     let _ = s3_client
         .create_bucket()
@@ -83,13 +90,20 @@ async fn test_email_delivery_to_s3_storage() {
     let addr = listener.local_addr().unwrap();
     let allowed_addresses = vec!["test@example.com".to_string()];
 
-    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+    let shutdown = CancellationToken::new();
+    let server_shutdown = shutdown.clone();
     let server_handle = tokio::spawn(async move {
-        let storage = Box::new(S3FileStorage::new(
+        let storage = Arc::new(S3FileStorage::new(
             get_s3_client().await,
             TEST_BUCKET_NAME.to_string(),
+            LayoutOptions::default(),
+            S3Options::default(),
         ));
-        run_server(listener, storage, &allowed_addresses, shutdown_rx)
+        let options = ServerOptions {
+            allowed_addresses,
+            ..Default::default()
+        };
+        run_server(listener, storage, options, server_shutdown)
             .await
             .unwrap();
     });
@@ -101,7 +115,7 @@ async fn test_email_delivery_to_s3_storage() {
         .body("Hello, world!".to_string())
         .unwrap();
 
-    let client = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&addr.ip().to_string())
+    let client = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(addr.ip().to_string())
         .port(addr.port())
         .credentials(Credentials::new(
             "test@example.com".to_string(),
@@ -113,7 +127,7 @@ async fn test_email_delivery_to_s3_storage() {
     client.send(email).await.unwrap();
 
     // Shutdown the server BEFORE checking the results
-    let _ = shutdown_tx.send(());
+    shutdown.cancel();
     server_handle.await.unwrap();
 
     // NOW check S3 for the results
@@ -126,8 +140,8 @@ async fn test_email_delivery_to_s3_storage() {
 
     assert_eq!(
         objects.key_count(),
-        Some(2),
-        "Should be two objects in the bucket"
+        Some(4),
+        "Should be four objects in the bucket"
     );
 
     let keys: Vec<String> = objects
@@ -139,9 +153,13 @@ async fn test_email_delivery_to_s3_storage() {
 
     let metadata_key = format!("{}/metadata.json", ulid_prefix);
     let content_key = format!("{}/body.html", ulid_prefix);
+    let text_content_key = format!("{}/body.txt", ulid_prefix);
+    let manifest_key = format!("{}/_COMPLETE", ulid_prefix);
 
     assert!(keys.contains(&metadata_key));
     assert!(keys.contains(&content_key));
+    assert!(keys.contains(&text_content_key));
+    assert!(keys.contains(&manifest_key));
 
     let metadata_object = s3_client
         .get_object()
@@ -151,6 +169,11 @@ async fn test_email_delivery_to_s3_storage() {
         .await
         .unwrap();
 
+    assert_eq!(metadata_object.content_type(), Some("application/json"));
+    let user_metadata = metadata_object.metadata().unwrap();
+    assert_eq!(user_metadata["from"], "test@example.com");
+    assert_eq!(user_metadata["recipient-domain"], "example.net");
+
     let metadata_bytes = metadata_object.body.collect().await.unwrap().into_bytes();
     let metadata_json: serde_json::Value = serde_json::from_slice(&metadata_bytes).unwrap();
 
@@ -171,3 +194,102 @@ async fn test_email_delivery_to_s3_storage() {
 
     assert!(content_str.contains("Hello, world!"));
 }
+
+#[tokio::test]
+async fn test_large_attachment_is_uploaded_in_parts() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+    let s3_client = get_s3_client().await;
+
+    let _ = s3_client
+        .create_bucket()
+        .bucket(MULTIPART_TEST_BUCKET_NAME)
+        .send()
+        .await;
+    cleanup_bucket(&s3_client, MULTIPART_TEST_BUCKET_NAME).await;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let allowed_addresses = vec!["test@example.com".to_string()];
+
+    let shutdown = CancellationToken::new();
+    let server_shutdown = shutdown.clone();
+    let server_handle = tokio::spawn(async move {
+        let storage = Arc::new(S3FileStorage::new(
+            get_s3_client().await,
+            MULTIPART_TEST_BUCKET_NAME.to_string(),
+            LayoutOptions::default(),
+            S3Options {
+                uploads: UploadOptions {
+                    multipart_threshold_bytes: 5 * 1024 * 1024,
+                    multipart_part_size_bytes: 5 * 1024 * 1024,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        ));
+        let options = ServerOptions {
+            allowed_addresses,
+            ..Default::default()
+        };
+        run_server(listener, storage, options, server_shutdown)
+            .await
+            .unwrap();
+    });
+
+    // Spans two parts: a full 5 MiB one and the remainder.
+    let attachment_contents: Vec<u8> = (0..6 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let email = Message::builder()
+        .from("test@example.com".parse().unwrap())
+        .to("user@example.net".parse().unwrap())
+        .subject("Large Attachment")
+        .multipart(
+            MultiPart::mixed()
+                .singlepart(SinglePart::plain("See attached.".to_string()))
+                .singlepart(
+                    Attachment::new("large.bin".to_string())
+                        .body(attachment_contents.clone(), ContentType::parse("application/octet-stream").unwrap()),
+                ),
+        )
+        .unwrap();
+
+    let client = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(addr.ip().to_string())
+        .port(addr.port())
+        .credentials(Credentials::new(
+            "test@example.com".to_string(),
+            "password".to_string(),
+        ))
+        .authentication(vec![Mechanism::Login])
+        .build();
+
+    client.send(email).await.unwrap();
+
+    shutdown.cancel();
+    server_handle.await.unwrap();
+
+    let objects = s3_client
+        .list_objects_v2()
+        .bucket(MULTIPART_TEST_BUCKET_NAME)
+        .send()
+        .await
+        .unwrap();
+    let attachment_key = objects
+        .contents()
+        .iter()
+        .filter_map(|o| o.key())
+        .find(|key| key.ends_with("/attachments/large.bin"))
+        .expect("The attachment should be stored")
+        .to_string();
+
+    let attachment_object = s3_client
+        .get_object()
+        .bucket(MULTIPART_TEST_BUCKET_NAME)
+        .key(attachment_key)
+        .send()
+        .await
+        .unwrap();
+    // Objects assembled from parts get an ETag with the number of parts as suffix.
+    assert!(attachment_object.e_tag().unwrap().trim_matches('"').ends_with("-2"));
+
+    let stored_contents = attachment_object.body.collect().await.unwrap().into_bytes();
+    assert_eq!(stored_contents.to_vec(), attachment_contents);
+}
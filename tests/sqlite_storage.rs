@@ -0,0 +1,82 @@
+use std::sync::Arc;
+
+use lettre::{
+    message::{header::ContentType, Attachment, MultiPart, SinglePart},
+    Message,
+};
+use smtp2s::storage::sqlite::SqliteStorage;
+use sqlx::{Row, SqlitePool};
+use tempfile::tempdir;
+
+mod common;
+
+#[tokio::test]
+async fn test_email_delivery_to_sqlite_storage() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+
+    let storage_dir = tempdir().unwrap();
+    let database_path = storage_dir.path().join("messages.db");
+
+    let email = Message::builder()
+        .from("test@example.com".parse().unwrap())
+        .to("user@example.net".parse().unwrap())
+        .cc("copy@example.org".parse().unwrap())
+        .subject("Test Email")
+        .multipart(
+            MultiPart::mixed()
+                .singlepart(SinglePart::plain("Hello, world!".to_string()))
+                .singlepart(
+                    Attachment::new("report.pdf".to_string())
+                        .body(b"%PDF-1.4".to_vec(), ContentType::parse("application/pdf").unwrap()),
+                ),
+        )
+        .unwrap();
+
+    let storage = Arc::new(SqliteStorage::connect(&database_path).await.unwrap());
+    common::send_message(storage, email).await.unwrap();
+
+    let pool = SqlitePool::connect(&format!("sqlite://{}", database_path.display())).await.unwrap();
+
+    let message = sqlx::query("SELECT id, mail_from, subject, raw_message FROM messages")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    let id: String = message.get("id");
+    assert_eq!(message.get::<String, _>("mail_from"), "test@example.com");
+    assert_eq!(message.get::<String, _>("subject"), "Test Email");
+    let raw_message: Vec<u8> = message.get("raw_message");
+    assert!(String::from_utf8(raw_message).unwrap().contains("Subject: Test Email"));
+
+    let recipients: Vec<(String, String)> =
+        sqlx::query_as("SELECT kind, address FROM recipients WHERE message_id = ? ORDER BY kind, address")
+            .bind(&id)
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+    assert_eq!(
+        recipients,
+        vec![
+            ("cc".to_string(), "copy@example.org".to_string()),
+            ("envelope".to_string(), "copy@example.org".to_string()),
+            ("envelope".to_string(), "user@example.net".to_string()),
+            ("to".to_string(), "user@example.net".to_string()),
+        ]
+    );
+
+    let text_body: String = sqlx::query_scalar("SELECT content FROM bodies WHERE message_id = ? AND file_name = 'body.txt'")
+        .bind(&id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert!(text_body.contains("Hello, world!"));
+
+    let (name, content_type, content): (String, String, Vec<u8>) =
+        sqlx::query_as("SELECT name, content_type, content FROM attachments WHERE message_id = ?")
+            .bind(&id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(name, "report.pdf");
+    assert_eq!(content_type, "application/pdf");
+    assert_eq!(content, b"%PDF-1.4");
+}
@@ -0,0 +1,61 @@
+#![cfg(unix)]
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+use common::{local_storage, read_reply, spawn_server};
+use smtp2s::listener::bind_unix_socket;
+use smtp2s::ServerOptions;
+use tempfile::tempdir;
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio_util::sync::CancellationToken;
+
+mod common;
+
+#[tokio::test]
+async fn test_email_delivery_over_unix_socket() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+    let storage_dir = tempdir().unwrap();
+    let socket_dir = tempdir().unwrap();
+    let socket_path = socket_dir.path().join("smtp2s.sock");
+
+    let listener = bind_unix_socket(&socket_path, Some(0o600)).unwrap();
+    let socket_mode = fs::metadata(&socket_path).unwrap().permissions().mode();
+    assert_eq!(socket_mode & 0o777, 0o600);
+
+    let options = ServerOptions {
+        allowed_addresses: vec!["*".to_string()],
+        ..Default::default()
+    };
+    let shutdown = CancellationToken::new();
+    let server_handle = spawn_server(listener, local_storage(storage_dir.path()), options, shutdown.clone());
+
+    let mut client = BufReader::new(UnixStream::connect(&socket_path).await.unwrap());
+    assert!(read_reply(&mut client).await.starts_with("220"));
+    for (command, expected) in [
+        ("EHLO sidecar\r\n", "250"),
+        ("AUTH LOGIN\r\n", "334"),
+        ("dGVzdEBleGFtcGxlLmNvbQ==\r\n", "334"),
+        ("cGFzc3dvcmQ=\r\n", "235"),
+        ("MAIL FROM:<test@example.com>\r\n", "250"),
+        ("RCPT TO:<user@example.net>\r\n", "250"),
+        ("DATA\r\n", "354"),
+        ("Subject: Over a socket\r\n\r\nBody\r\n.\r\n", "250"),
+        ("QUIT\r\n", "221"),
+    ] {
+        client.get_mut().write_all(command.as_bytes()).await.unwrap();
+        assert!(read_reply(&mut client).await.starts_with(expected));
+    }
+
+    shutdown.cancel();
+    server_handle.await.unwrap();
+    assert!(!socket_path.exists());
+    assert_eq!(fs::read_dir(socket_dir.path()).unwrap().count(), 0);
+
+    let message_dir = fs::read_dir(storage_dir.path()).unwrap().next().unwrap().unwrap();
+    let metadata_content = fs::read_to_string(message_dir.path().join("metadata.json")).unwrap();
+    let metadata_json: serde_json::Value = serde_json::from_str(&metadata_content).unwrap();
+    assert_eq!(metadata_json["client_address"], "unix");
+    assert_eq!(metadata_json["subject"], "Over a socket");
+}
@@ -0,0 +1,197 @@
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, HeaderMap, Request, Response, Server, StatusCode};
+use lettre::{
+    message::{header::ContentType, Attachment, MultiPart, SinglePart},
+    Message,
+};
+use sha2::Sha256;
+use smtp2s::storage::webhook::{WebhookOptions, WebhookStorage, DELIVERY_ID_HEADER};
+
+mod common;
+
+struct ReceivedRequest {
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+/// Answers each request with the next status, then with 200, recording every request it gets.
+async fn start_webhook_receiver(statuses: Vec<u16>) -> (String, Arc<Mutex<Vec<ReceivedRequest>>>) {
+    let received = Arc::new(Mutex::new(vec![]));
+    let statuses = Arc::new(Mutex::new(VecDeque::from(statuses)));
+
+    let service_received = received.clone();
+    let make_svc = make_service_fn(move |_conn| {
+        let received = service_received.clone();
+        let statuses = statuses.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let received = received.clone();
+                let status = statuses.lock().unwrap().pop_front().unwrap_or(200);
+                async move {
+                    let headers = request.headers().clone();
+                    let body = hyper::body::to_bytes(request.into_body()).await.unwrap().to_vec();
+                    received.lock().unwrap().push(ReceivedRequest { headers, body });
+                    let mut response = Response::new(Body::empty());
+                    *response.status_mut() = StatusCode::from_u16(status).unwrap();
+                    Ok::<_, Infallible>(response)
+                }
+            }))
+        }
+    });
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+    let url = format!("http://{}/messages", server.local_addr());
+    tokio::spawn(server);
+    (url, received)
+}
+
+/// Sends a message through a server storing with the given webhook, returning the SMTP outcome.
+async fn send_through_webhook(url: String, options: WebhookOptions, email: Message) -> Result<(), lettre::transport::smtp::Error> {
+    let storage = Arc::new(WebhookStorage::new(url, options).unwrap());
+    common::send_message(storage, email).await
+}
+
+fn simple_email() -> Message {
+    Message::builder()
+        .from("test@example.com".parse().unwrap())
+        .to("user@example.net".parse().unwrap())
+        .subject("Test Email")
+        .body("Hello, world!".to_string())
+        .unwrap()
+}
+
+fn fast_retries() -> WebhookOptions {
+    WebhookOptions {
+        initial_backoff_ms: 10,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_email_delivery_to_json_webhook() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+    let (url, received) = start_webhook_receiver(vec![]).await;
+
+    let email = Message::builder()
+        .from("test@example.com".parse().unwrap())
+        .to("user@example.net".parse().unwrap())
+        .subject("Test Email")
+        .multipart(
+            MultiPart::mixed()
+                .singlepart(SinglePart::plain("Hello, world!".to_string()))
+                .singlepart(
+                    Attachment::new("report.pdf".to_string())
+                        .body(b"%PDF-1.4".to_vec(), ContentType::parse("application/pdf").unwrap()),
+                ),
+        )
+        .unwrap();
+    let options = WebhookOptions {
+        headers: [("Authorization".to_string(), "Bearer token".to_string())].into(),
+        signing_secret: Some("secret".to_string()),
+        ..Default::default()
+    };
+
+    send_through_webhook(url, options, email).await.unwrap();
+
+    let received = received.lock().unwrap();
+    assert_eq!(received.len(), 1);
+    let request = &received[0];
+    assert_eq!(request.headers["content-type"], "application/json");
+    assert_eq!(request.headers["authorization"], "Bearer token");
+    assert!(request.headers.contains_key(DELIVERY_ID_HEADER));
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+    mac.update(&request.body);
+    let expected_signature = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+    assert_eq!(request.headers["x-smtp2s-signature"], expected_signature.as_str());
+
+    let document: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+    assert_eq!(document["metadata"]["from"], "test@example.com");
+    assert_eq!(document["metadata"]["subject"], "Test Email");
+    let bodies = document["bodies"].as_array().unwrap();
+    assert!(bodies
+        .iter()
+        .any(|body| body["file_name"] == "body.txt" && body["content"].as_str().unwrap().contains("Hello, world!")));
+    assert_eq!(document["attachments"][0]["name"], "report.pdf");
+    assert_eq!(document["attachments"][0]["content_type"], "application/pdf");
+    let contents = BASE64_STANDARD
+        .decode(document["attachments"][0]["content_base64"].as_str().unwrap())
+        .unwrap();
+    assert_eq!(contents, b"%PDF-1.4");
+}
+
+#[tokio::test]
+async fn test_failed_webhook_deliveries_are_retried() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+    let (url, received) = start_webhook_receiver(vec![503, 429]).await;
+
+    send_through_webhook(url, fast_retries(), simple_email()).await.unwrap();
+
+    let received = received.lock().unwrap();
+    assert_eq!(received.len(), 3);
+    // Every attempt carries the same delivery id, so the receiver can deduplicate them.
+    assert_eq!(received[0].headers[DELIVERY_ID_HEADER], received[2].headers[DELIVERY_ID_HEADER]);
+}
+
+#[tokio::test]
+async fn test_webhook_responses_are_mapped_to_smtp_replies() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+
+    // Still failing after every attempt, the sender is asked to retry later.
+    let (url, received) = start_webhook_receiver(vec![503, 503, 503]).await;
+    let error = send_through_webhook(url, fast_retries(), simple_email()).await.unwrap_err();
+    assert!(error.is_transient());
+    assert_eq!(error.status().map(|code| code.to_string()), Some("451".to_string()));
+    assert_eq!(received.lock().unwrap().len(), 3);
+
+    // A client error won't go away by retrying.
+    let (url, received) = start_webhook_receiver(vec![400]).await;
+    let error = send_through_webhook(url, fast_retries(), simple_email()).await.unwrap_err();
+    assert!(error.is_permanent());
+    assert_eq!(error.status().map(|code| code.to_string()), Some("554".to_string()));
+    assert_eq!(received.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_email_delivery_to_multipart_webhook() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+    let (url, received) = start_webhook_receiver(vec![]).await;
+    let options = WebhookOptions {
+        format: smtp2s::storage::webhook::WebhookFormat::Multipart,
+        ..Default::default()
+    };
+
+    send_through_webhook(url, options, simple_email()).await.unwrap();
+
+    let received = received.lock().unwrap();
+    let content_type = received[0].headers["content-type"].to_str().unwrap();
+    let boundary = content_type.strip_prefix("multipart/form-data; boundary=").unwrap();
+    let body = String::from_utf8(received[0].body.clone()).unwrap();
+    assert!(body.starts_with(&format!("--{}\r\nContent-Disposition: form-data; name=\"metadata\"", boundary)));
+    assert!(body.contains("Content-Disposition: form-data; name=\"body\"; filename=\"body.txt\""));
+    assert!(body.contains("Hello, world!"));
+    assert!(body.ends_with(&format!("--{}--\r\n", boundary)));
+}
+
+#[test]
+fn test_invalid_webhook_headers_are_refused_at_startup() {
+    let url = "http://127.0.0.1/messages".to_string();
+    let options = WebhookOptions {
+        headers: [("Authorization".to_string(), "Bearer\ntoken".to_string())].into(),
+        ..Default::default()
+    };
+    assert!(WebhookStorage::new(url.clone(), options).is_err());
+
+    let options = WebhookOptions {
+        signature_header: "X Signature".to_string(),
+        ..Default::default()
+    };
+    assert!(WebhookStorage::new(url, options).is_err());
+}